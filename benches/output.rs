@@ -0,0 +1,69 @@
+//! Benchmark of ordering the accounts of all partitions for output,
+//! e.g. `cargo bench --bench output`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::hint::black_box;
+use transactor::models::{Account, ClientId, Record};
+use transactor::processing::merge_sorted;
+
+const CLIENTS: u16 = u16::MAX;
+const WORKERS: usize = 8;
+
+/// Returns the accounts of all clients spread over the workers as a partition
+/// does, i.e. in no particular order.
+fn outputs() -> Vec<Vec<Record<Account, ClientId>>> {
+    let mut outputs: Vec<_> = (0..WORKERS).map(|_| Vec::new()).collect();
+    for client_id in 0..CLIENTS {
+        // A multiplicative hash scatters the ids like the hash map of a partition.
+        let client_id = client_id.wrapping_mul(40503);
+        outputs[client_id as usize % WORKERS]
+            .push(Record::new(Account::default(), ClientId::new(client_id)));
+    }
+    outputs
+}
+
+fn ordering(c: &mut Criterion) {
+    let mut group = c.benchmark_group("output");
+    group.throughput(Throughput::Elements(CLIENTS as u64));
+    // The order before partitions sorted their own outputs.
+    group.bench_function("sort_all", |b| {
+        b.iter_batched(
+            outputs,
+            |outputs| {
+                let mut all: Vec<_> = outputs.into_iter().flatten().collect();
+                all.sort_unstable_by_key(|r| r.id);
+                black_box(all)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    // What each worker now does on its own thread, in parallel to the others.
+    group.bench_function("sort_partition", |b| {
+        b.iter_batched(
+            || outputs().swap_remove(0),
+            |mut output| {
+                output.sort_unstable_by_key(|r| r.id);
+                black_box(output)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    // With the outputs of the workers sorted, the merge is all that is left.
+    group.bench_function("merge_sorted", |b| {
+        b.iter_batched(
+            || {
+                let mut outputs = outputs();
+                for output in &mut outputs {
+                    output.sort_unstable_by_key(|r| r.id);
+                }
+                outputs
+            },
+            |outputs| black_box(merge_sorted(outputs)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ordering);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+//! Benchmark of reading transactions, e.g. `cargo bench --bench read`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::hint::black_box;
+use transactor::generate::generate;
+use transactor::proto::Transaction;
+
+const COUNT: usize = 100_000;
+
+fn reading(c: &mut Criterion) {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    generate(&mut writer, COUNT, 1000, 42).unwrap();
+    let input = writer.into_inner().unwrap();
+
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Elements(COUNT as u64));
+    group.bench_function("read_many", |b| {
+        b.iter(|| {
+            let mut reader = csv::Reader::from_reader(input.as_slice());
+            black_box(Transaction::read_many(&mut reader).count())
+        })
+    });
+    group.bench_function("deserialize", |b| {
+        b.iter(|| {
+            let mut reader = csv::Reader::from_reader(input.as_slice());
+            black_box(reader.deserialize::<Transaction>().count())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, reading);
+criterion_main!(benches);
@@ -0,0 +1,129 @@
+//! Module compares the accounts output by two runs, e.g. of two versions of
+//! a statement, for auditing what changed between them.
+
+use crate::proto::{Account, Column};
+use std::collections::BTreeMap;
+use std::io;
+
+/// Final state of a client differing between two runs.
+///
+/// * `client_id` - the client.
+/// * `old` - the account output by the first run, `None` if absent from it.
+/// * `new` - the account output by the second run, `None` if absent from it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Change {
+    pub client_id: u16,
+    pub old: Option<Account>,
+    pub new: Option<Account>,
+}
+
+/// Returns the changes from the `old` to the `new` accounts, sorted by client id.
+/// Clients with equal accounts in both are left out.
+pub fn diff(old: Vec<Account>, new: Vec<Account>) -> Vec<Change> {
+    let mut changes: BTreeMap<_, _> = old
+        .into_iter()
+        .map(|account| (account.client_id, (Some(account), None)))
+        .collect();
+    for account in new {
+        let client_id = account.client_id;
+        changes.entry(client_id).or_insert((None, None)).1 = Some(account);
+    }
+
+    changes
+        .into_iter()
+        .filter(|(_, (old, new))| old != new)
+        .map(|(client_id, (old, new))| Change {
+            client_id,
+            old,
+            new,
+        })
+        .collect()
+}
+
+/// Writes the `changes` as CSV records of the client followed by the old and
+/// the new values of the other default columns, empty for an absent account.
+pub fn write_changes<U: io::Write>(
+    writer: &mut csv::Writer<U>,
+    changes: &[Change],
+) -> csv::Result<()> {
+    let columns = &Column::DEFAULT[1..];
+    let header = columns
+        .iter()
+        .map(|c| format!("old_{}", c.name()))
+        .chain(columns.iter().map(|c| format!("new_{}", c.name())));
+    writer.write_record(["client".to_string()].into_iter().chain(header))?;
+
+    for change in changes {
+        let fields = |account: &Option<Account>| -> Vec<String> {
+            columns
+                .iter()
+                .map(|c| account.as_ref().map(|a| a.field(*c)).unwrap_or_default())
+                .collect()
+        };
+        let client = change.client_id.to_string();
+        let record = [client]
+            .into_iter()
+            .chain(fields(&change.old))
+            .chain(fields(&change.new));
+        writer.write_record(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    fn accounts(input: &str) -> Vec<Account> {
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+        let mut accounts = Vec::new();
+        crate::process_into(&mut reader, &mut accounts, &Default::default()).unwrap();
+        accounts
+    }
+
+    #[test]
+    fn diffing_runs() {
+        let old = accounts(indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,2,2,2.0
+            deposit,3,3,3.0
+        "});
+        let new = accounts(indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,2,2,2.5
+            deposit,3,3,3.0
+        "});
+
+        let changes = diff(old, new);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].client_id, 2);
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        write_changes(&mut writer, &changes).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            indoc! {"
+                client,old_available,old_held,old_total,old_locked,new_available,new_held,new_total,new_locked
+                2,2,0,2,false,2.5,0,2.5,false
+            "}
+        );
+    }
+
+    #[test]
+    fn diffing_missing_clients() {
+        let old = accounts("type,client,tx,amount\ndeposit,1,1,1.0\n");
+        let new = accounts("type,client,tx,amount\ndeposit,2,2,1.0\n");
+
+        let changes = diff(old, new);
+        let clients: Vec<_> = changes
+            .iter()
+            .map(|c| (c.client_id, c.old.is_some(), c.new.is_some()))
+            .collect();
+        assert_eq!(clients, vec![(1, true, false), (2, false, true)]);
+    }
+}
@@ -0,0 +1,194 @@
+//! Module generates random but reproducible transaction data for
+//! benchmarking and fuzzing.
+
+use crate::proto;
+use rust_decimal::Decimal;
+
+/// Maximum number of recent deposits remembered as dispute candidates.
+const MAX_RECENT_DEPOSITS: usize = 1024;
+
+/// Small deterministic pseudo-random generator (SplitMix64).
+///
+/// Used instead of an external crate so that the same seed keeps producing
+/// the same data regardless of dependency versions.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Generator of transaction records.
+///
+/// Produces a mix of deposits and withdrawals with occasional disputes
+/// of earlier deposits, and resolves or chargebacks of open disputes.
+pub struct Generator {
+    rng: Rng,
+    n_clients: u16,
+    next_transaction_id: u64,
+    recent_deposits: Vec<(u16, u64)>,
+    open_disputes: Vec<(u16, u64)>,
+}
+
+impl Generator {
+    /// Creates a generator of transactions across `n_clients` clients.
+    pub fn new(n_clients: u16, seed: u64) -> Generator {
+        assert!(n_clients > 0, "At least one client is required");
+        Generator {
+            rng: Rng(seed),
+            n_clients,
+            next_transaction_id: 1,
+            recent_deposits: Vec::new(),
+            open_disputes: Vec::new(),
+        }
+    }
+
+    fn amount(&mut self) -> Option<Decimal> {
+        let units = self.rng.below(10_000_000) as i64 + 1;
+        Some(Decimal::new(units, 4).normalize())
+    }
+
+    fn record(
+        kind: &'static str,
+        client_id: u16,
+        transaction_id: u64,
+        amount: Option<Decimal>,
+    ) -> proto::Transaction {
+        proto::Transaction {
+            kind: kind.into(),
+            client_id: client_id.into(),
+            transaction_id,
+            amount,
+            original_transaction_id: None,
+            timestamp: None,
+            row: None,
+        }
+    }
+
+    fn transfer(&mut self) -> proto::Transaction {
+        let client_id = self.rng.below(self.n_clients as u64) as u16 + 1;
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id += 1;
+        let amount = self.amount();
+
+        if self.rng.below(3) == 0 {
+            return Generator::record("withdrawal", client_id, transaction_id, amount);
+        }
+
+        if self.recent_deposits.len() < MAX_RECENT_DEPOSITS {
+            self.recent_deposits.push((client_id, transaction_id));
+        } else {
+            let slot = self.rng.below(MAX_RECENT_DEPOSITS as u64) as usize;
+            self.recent_deposits[slot] = (client_id, transaction_id);
+        }
+        Generator::record("deposit", client_id, transaction_id, amount)
+    }
+
+    fn dispute(&mut self) -> Option<proto::Transaction> {
+        if self.recent_deposits.is_empty() {
+            return None;
+        }
+        let i = self.rng.below(self.recent_deposits.len() as u64) as usize;
+        let (client_id, transaction_id) = self.recent_deposits.swap_remove(i);
+        self.open_disputes.push((client_id, transaction_id));
+        Some(Generator::record(
+            "dispute",
+            client_id,
+            transaction_id,
+            None,
+        ))
+    }
+
+    fn settle(&mut self) -> Option<proto::Transaction> {
+        if self.open_disputes.is_empty() {
+            return None;
+        }
+        let i = self.rng.below(self.open_disputes.len() as u64) as usize;
+        let (client_id, transaction_id) = self.open_disputes.swap_remove(i);
+        let kind = match self.rng.below(10) {
+            0 => "chargeback",
+            _ => "resolve",
+        };
+        Some(Generator::record(kind, client_id, transaction_id, None))
+    }
+}
+
+impl Iterator for Generator {
+    type Item = proto::Transaction;
+
+    fn next(&mut self) -> Option<proto::Transaction> {
+        let record = match self.rng.below(100) {
+            0 => self.dispute(),
+            1 => self.settle(),
+            _ => None,
+        };
+        Some(record.unwrap_or_else(|| self.transfer()))
+    }
+}
+
+/// Writes `count` transactions across `n_clients` clients generated from `seed`.
+pub fn generate<W: std::io::Write>(
+    writer: &mut csv::Writer<W>,
+    count: usize,
+    n_clients: u16,
+    seed: u64,
+) -> csv::Result<()> {
+    for record in Generator::new(n_clients, seed).take(count) {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn generate_string(count: usize, n_clients: u16, seed: u64) -> String {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        generate(&mut writer, count, n_clients, seed).unwrap();
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn reproducible() {
+        assert_eq!(generate_string(1000, 10, 42), generate_string(1000, 10, 42));
+        assert_ne!(generate_string(1000, 10, 42), generate_string(1000, 10, 43));
+    }
+
+    #[test]
+    fn disputes_reference_earlier_deposits() {
+        let mut deposits = HashMap::new();
+        let mut n_disputes = 0;
+
+        for record in Generator::new(5, 7).take(10_000) {
+            assert!((1..=5).contains(&record.client_id));
+            match &*record.kind {
+                "deposit" => {
+                    deposits.insert(record.transaction_id, record.client_id);
+                }
+                "dispute" | "resolve" | "chargeback" => {
+                    assert_eq!(
+                        deposits.get(&record.transaction_id),
+                        Some(&record.client_id)
+                    );
+                    n_disputes += 1;
+                }
+                _ => {}
+            }
+        }
+
+        assert!(n_disputes > 0);
+    }
+}
@@ -0,0 +1,422 @@
+//! Module opens transaction inputs, decompressing them if needed.
+//!
+//! Processing is generic over `Read`, so compressed inputs only need wrapping
+//! in a decoder before the `csv::Reader` is constructed. Archives of many
+//! inputs open into a reader per entry, processed as multiple inputs are.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Compression of an input file.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Compression {
+    None,
+    /// Zstandard, supported with the `zstd` feature.
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression of the file at `path` by its extension, e.g. `.zst`.
+    pub fn detect(path: &Path) -> Compression {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// Wraps the `input` in a decoder of the compression.
+    fn decode(self, input: Box<dyn io::Read>) -> io::Result<Box<dyn io::Read>> {
+        match self {
+            Compression::None => Ok(input),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(zstd::Decoder::new(input)?)),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "zstd input requires the zstd feature",
+            )),
+        }
+    }
+}
+
+/// CSV quoting of inputs and outputs, for files of tools not following
+/// RFC 4180, e.g. quoting with `'` or escaping quotes with `\`.
+///
+/// * `quote` - the quote character, `"` by default.
+/// * `escape` - the character escaping quotes within quoted fields, if any.
+///   Quotes are escaped by doubling them otherwise.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Quoting {
+    pub quote: u8,
+    pub escape: Option<u8>,
+}
+
+impl Default for Quoting {
+    fn default() -> Quoting {
+        Quoting {
+            quote: b'"',
+            escape: None,
+        }
+    }
+}
+
+impl Quoting {
+    /// Returns a builder of readers of the quoting.
+    pub fn reader(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder.quote(self.quote).escape(self.escape);
+        builder
+    }
+
+    /// Returns a builder of writers of the quoting.
+    pub fn writer(&self) -> csv::WriterBuilder {
+        let mut builder = csv::WriterBuilder::new();
+        builder.quote(self.quote);
+        if let Some(escape) = self.escape {
+            builder.double_quote(false).escape(escape);
+        }
+        builder
+    }
+}
+
+/// UTF-8 byte order mark, prefixing files exported by some Windows tools.
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Reader skipping a leading UTF-8 BOM of the `inner` reader.
+///
+/// The `csv` reader only strips a BOM read at once, so it is left in the first
+/// header, e.g. `\u{feff}type`, failing every record of inputs read in small
+/// chunks like streams. The wrapper reads up to the length of a BOM upfront.
+pub struct SkipBom<R> {
+    inner: R,
+    prefix: [u8; 3],
+    start: usize,
+    end: usize,
+    checked: bool,
+}
+
+impl<R: io::Read> SkipBom<R> {
+    pub fn new(inner: R) -> SkipBom<R> {
+        SkipBom {
+            inner,
+            prefix: [0; 3],
+            start: 0,
+            end: 0,
+            checked: false,
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for SkipBom<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.checked {
+            while self.end < BOM.len() {
+                match self.inner.read(&mut self.prefix[self.end..])? {
+                    0 => break,
+                    n => self.end += n,
+                }
+            }
+            if self.prefix[..self.end] == *BOM {
+                self.start = self.end;
+            }
+            self.checked = true;
+        }
+
+        if self.start < self.end {
+            let n = buf.len().min(self.end - self.start);
+            buf[..n].copy_from_slice(&self.prefix[self.start..self.start + n]);
+            self.start += n;
+            return Ok(n);
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Opens a reader of the input file at `path` or of stdin if the path is `-`.
+///
+/// The input is decompressed according to `compression`, or to the compression
+/// detected by the file extension if `None` (see `Compression::detect`).
+/// A leading BOM of the decompressed input is skipped.
+pub fn open(
+    path: &Path,
+    compression: Option<Compression>,
+    quoting: Quoting,
+) -> io::Result<csv::Reader<Box<dyn io::Read>>> {
+    let input: Box<dyn io::Read> = if path.as_os_str() == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    let compression = compression.unwrap_or_else(|| Compression::detect(path));
+    let input = SkipBom::new(compression.decode(input)?);
+    Ok(quoting.reader().from_reader(Box::new(input)))
+}
+
+/// Whether the file at `path` is a gzipped tar archive of inputs
+/// by its extension, `.tar.gz` or `.tgz`.
+pub fn is_archive(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+/// Opens readers of the entries of the gzipped tar archive at `path`, in order
+/// of their names, supported with the `archive` feature. Entries other than
+/// files, e.g. directories, are skipped.
+///
+/// The archive is listed upfront, as its order needn't be the order of the names,
+/// then every entry is streamed from the archive opened anew once it is read
+/// (see `ArchiveEntry`).
+#[cfg(feature = "archive")]
+pub fn open_archive(
+    path: &Path,
+    quoting: Quoting,
+) -> io::Result<Vec<csv::Reader<Box<dyn io::Read>>>> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(File::open(path)?));
+    let mut entries = std::collections::BTreeMap::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() {
+            let name = entry.path()?.into_owned();
+            entries.insert(name, (entry.raw_file_position(), entry.size()));
+        }
+    }
+
+    Ok(entries
+        .into_values()
+        .map(|(position, size)| {
+            let entry = ArchiveEntry {
+                path: path.to_path_buf(),
+                position,
+                size,
+                input: None,
+            };
+            let input: Box<dyn io::Read> = Box::new(SkipBom::new(entry));
+            quoting.reader().from_reader(input)
+        })
+        .collect())
+}
+
+/// Reader of a file entry of a gzipped tar archive.
+///
+/// The archive is only opened on the first read, decompressed up to the
+/// `position` of the entry contents, so at most one archive is open at a time
+/// when entries are read one after another.
+#[cfg(feature = "archive")]
+struct ArchiveEntry {
+    path: std::path::PathBuf,
+    position: u64,
+    size: u64,
+    input: Option<io::Take<flate2::read::GzDecoder<File>>>,
+}
+
+#[cfg(feature = "archive")]
+impl io::Read for ArchiveEntry {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let input = match &mut self.input {
+            Some(input) => input,
+            None => {
+                let mut archive = flate2::read::GzDecoder::new(File::open(&self.path)?);
+                let skipped = io::copy(&mut (&mut archive).take(self.position), &mut io::sink())?;
+                if skipped < self.position {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "archive ended before the entry",
+                    ));
+                }
+                self.input.insert(archive.take(self.size))
+            }
+        };
+        input.read(buf)
+    }
+}
+
+/// Fails as archives require the `archive` feature.
+#[cfg(not(feature = "archive"))]
+pub fn open_archive(
+    _path: &Path,
+    _quoting: Quoting,
+) -> io::Result<Vec<csv::Reader<Box<dyn io::Read>>>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "tar.gz input requires the archive feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{process, proto};
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
+
+    fn run(path: &Path) -> io::Result<Vec<proto::Account>> {
+        let mut reader = open(path, None, Quoting::default())?;
+        let mut writer = csv::Writer::from_writer(vec![]);
+        process(&mut reader, &mut writer).unwrap();
+
+        let output = writer.into_inner().unwrap();
+        let mut reader = csv::Reader::from_reader(output.as_slice());
+        Ok(proto::Account::read_many(&mut reader)
+            .map(|r| r.unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn detecting_compression() {
+        assert_eq!(
+            Compression::detect(Path::new("in.csv.zst")),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::detect(Path::new("in.csv")), Compression::None);
+        assert_eq!(Compression::detect(Path::new("-")), Compression::None);
+    }
+
+    /// Reader returning a byte at a time, as a slow stream may.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((byte, rest)), Some(first)) => {
+                    *first = *byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn skipping_bom() {
+        let input = "\u{feff}type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,2,2,2.0\n";
+        let read = |reader: Box<dyn io::Read>| {
+            let mut reader = csv::Reader::from_reader(reader);
+            let mut accounts = Vec::new();
+            crate::process_into(&mut reader, &mut accounts, &Default::default()).unwrap();
+            accounts
+        };
+
+        assert!(read(Box::new(Trickle(input.as_bytes()))).is_empty());
+        let accounts = read(Box::new(SkipBom::new(Trickle(input.as_bytes()))));
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(read(Box::new(SkipBom::new(input.as_bytes()))), accounts);
+
+        let mut plain = String::new();
+        io::Read::read_to_string(&mut SkipBom::new(&input.as_bytes()[3..]), &mut plain).unwrap();
+        assert_eq!(plain, &input[3..]);
+        let mut short = Vec::new();
+        io::Read::read_to_end(&mut SkipBom::new(&b"\xef\xbb"[..]), &mut short).unwrap();
+        assert_eq!(short, b"\xef\xbb");
+    }
+
+    #[test]
+    fn reading_quoted() {
+        let input = "type,client,tx,amount\n'deposit',1,1,'2.5'\n'withdrawal',1,2,'1.0'\n";
+        let read = |quoting: Quoting| {
+            let mut reader = quoting.reader().from_reader(input.as_bytes());
+            let mut accounts = Vec::new();
+            crate::process_into(&mut reader, &mut accounts, &Default::default()).unwrap();
+            accounts
+        };
+
+        assert!(read(Quoting::default()).is_empty());
+        let quoting = Quoting {
+            quote: b'\'',
+            escape: None,
+        };
+        let accounts = read(quoting);
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available_funds.to_string(), "1.5");
+
+        let mut writer = Quoting {
+            quote: b'\'',
+            escape: Some(b'\\'),
+        }
+        .writer()
+        .from_writer(vec![]);
+        writer.write_record(["it's", "plain"]).unwrap();
+        let output = writer.into_inner().unwrap();
+        assert_eq!(output, b"'it\\'s',plain\n");
+    }
+
+    #[test]
+    fn detecting_archives() {
+        assert!(is_archive(Path::new("statements.tar.gz")));
+        assert!(is_archive(Path::new("statements.tgz")));
+        assert!(!is_archive(Path::new("statements.csv.gz")));
+        assert!(!is_archive(Path::new("statements.csv")));
+    }
+
+    #[cfg(feature = "archive")]
+    #[test]
+    fn reading_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactor-archive-test.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        let entries = [
+            (
+                "2024-02.csv",
+                "type,client,tx,amount\nwithdrawal,1,3,1.5\ndeposit,2,4,2.0\n",
+            ),
+            (
+                "2024-01.csv",
+                "type,client,tx,amount\ndeposit,1,1,4.0\ndeposit,2,2,1.0\n",
+            ),
+        ];
+        for (name, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let mut readers = open_archive(&path, Quoting::default()).unwrap();
+        assert_eq!(readers.len(), 2);
+        let mut accounts = Vec::new();
+        crate::process_many_into(&mut readers, &mut accounts, &Default::default()).unwrap();
+
+        let balances: Vec<_> = accounts
+            .iter()
+            .map(|a| (a.client_id, a.available_funds.to_string()))
+            .collect();
+        assert_eq!(balances, vec![(1, "2.5".to_string()), (2, "3".to_string())]);
+    }
+
+    #[cfg(not(feature = "archive"))]
+    #[test]
+    fn reading_archive_unsupported() {
+        let err = open_archive(Path::new("statements.tar.gz"), Quoting::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn reading_zstd() {
+        let plain = run(&fixture("transactions.csv")).unwrap();
+        assert_eq!(plain.len(), 2);
+        assert_eq!(run(&fixture("transactions.csv.zst")).unwrap(), plain);
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn reading_zstd_unsupported() {
+        let err = run(&fixture("transactions.csv.zst")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
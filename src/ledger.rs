@@ -0,0 +1,369 @@
+//! Module defines the core ledger: client accounts and their arithmetic.
+//!
+//! The module depends on `core` and `rust_decimal` only, so it builds without
+//! the `std` feature (e.g. for embedded use) where the rest of the crate is left out.
+//! With the feature, ids are serializable as part of `models::Transaction`.
+
+use core::fmt;
+use rust_decimal::Decimal;
+
+/// Type-safe client id.
+///
+/// Ids of different namespaces identify different clients, e.g. when every
+/// input file has its own clients. Ids are ordered by namespace first.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientId {
+    namespace: u16,
+    id: u16,
+}
+
+impl ClientId {
+    /// Creates an id in the default namespace `0`.
+    pub fn new(inner: u16) -> ClientId {
+        ClientId::namespaced(0, inner)
+    }
+
+    /// Creates an id in the given `namespace`.
+    pub fn namespaced(namespace: u16, inner: u16) -> ClientId {
+        ClientId {
+            namespace,
+            id: inner,
+        }
+    }
+
+    /// Returns the raw id value.
+    pub fn value(&self) -> u16 {
+        self.id
+    }
+
+    /// Returns the namespace of the id.
+    pub fn namespace(&self) -> u16 {
+        self.namespace
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id)
+    }
+}
+
+/// Type-safe transaction id.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransactionId(u32);
+
+impl TransactionId {
+    pub fn new(inner: u32) -> TransactionId {
+        TransactionId(inner)
+    }
+
+    /// Returns the raw id value.
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Mode of handling arithmetic overflows in account operations.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ArithmeticMode {
+    /// Fails the operation leaving the account intact.
+    #[default]
+    Checked,
+    /// Clamps the result to the `Decimal` range and emits a warning (with `std`).
+    Saturating,
+}
+
+/// Error of an account operation overflowing the `Decimal` range.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Overflow;
+
+impl ArithmeticMode {
+    /// Adds `rhs` to `lhs` according to the mode.
+    pub(crate) fn add(self, lhs: Decimal, rhs: Decimal) -> Result<Decimal, Overflow> {
+        match (lhs.checked_add(rhs), self) {
+            (Some(result), _) => Ok(result),
+            (None, ArithmeticMode::Checked) => Err(Overflow),
+            (None, ArithmeticMode::Saturating) => {
+                #[cfg(feature = "std")]
+                eprintln!("Warning: {} + {} overflows, saturating", lhs, rhs);
+                Ok(lhs.saturating_add(rhs))
+            }
+        }
+    }
+
+    /// Subtracts `rhs` from `lhs` according to the mode.
+    pub(crate) fn sub(self, lhs: Decimal, rhs: Decimal) -> Result<Decimal, Overflow> {
+        self.add(lhs, -rhs)
+    }
+}
+
+/// Reason of an account lock.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LockReason {
+    /// Locked by the chargeback of the transaction.
+    Chargeback(TransactionId),
+    /// Locked by an explicit administrative action.
+    Admin,
+}
+
+impl fmt::Display for LockReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockReason::Chargeback(transaction_id) => write!(f, "{}", transaction_id),
+            LockReason::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Client Account model.
+///
+/// Disputed withdrawals don't hold funds: the money has left the account
+/// already, so the disputed amount is a pending credit, returned to available
+/// funds by a chargeback. Pending credits are not part of the total funds.
+///
+/// The lock reason is unknown for accounts restored locked (see `restore`).
+#[derive(Debug, Clone, Default)]
+pub struct Account {
+    available_funds: Decimal,
+    held_funds: Decimal,
+    pending_credits: Decimal,
+    /// Fees paid in total.
+    fees: Decimal,
+    /// Numbers of deposits and withdrawals applied, if counted.
+    deposits: u64,
+    withdrawals: u64,
+    is_locked: bool,
+    lock_reason: Option<LockReason>,
+}
+
+impl Account {
+    /// Creates new unlocked account with zero funds.
+    pub fn new() -> Account {
+        Account::default()
+    }
+
+    /// Restores an account with the given funds and lock, e.g. from a prior run.
+    /// The lock reason is unknown.
+    pub fn restore(available_funds: Decimal, held_funds: Decimal, is_locked: bool) -> Account {
+        Account {
+            available_funds,
+            held_funds,
+            pending_credits: Decimal::ZERO,
+            fees: Decimal::ZERO,
+            deposits: 0,
+            withdrawals: 0,
+            is_locked,
+            lock_reason: None,
+        }
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.is_locked
+    }
+
+    /// Returns the reason the account is locked, if known.
+    pub fn lock_reason(&self) -> Option<LockReason> {
+        self.lock_reason
+    }
+
+    /// Returns available funds.
+    pub fn get_available_funds(&self) -> &Decimal {
+        &self.available_funds
+    }
+
+    /// Returns held funds.
+    pub fn get_held_funds(&self) -> &Decimal {
+        &self.held_funds
+    }
+
+    /// Returns credits pending from disputed withdrawals.
+    pub fn get_pending_credits(&self) -> &Decimal {
+        &self.pending_credits
+    }
+
+    /// Returns fees paid in total.
+    pub fn get_fees(&self) -> &Decimal {
+        &self.fees
+    }
+
+    /// Returns the number of deposits counted (see `count_deposit`).
+    pub fn get_deposit_count(&self) -> u64 {
+        self.deposits
+    }
+
+    /// Returns the number of withdrawals counted (see `count_withdrawal`).
+    pub fn get_withdrawal_count(&self) -> u64 {
+        self.withdrawals
+    }
+
+    /// Counts a deposit applied to the account.
+    pub fn count_deposit(&mut self) {
+        self.deposits = self.deposits.saturating_add(1);
+    }
+
+    /// Counts a withdrawal applied to the account.
+    pub fn count_withdrawal(&mut self) {
+        self.withdrawals = self.withdrawals.saturating_add(1);
+    }
+
+    /// Deposits the given `amount` to the account.
+    pub fn deposit(&mut self, amount: &Decimal, mode: ArithmeticMode) -> Result<(), Overflow> {
+        self.available_funds = mode.add(self.available_funds, *amount)?;
+        Ok(())
+    }
+
+    /// Withdraws the given `amount` from the account.
+    pub fn withdraw(&mut self, amount: &Decimal, mode: ArithmeticMode) -> Result<(), Overflow> {
+        assert!(
+            self.available_funds >= *amount,
+            "Attempting to withdraw more than the account has"
+        );
+        self.available_funds = mode.sub(self.available_funds, *amount)?;
+        Ok(())
+    }
+
+    /// Pays the `fee` out of available funds.
+    pub fn pay_fee(&mut self, fee: &Decimal, mode: ArithmeticMode) -> Result<(), Overflow> {
+        let available_funds = mode.sub(self.available_funds, *fee)?;
+        let fees = mode.add(self.fees, *fee)?;
+        self.available_funds = available_funds;
+        self.fees = fees;
+        Ok(())
+    }
+
+    /// Holds the specified fund amount.
+    pub fn hold_funds(&mut self, amount: &Decimal, mode: ArithmeticMode) -> Result<(), Overflow> {
+        let available_funds = mode.sub(self.available_funds, *amount)?;
+        let held_funds = mode.add(self.held_funds, *amount)?;
+        self.available_funds = available_funds;
+        self.held_funds = held_funds;
+        Ok(())
+    }
+
+    /// Release the previously held specified fund amount.
+    pub fn release_funds(
+        &mut self,
+        amount: &Decimal,
+        mode: ArithmeticMode,
+    ) -> Result<(), Overflow> {
+        let available_funds = mode.add(self.available_funds, *amount)?;
+        let held_funds = mode.sub(self.held_funds, *amount)?;
+        self.available_funds = available_funds;
+        self.held_funds = held_funds;
+        Ok(())
+    }
+
+    /// Adds the disputed amount of a withdrawal to pending credits.
+    pub fn add_pending_credit(
+        &mut self,
+        amount: &Decimal,
+        mode: ArithmeticMode,
+    ) -> Result<(), Overflow> {
+        self.pending_credits = mode.add(self.pending_credits, *amount)?;
+        Ok(())
+    }
+
+    /// Drops the previously pending credit amount, the withdrawal stands.
+    pub fn drop_pending_credit(
+        &mut self,
+        amount: &Decimal,
+        mode: ArithmeticMode,
+    ) -> Result<(), Overflow> {
+        self.pending_credits = mode.sub(self.pending_credits, *amount)?;
+        Ok(())
+    }
+
+    /// Credits the previously pending amount of the withdrawal `transaction_id`
+    /// back to available funds and locks the account, as a chargeback does.
+    pub fn refund(
+        &mut self,
+        amount: &Decimal,
+        transaction_id: TransactionId,
+        mode: ArithmeticMode,
+    ) -> Result<(), Overflow> {
+        let pending_credits = mode.sub(self.pending_credits, *amount)?;
+        let available_funds = mode.add(self.available_funds, *amount)?;
+        self.pending_credits = pending_credits;
+        self.available_funds = available_funds;
+        self.is_locked = true;
+        self.lock_reason = Some(LockReason::Chargeback(transaction_id));
+        Ok(())
+    }
+
+    /// Rounds the funds to at most `scale` decimal places, midpoints to even,
+    /// so the scale stays bounded over many operations.
+    pub fn limit_scale(&mut self, scale: u32) {
+        self.available_funds = self.available_funds.round_dp(scale);
+        self.held_funds = self.held_funds.round_dp(scale);
+        self.pending_credits = self.pending_credits.round_dp(scale);
+    }
+
+    /// Locks the account by an explicit administrative action.
+    pub fn freeze(&mut self) {
+        self.is_locked = true;
+        self.lock_reason = Some(LockReason::Admin);
+    }
+
+    /// Unlocks the account by an explicit administrative action.
+    pub fn unfreeze(&mut self) {
+        self.is_locked = false;
+        self.lock_reason = None;
+    }
+
+    /// Charges the previously held specified fund amount of the transaction
+    /// `transaction_id` again and lock the account.
+    pub fn chargeback(
+        &mut self,
+        amount: &Decimal,
+        transaction_id: TransactionId,
+        mode: ArithmeticMode,
+    ) -> Result<(), Overflow> {
+        self.held_funds = mode.sub(self.held_funds, *amount)?;
+        self.is_locked = true;
+        self.lock_reason = Some(LockReason::Chargeback(transaction_id));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn account_arithmetic() {
+        let mode = ArithmeticMode::Checked;
+        let mut account = Account::new();
+        account.deposit(&dec!(10), mode).unwrap();
+        account.withdraw(&dec!(3), mode).unwrap();
+        account.hold_funds(&dec!(5), mode).unwrap();
+        account.release_funds(&dec!(2), mode).unwrap();
+        account
+            .chargeback(&dec!(3), TransactionId::new(7), mode)
+            .unwrap();
+
+        assert_eq!(account.get_available_funds(), &dec!(4));
+        assert_eq!(account.get_held_funds(), &dec!(0));
+        assert!(account.is_frozen());
+        assert_eq!(
+            account.lock_reason(),
+            Some(LockReason::Chargeback(TransactionId::new(7)))
+        );
+
+        let mut account = Account::restore(Decimal::MAX, dec!(0), false);
+        assert_eq!(account.deposit(&dec!(1), mode), Err(Overflow));
+        assert_eq!(account.get_available_funds(), &Decimal::MAX);
+        account
+            .deposit(&dec!(1), ArithmeticMode::Saturating)
+            .unwrap();
+        assert_eq!(account.get_available_funds(), &Decimal::MAX);
+    }
+}
@@ -0,0 +1,127 @@
+//! Module implements a data quality report of a transactions file,
+//! assembled without processing the transactions.
+
+use crate::proto::{self, OUTPUT_DECIMALS};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// Report of anomalies found in the input.
+///
+/// * `records` - number of records read.
+/// * `invalid_records` - number of records failed to be read.
+/// * `types` - number of records by transaction type, unknown types included.
+/// * `duplicate_ids` - number of deposits and withdrawals reusing a transaction id.
+/// * `unknown_references` - number of disputes, resolves and chargebacks
+///   referencing no earlier deposit or withdrawal.
+/// * `excess_precision` - number of amounts with more than `OUTPUT_DECIMALS` places.
+/// * `nonpositive_amounts` - number of zero or negative amounts.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Report {
+    pub records: u64,
+    pub invalid_records: u64,
+    pub types: BTreeMap<String, u64>,
+    pub duplicate_ids: u64,
+    pub unknown_references: u64,
+    pub excess_precision: u64,
+    pub nonpositive_amounts: u64,
+}
+
+/// Reads all the records from the `reader` and reports their anomalies.
+pub fn lint<T: std::io::Read>(reader: &mut csv::Reader<T>) -> Report {
+    let mut report = Report::default();
+    let mut ids = HashSet::new();
+
+    for result in proto::Transaction::read_many(reader) {
+        report.records += 1;
+        let record = match result {
+            Ok(record) => record,
+            Err(_) => {
+                report.invalid_records += 1;
+                continue;
+            }
+        };
+
+        *report.types.entry(record.kind.to_string()).or_default() += 1;
+        let id = record.transaction_id;
+        match &*record.kind {
+            "deposit" | "withdrawal" if !ids.insert(id) => report.duplicate_ids += 1,
+            "dispute" | "resolve" | "chargeback" if !ids.contains(&id) => {
+                report.unknown_references += 1
+            }
+            _ => (),
+        }
+
+        if let Some(amount) = record.amount {
+            if amount.normalize().scale() > OUTPUT_DECIMALS {
+                report.excess_precision += 1;
+            }
+            if amount <= Decimal::ZERO {
+                report.nonpositive_amounts += 1;
+            }
+        }
+    }
+
+    report
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rows = vec![
+            ("records".to_string(), self.records),
+            ("invalid records".to_string(), self.invalid_records),
+        ];
+        rows.extend(
+            self.types
+                .iter()
+                .map(|(kind, count)| (format!("type {}", kind), *count)),
+        );
+        rows.extend([
+            ("duplicate tx ids".to_string(), self.duplicate_ids),
+            ("unknown tx references".to_string(), self.unknown_references),
+            ("excess precision".to_string(), self.excess_precision),
+            ("nonpositive amounts".to_string(), self.nonpositive_amounts),
+        ]);
+
+        let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        for (name, count) in rows {
+            writeln!(f, "{:<width$}  {:>10}", name, count, width = width)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::ReaderBuilder;
+    use indoc::indoc;
+
+    #[test]
+    fn reporting_anomalies() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,1,1,2.0
+            withdrawal,1,2,0.000001
+            dispute,1,3,
+            resolve,1,1,
+            deposit,1,4,-1.0
+            deposit,x,5,1.0
+        "};
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let report = lint(&mut reader);
+
+        assert_eq!(report.records, 7);
+        assert_eq!(report.invalid_records, 1);
+        assert_eq!(report.types["deposit"], 3);
+        assert_eq!(report.types["dispute"], 1);
+        assert_eq!(report.duplicate_ids, 1);
+        assert_eq!(report.unknown_references, 1);
+        assert_eq!(report.excess_precision, 1);
+        assert_eq!(report.nonpositive_amounts, 1);
+        assert!(report
+            .to_string()
+            .contains("unknown tx references           1\n"));
+    }
+}
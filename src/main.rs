@@ -1,21 +1,716 @@
-use csv;
+use rust_decimal::Decimal;
 use std::env;
-use std::io;
-use std::path::PathBuf;
-use transactor::process;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+use transactor::diff::{diff, write_changes};
+use transactor::generate::generate;
+use transactor::input::{self, Compression, Quoting};
+use transactor::lint::lint;
+use transactor::models::ArithmeticMode;
+use transactor::models::Transaction;
+use transactor::processing::{
+    DisputePolicy, DuplicateTxPolicy, Fee, Histogram, LimitSpan, RedisputePolicy, WithdrawalLimit,
+};
+use transactor::proto::{self, Column, Grouping, ParseOptions, Rounding, ZeroAmountPolicy};
+use transactor::sink::{BincodeSink, CsvSink, TableSink};
+use transactor::{
+    canonicalize, output_columns, process_into, process_many, process_many_into, replay_into,
+    FlushInterval, ProcessConfig,
+};
 
-fn parse_args(args: &[String]) -> PathBuf {
-    if args.len() != 2 {
-        panic!("Invalid arguments. Usage: cargo run -- <transactions file path>")
+const USAGE: &str = "Usage:
+    cargo run -- [run] [options] <transactions file or .tar.gz archive path | - for stdin>...
+    cargo run -- generate --count N --clients C --seed S
+    cargo run -- lint [--quote CHAR] [--escape CHAR] <transactions file path | - for stdin>
+    cargo run -- replay <JSON transactions file path>
+    cargo run -- diff [--quote CHAR] [--escape CHAR] <old transactions file path> <new transactions file path>
+    cargo run -- canonicalize [--grouping us|eu] [--tolerant-amounts] [--preserve-scale] [--quote CHAR] [--escape CHAR] <input> <output>
+
+Run options:
+    --preserve-scale                      keep amounts at their input scale
+    --progress                            report progress to stderr
+    --workers N                           process with N worker threads, one per CPU by default, 1 for ordered logs
+    --deadline SECONDS                    stop reading after SECONDS and output partial accounts
+    --max-transactions N                  stop reading after N transactions and output partial accounts
+    --only-types TYPE,...                 process only transactions of the given types, skipping others
+    --flush-interval N|Ns                 write snapshots of the accounts every N transactions or N seconds
+    --snapshot-prefix PREFIX              write the snapshots to PREFIX-<unix time>-<transactions>.csv
+    --dedup                               reject repeated identical transactions
+    --dispute-policy strict|permissive    handling of disputes exceeding available funds
+    --no-disputes                         keep no history for inputs without disputes, rejecting any
+    --redispute allow|reject              handling of disputes of transactions resolved before
+    --duplicate-tx reject|keep-first      handling of deposits and withdrawals reusing a tx id, reject by default
+    --dead-letter PATH                    write transactions rejected by frozen accounts to PATH
+    --errors PATH                         write the rows rejected as invalid to PATH as JSON
+    --audit-log PATH                      write every applied transaction to PATH
+    --arithmetic checked|saturating       handling of overflowing balances
+    --max-scale N                         round balances to N decimal places after every transaction
+    --columns NAME,...                    output only the given columns in the given order
+    --strict-headers                      fail on unexpected, duplicate or missing columns
+    --grouping us|eu                      accept amounts with grouped thousands like 1,000.50 or 1.000,50
+    --tolerant-amounts                    accept amounts with currency like $4.00 or 4.00 USD
+    --zero-amounts reject|accept          handling of deposits and withdrawals of zero amount
+    --negative-deposits                   accept negative deposits as reversals debiting the account
+    --amount-scale-factor N               divide amounts by 10^N, e.g. 2 for integer cents
+    --cents                               amounts are integer cents, same as --amount-scale-factor 2
+    --since SECONDS                       apply only transactions timestamped at or after SECONDS
+    --until SECONDS                       apply only transactions timestamped before SECONDS
+    --recover                             drop the accounts of a failed worker and carry on
+    --continue-on-panic                   skip a transaction panicking in a worker, roll it back
+    --seed-accounts PATH                  start from the accounts of a prior output at PATH
+    --seed-disputes PATH                  restore open disputes (client,tx,held) of a prior run from PATH
+    --channel-bound N                     queue at most N transactions per worker
+    --throttle-depth N                    pause reading while a worker has N queued transactions
+    --guard-held                          reject transactions making held funds exceed deposits
+    --check-total                         warn of transactions making an account total negative
+    --dedupe-window N                     warn of deposits and withdrawals like one of the last N of the client
+    --dispute-window SECONDS              reject disputes of deposits processed over SECONDS ago
+    --withdrawal-limit AMOUNT             cap the total a client withdraws within a span, one of:
+    --withdrawal-window SECONDS           the withdrawals of the last SECONDS
+    --withdrawal-count N                  the last N withdrawals
+    --deposit-fee FEE                     deduct FEE from deposits, an amount or a percentage, e.g. 1%
+    --withdrawal-fee FEE                  debit FEE on top of withdrawals, an amount or a percentage
+    --hot-client-percent N                warn of clients with more than N% of transactions
+    --amount-histogram                    report a histogram of deposit and withdrawal amounts
+    --stats PATH                          write the amount histogram to PATH instead of stderr
+    --metrics PATH                        write the run metrics to PATH in Prometheus text format
+    --fail-on-frozen                      exit with status 1 if any account ends up frozen
+    --control PATH                        exit with status 1 if totals differ from the accounts at PATH
+    --control-tolerance AMOUNT            accept totals differing from the control by up to AMOUNT
+    --summary                             report totals across all output accounts to stderr
+    --explain CLIENT                      trace the transactions of CLIENT to stderr
+    --zstd                                inputs are zstd compressed, assumed for .zst files
+    --quote CHAR                          quote character of inputs and csv files, \" by default
+    --escape CHAR                         character escaping quotes instead of doubling them, e.g. \\
+    --rounding half-up|half-even|truncate rounding of amounts beyond 4 decimal places
+    --grouped                             input has all transactions of a client contiguous
+    --chunk-clients N                     with --grouped, output and evict completed clients N at a time
+    --namespace-files                     treat client ids of every input file as separate clients
+    --shard-output PREFIX                 write the accounts of every worker to PREFIX-N.csv instead
+    --normalize-ids                       remap client ids to 0..N in sorted order
+    --id-map PATH                         write the old,new client ids of --normalize-ids to PATH
+    --partition-by-lock PREFIX            write the accounts to PREFIX-locked.csv and PREFIX-unlocked.csv instead
+    --format csv|wide|bincode|table       output format of the accounts, wide for csv of all columns
+    --count-transactions                  count deposits and withdrawals of accounts for wide format
+    --suppress-zero                       omit unlocked accounts with zero total
+    --lock-reason                         output the chargeback tx id or admin for locked accounts
+    --client-width N                      zero-pad client ids to N digits, e.g. 00001 for 5
+    --split-amounts                       output amounts as integer and 4 digit fraction columns, e.g. available_int
+    --enrich PATH                         append the name and region of clients from a client,name,region file";
+
+/// Output format of the accounts.
+enum Format {
+    Csv,
+    /// CSV of all the columns (see `Column::ALL`).
+    Wide,
+    Bincode,
+    /// Aligned plain text table for review in a terminal.
+    Table,
+}
+
+/// Command selected by the command line arguments.
+enum Command {
+    /// Process transactions from `inputs` and output the accounts in `format`.
+    Run {
+        inputs: Vec<PathBuf>,
+        options: Box<ProcessConfig>,
+        format: Format,
+        stats: Option<PathBuf>,
+        metrics: Option<PathBuf>,
+        fail_on_frozen: bool,
+        summary: bool,
+        compression: Option<Compression>,
+    },
+    /// Report anomalies of the transactions in `input` without processing them.
+    Lint { input: PathBuf, quoting: Quoting },
+    /// Process the JSON array of parsed transactions in `input`, e.g. of a bug report.
+    Replay { input: PathBuf },
+    /// Process `old` and `new` separately and output the accounts that differ.
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        quoting: Quoting,
+    },
+    /// Rewrite the transactions in `input` in the canonical form to `output`.
+    Canonicalize {
+        input: PathBuf,
+        output: PathBuf,
+        options: ParseOptions,
+        quoting: Quoting,
+    },
+    /// Output `count` random transactions across `clients` clients generated from `seed`.
+    Generate {
+        count: usize,
+        clients: u16,
+        seed: u64,
+    },
+}
+
+/// Number of records between progress reports of `--progress`.
+const PROGRESS_INTERVAL: usize = 1_000_000;
+
+/// Lowest bound and number of bounds of the `--amount-histogram` buckets,
+/// i.e. the smallest output amount up to ten million.
+const HISTOGRAM_LOWEST: Decimal = Decimal::from_parts(1, 0, 0, false, 4);
+const HISTOGRAM_BOUNDS: usize = 12;
+
+fn parse_dispute_policy(value: &str) -> DisputePolicy {
+    match value {
+        "strict" => DisputePolicy::Strict,
+        "permissive" => DisputePolicy::Permissive,
+        other => panic!("Unknown dispute policy {}. {}", other, USAGE),
+    }
+}
+
+fn parse_redispute_policy(value: &str) -> RedisputePolicy {
+    match value {
+        "allow" => RedisputePolicy::Allow,
+        "reject" => RedisputePolicy::Reject,
+        other => panic!("Unknown redispute policy {}. {}", other, USAGE),
+    }
+}
+
+fn parse_duplicate_tx_policy(value: &str) -> DuplicateTxPolicy {
+    match value {
+        "reject" => DuplicateTxPolicy::Reject,
+        "keep-first" => DuplicateTxPolicy::KeepFirst,
+        other => panic!("Unknown duplicate tx policy {}. {}", other, USAGE),
+    }
+}
+
+fn parse_arithmetic_mode(value: &str) -> ArithmeticMode {
+    match value {
+        "checked" => ArithmeticMode::Checked,
+        "saturating" => ArithmeticMode::Saturating,
+        other => panic!("Unknown arithmetic mode {}. {}", other, USAGE),
+    }
+}
+
+fn parse_rounding(value: &str) -> Rounding {
+    match value {
+        "half-up" => Rounding::HalfUp,
+        "half-even" => Rounding::HalfEven,
+        "truncate" => Rounding::Truncate,
+        other => panic!("Unknown rounding {}. {}", other, USAGE),
+    }
+}
+
+fn parse_grouping(value: &str) -> Grouping {
+    match value {
+        "us" => Grouping::Us,
+        "eu" => Grouping::Eu,
+        other => panic!("Unknown grouping {}. {}", other, USAGE),
+    }
+}
+
+fn parse_zero_amount_policy(value: &str) -> ZeroAmountPolicy {
+    match value {
+        "reject" => ZeroAmountPolicy::Reject,
+        "accept" => ZeroAmountPolicy::Accept,
+        other => panic!("Unknown zero amount policy {}. {}", other, USAGE),
+    }
+}
+
+fn parse_format(value: &str) -> Format {
+    match value {
+        "csv" => Format::Csv,
+        "wide" => Format::Wide,
+        "bincode" => Format::Bincode,
+        "table" => Format::Table,
+        other => panic!("Unknown format {}. {}", other, USAGE),
+    }
+}
+
+fn parse_fee(flag: &str, value: &str) -> Fee {
+    match value.strip_suffix('%') {
+        Some(percent) => Fee::Percent(parse_number(flag, percent)),
+        None => Fee::Flat(parse_number(flag, value)),
+    }
+}
+
+fn parse_flush_interval(flag: &str, value: &str) -> FlushInterval {
+    match value.strip_suffix('s') {
+        Some(seconds) => FlushInterval::Time(Duration::from_secs(parse_number(flag, seconds))),
+        None => match parse_number(flag, value) {
+            0 => panic!("Invalid value {} of {}. {}", value, flag, USAGE),
+            n => FlushInterval::Transactions(n),
+        },
+    }
+}
+
+fn parse_char(flag: &str, value: &str) -> u8 {
+    match value.as_bytes() {
+        [char] => *char,
+        _ => panic!("Invalid value {} of {}. {}", value, flag, USAGE),
+    }
+}
+
+fn parse_number<T: FromStr>(flag: &str, value: &str) -> T {
+    match value.parse() {
+        Ok(number) => number,
+        Err(_) => panic!("Invalid value {} of {}. {}", value, flag, USAGE),
+    }
+}
+
+fn parse_args(args: &[String]) -> Command {
+    match args.get(1).map(String::as_str) {
+        Some("generate") => parse_generate_args(&args[2..]),
+        Some("run") => parse_run_args(&args[2..]),
+        Some("canonicalize") => parse_canonicalize_args(&args[2..]),
+        Some("lint") => match parse_quoting_args(&args[2..]) {
+            (quoting, [input]) => Command::Lint {
+                input: PathBuf::from(input),
+                quoting,
+            },
+            _ => panic!("Invalid arguments. {}", USAGE),
+        },
+        Some("replay") => match &args[2..] {
+            [input] => Command::Replay {
+                input: PathBuf::from(input),
+            },
+            _ => panic!("Invalid arguments. {}", USAGE),
+        },
+        Some("diff") => match parse_quoting_args(&args[2..]) {
+            (quoting, [old, new]) => Command::Diff {
+                old: PathBuf::from(old),
+                new: PathBuf::from(new),
+                quoting,
+            },
+            _ => panic!("Invalid arguments. {}", USAGE),
+        },
+        _ => parse_run_args(&args[1..]),
+    }
+}
+
+/// Parses the `--quote` and `--escape` options of a subcommand out of the `args`,
+/// returning their quoting and the other args in order.
+fn parse_quoting_args(args: &[String]) -> (Quoting, &[String]) {
+    let mut quoting = Quoting::default();
+    let mut args = args;
+    loop {
+        match args {
+            [flag, value, rest @ ..] if flag == "--quote" => {
+                quoting.quote = parse_char(flag, value);
+                args = rest;
+            }
+            [flag, value, rest @ ..] if flag == "--escape" => {
+                quoting.escape = Some(parse_char(flag, value));
+                args = rest;
+            }
+            _ => return (quoting, args),
+        }
+    }
+}
+
+fn parse_canonicalize_args(args: &[String]) -> Command {
+    let mut options = ParseOptions::default();
+    let mut quoting = Quoting::default();
+    let mut paths = vec![];
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = || match args.next() {
+            Some(value) => value.as_str(),
+            None => panic!("Missing value of {}. {}", arg, USAGE),
+        };
+        match arg.as_str() {
+            "--grouping" => options.grouping = Some(parse_grouping(value())),
+            "--tolerant-amounts" => options.tolerant_amounts = true,
+            "--preserve-scale" => options.preserve_scale = true,
+            "--quote" => quoting.quote = parse_char(arg, value()),
+            "--escape" => quoting.escape = Some(parse_char(arg, value())),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+
+    match <[PathBuf; 2]>::try_from(paths) {
+        Ok([input, output]) => Command::Canonicalize {
+            input,
+            output,
+            options,
+            quoting,
+        },
+        Err(_) => panic!("Invalid arguments. {}", USAGE),
+    }
+}
+
+fn parse_generate_args(args: &[String]) -> Command {
+    let (mut count, mut clients, mut seed) = (None, None, None);
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let value = match args.next() {
+            Some(value) => value.as_str(),
+            None => panic!("Missing value of {}. {}", arg, USAGE),
+        };
+
+        match arg.as_str() {
+            "--count" => count = Some(parse_number(arg, value)),
+            "--clients" => clients = Some(parse_number(arg, value)),
+            "--seed" => seed = Some(parse_number(arg, value)),
+            _ => panic!("Invalid arguments. {}", USAGE),
+        }
+    }
+
+    match (count, clients, seed) {
+        (Some(count), Some(clients), Some(seed)) => Command::Generate {
+            count,
+            clients,
+            seed,
+        },
+        _ => panic!("Invalid arguments. {}", USAGE),
+    }
+}
+
+fn parse_run_args(args: &[String]) -> Command {
+    let mut options = ProcessConfig::default();
+    let mut format = Format::Csv;
+    let mut stats = None;
+    let mut metrics = None;
+    let mut histogram = false;
+    let mut fail_on_frozen = false;
+    let mut summary = false;
+    let mut compression = None;
+    let mut withdrawal_limit = None;
+    let mut withdrawal_span = None;
+    let mut inputs = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = || match args.next() {
+            Some(value) => value.as_str(),
+            None => panic!("Missing value of {}. {}", arg, USAGE),
+        };
+
+        match arg.as_str() {
+            "--preserve-scale" => options.parsing.preserve_scale = true,
+            "--strict-headers" => options.parsing.strict_headers = true,
+            "--grouping" => options.parsing.grouping = Some(parse_grouping(value())),
+            "--tolerant-amounts" => options.parsing.tolerant_amounts = true,
+            "--negative-deposits" => options.parsing.negative_deposits = true,
+            "--amount-scale-factor" => options.parsing.amount_scale = parse_number(arg, value()),
+            "--cents" => options.parsing.amount_scale = 2,
+            "--zero-amounts" => options.parsing.zero_amounts = parse_zero_amount_policy(value()),
+            "--since" => {
+                options.parsing.window.get_or_insert_default().since =
+                    Some(parse_number(arg, value()))
+            }
+            "--until" => {
+                options.parsing.window.get_or_insert_default().until =
+                    Some(parse_number(arg, value()))
+            }
+            "--progress" => options.progress = Some(PROGRESS_INTERVAL),
+            "--workers" => options.workers = Some(parse_number(arg, value())),
+            "--max-transactions" => options.max_transactions = Some(parse_number(arg, value())),
+            "--only-types" => match proto::parse_type_list(value()) {
+                Ok(types) => options.only_types = Some(types),
+                Err(err) => panic!("Invalid --only-types: {}. {}", err, USAGE),
+            },
+            "--flush-interval" => options.flush_interval = Some(parse_flush_interval(arg, value())),
+            "--snapshot-prefix" => options.snapshot_prefix = Some(PathBuf::from(value())),
+            "--deadline" => {
+                let seconds = parse_number(arg, value());
+                options.deadline = Some(Duration::from_secs(seconds))
+            }
+            "--recover" => options.processing.recover = true,
+            "--continue-on-panic" => options.processing.continue_on_panic = true,
+            "--grouped" => options.grouped = true,
+            "--chunk-clients" => options.chunk_clients = Some(parse_number(arg, value())),
+            "--namespace-files" => options.namespace_files = true,
+            "--suppress-zero" => options.suppress_zero = true,
+            "--lock-reason" => options.lock_reason = true,
+            "--client-width" => options.client_width = Some(parse_number(arg, value())),
+            "--format" => format = parse_format(value()),
+            "--count-transactions" => options.processing.count_transactions = true,
+            "--dedup" => options.processing.dedup_fingerprints = true,
+            "--guard-held" => options.processing.guard_held = true,
+            "--check-total" => options.processing.check_total = true,
+            "--dedupe-window" => {
+                options.processing.dedupe_window = Some(parse_number(arg, value()))
+            }
+            "--dispute-policy" => options.processing.dispute_policy = parse_dispute_policy(value()),
+            "--no-disputes" => options.processing.no_disputes = true,
+            "--redispute" => options.processing.redispute_policy = parse_redispute_policy(value()),
+            "--duplicate-tx" => {
+                options.processing.duplicate_tx_policy = parse_duplicate_tx_policy(value())
+            }
+            "--arithmetic" => options.processing.arithmetic_mode = parse_arithmetic_mode(value()),
+            "--max-scale" => options.processing.max_scale = Some(parse_number(arg, value())),
+            "--rounding" => options.rounding = parse_rounding(value()),
+            "--columns" => match Column::parse_list(value()) {
+                Ok(columns) => options.columns = Some(columns),
+                Err(err) => panic!("Invalid --columns: {}. {}", err, USAGE),
+            },
+            "--seed-accounts" => options.seed_accounts = Some(PathBuf::from(value())),
+            "--seed-disputes" => options.seed_disputes = Some(PathBuf::from(value())),
+            "--channel-bound" => {
+                options.processing.channel_bound = Some(parse_number(arg, value()))
+            }
+            "--dispute-window" => {
+                let seconds = parse_number(arg, value());
+                options.processing.dispute_window = Some(Duration::from_secs(seconds))
+            }
+            "--deposit-fee" => options.processing.deposit_fee = Some(parse_fee(arg, value())),
+            "--withdrawal-fee" => options.processing.withdrawal_fee = Some(parse_fee(arg, value())),
+            "--withdrawal-limit" => withdrawal_limit = Some(parse_number(arg, value())),
+            "--withdrawal-window" => {
+                let seconds = parse_number(arg, value());
+                withdrawal_span = Some(LimitSpan::Time(Duration::from_secs(seconds)))
+            }
+            "--withdrawal-count" => {
+                withdrawal_span = Some(LimitSpan::Withdrawals(parse_number(arg, value())))
+            }
+            "--hot-client-percent" => {
+                options.processing.hot_client_percent = Some(parse_number(arg, value()))
+            }
+            "--throttle-depth" => {
+                options.processing.throttle_depth = Some(parse_number(arg, value()))
+            }
+            "--audit-log" => options.audit_log = Some(PathBuf::from(value())),
+            "--errors" => options.errors = Some(PathBuf::from(value())),
+            "--shard-output" => options.shard_output = Some(PathBuf::from(value())),
+            "--normalize-ids" => options.normalize_ids = true,
+            "--id-map" => options.id_map = Some(PathBuf::from(value())),
+            "--partition-by-lock" => options.partition_by_lock = Some(PathBuf::from(value())),
+            "--dead-letter" => options.dead_letter = Some(PathBuf::from(value())),
+            "--explain" => options.explain = Some(parse_number(arg, value())),
+            "--amount-histogram" => histogram = true,
+            "--stats" => stats = Some(PathBuf::from(value())),
+            "--metrics" => metrics = Some(PathBuf::from(value())),
+            "--fail-on-frozen" => fail_on_frozen = true,
+            "--control" => options.control = Some(PathBuf::from(value())),
+            "--control-tolerance" => options.control_tolerance = parse_number(arg, value()),
+            "--summary" => summary = true,
+            "--zstd" => compression = Some(Compression::Zstd),
+            "--split-amounts" => options.split_amounts = true,
+            "--enrich" => options.enrich = Some(PathBuf::from(value())),
+            "--quote" => options.quoting.quote = parse_char(arg, value()),
+            "--escape" => options.quoting.escape = Some(parse_char(arg, value())),
+            flag if flag.starts_with("--") => {
+                panic!("Unknown option {}. {}", flag, USAGE)
+            }
+            path => inputs.push(PathBuf::from(path)),
+        }
+    }
+
+    if matches!(format, Format::Bincode) && options.columns.is_some() {
+        panic!("--columns only applies to csv format. {}", USAGE);
+    }
+
+    if matches!(format, Format::Wide) {
+        if options.columns.is_some() {
+            panic!("--columns only applies to csv format. {}", USAGE);
+        }
+        options.columns = Some(Column::ALL.to_vec());
+    }
+
+    if matches!(format, Format::Bincode | Format::Table) && options.client_width.is_some() {
+        panic!("--client-width only applies to csv format. {}", USAGE);
+    }
+
+    if matches!(format, Format::Bincode | Format::Table) && options.split_amounts {
+        panic!("--split-amounts only applies to csv format. {}", USAGE);
+    }
+
+    if matches!(format, Format::Bincode | Format::Table) && options.enrich.is_some() {
+        panic!("--enrich only applies to csv format. {}", USAGE);
+    }
+
+    if matches!(format, Format::Bincode | Format::Table) && options.shard_output.is_some() {
+        panic!("--shard-output only applies to csv format. {}", USAGE);
+    }
+
+    if matches!(format, Format::Bincode | Format::Table) && options.partition_by_lock.is_some() {
+        panic!("--partition-by-lock only applies to csv format. {}", USAGE);
+    }
+
+    if let Err(err) = options.validate() {
+        panic!("Invalid options: {}. {}", err, USAGE);
+    }
+
+    if matches!(format, Format::Bincode | Format::Table) && options.chunk_clients.is_some() {
+        panic!("--chunk-clients only applies to csv format. {}", USAGE);
+    }
+
+    if inputs.is_empty() {
+        panic!("Invalid arguments. {}", USAGE);
+    }
+
+    options.processing.withdrawal_limit = match (withdrawal_limit, withdrawal_span) {
+        (Some(amount), Some(span)) => Some(WithdrawalLimit { amount, span }),
+        (None, None) => None,
+        _ => panic!(
+            "--withdrawal-limit requires --withdrawal-window or --withdrawal-count. {}",
+            USAGE
+        ),
+    };
+
+    if histogram || stats.is_some() {
+        options.processing.amount_histogram =
+            Some(Histogram::log_scale(HISTOGRAM_LOWEST, HISTOGRAM_BOUNDS));
+    }
+
+    Command::Run {
+        inputs,
+        options: Box::new(options),
+        format,
+        stats,
+        metrics,
+        fail_on_frozen,
+        summary,
+        compression,
     }
+}
+
+/// Opens a reader of the input file at `path` or of stdin if the path is `-`.
+fn open_input(
+    path: &Path,
+    compression: Option<Compression>,
+    quoting: Quoting,
+) -> csv::Reader<Box<dyn io::Read>> {
+    input::open(path, compression, quoting).expect("Failed to read input file")
+}
 
-    PathBuf::from(&args[1])
+/// Opens readers of the entries of the archive at `path` if it is an archive,
+/// otherwise a reader of the input file (see `open_input`).
+fn open_inputs(
+    path: &Path,
+    compression: Option<Compression>,
+    quoting: Quoting,
+) -> Vec<csv::Reader<Box<dyn io::Read>>> {
+    match input::is_archive(path) {
+        true => input::open_archive(path, quoting).expect("Failed to read input archive"),
+        false => vec![open_input(path, compression, quoting)],
+    }
 }
 
 fn main() {
     let args: Vec<_> = env::args().collect();
-    let fpath = parse_args(&args);
-    let mut reader = csv::Reader::from_path(&fpath).expect("Failed to read input file");
     let mut writer = csv::Writer::from_writer(io::stdout());
-    process(&mut reader, &mut writer);
+
+    match parse_args(&args) {
+        Command::Run {
+            inputs,
+            options,
+            format,
+            stats,
+            metrics: metrics_path,
+            fail_on_frozen,
+            summary,
+            compression,
+        } => {
+            let quoting = options.quoting;
+            let mut readers: Vec<_> = inputs
+                .iter()
+                .flat_map(|path| open_inputs(path, compression, quoting))
+                .collect();
+            let mut writer = quoting.writer().from_writer(io::stdout());
+            let result = match format {
+                Format::Csv | Format::Wide => process_many(&mut readers, &mut writer, &options),
+                Format::Bincode => {
+                    process_many_into(&mut readers, &mut BincodeSink::new(io::stdout()), &options)
+                }
+                Format::Table => {
+                    let mut sink = TableSink::new(io::stdout(), output_columns(&options));
+                    process_many_into(&mut readers, &mut sink, &options)
+                }
+            };
+            let metrics = result.unwrap_or_else(|err| panic!("Failed to process: {}", err));
+            if metrics.partial {
+                eprintln!("Warning: input not read to the end, the accounts are incomplete");
+            }
+            if let Some(histogram) = &metrics.amount_histogram {
+                match stats {
+                    Some(path) => {
+                        let mut file = File::create(path).expect("Failed to write stats file");
+                        write!(file, "{}", histogram).expect("Failed to write stats file");
+                    }
+                    None => eprint!("{}", histogram),
+                }
+            }
+            if let Some(path) = metrics_path {
+                std::fs::write(path, metrics.to_prometheus())
+                    .expect("Failed to write metrics file");
+            }
+            if summary {
+                if metrics.summary.overflow {
+                    eprintln!("Error: summary totals overflow the decimal range");
+                    process::exit(1);
+                }
+                eprintln!("Summary: {}", metrics.summary);
+            }
+            if !metrics.control_mismatches.is_empty() {
+                for mismatch in &metrics.control_mismatches {
+                    eprintln!("Mismatch: {}", mismatch);
+                }
+                eprintln!(
+                    "Error: {} totals differ from the control",
+                    metrics.control_mismatches.len()
+                );
+                process::exit(1);
+            }
+            if fail_on_frozen && metrics.frozen_accounts > 0 {
+                eprintln!("Error: {} frozen accounts", metrics.frozen_accounts);
+                process::exit(1);
+            }
+        }
+        Command::Canonicalize {
+            input,
+            output,
+            options,
+            quoting,
+        } => {
+            let mut writer = quoting
+                .writer()
+                .from_path(output)
+                .expect("Failed to create output file");
+            let (_, skipped) = canonicalize(
+                &mut open_input(&input, None, quoting),
+                &mut writer,
+                &options,
+            )
+            .unwrap_or_else(|err| panic!("Failed to canonicalize: {}", err));
+            if skipped > 0 {
+                eprintln!("Warning: skipped {} invalid records", skipped);
+            }
+        }
+        Command::Lint { input, quoting } => {
+            print!("{}", lint(&mut open_input(&input, None, quoting)))
+        }
+        Command::Replay { input } => {
+            let file = File::open(input).expect("Failed to read input file");
+            let transactions = Transaction::read_json(io::BufReader::new(file))
+                .unwrap_or_else(|err| panic!("Invalid transactions: {}", err));
+            replay_into(
+                transactions,
+                &mut CsvSink::new(&mut writer, None),
+                &ProcessConfig::default(),
+            )
+            .unwrap_or_else(|err| panic!("Failed to process: {}", err));
+        }
+        Command::Diff { old, new, quoting } => {
+            let compute = |path: &Path| {
+                let mut accounts = Vec::new();
+                process_into(
+                    &mut open_input(path, None, quoting),
+                    &mut accounts,
+                    &ProcessConfig::default(),
+                )
+                .unwrap_or_else(|err| panic!("Failed to process: {}", err));
+                accounts
+            };
+            let mut writer = quoting.writer().from_writer(io::stdout());
+            write_changes(&mut writer, &diff(compute(&old), compute(&new)))
+                .expect("Failed to write output");
+        }
+        Command::Generate {
+            count,
+            clients,
+            seed,
+        } => generate(&mut writer, count, clients, seed).expect("Failed to write transactions"),
+    }
 }
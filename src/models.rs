@@ -1,45 +1,117 @@
+//! Module defines transactor data model.
+//!
+//! Accounts and ids are defined by the core `ledger` module and re-exported here.
+
 use crate::proto;
 use rust_decimal::Decimal;
-use std::hash::Hash;
+use serde::{Deserialize, Serialize};
 use std::iter::Iterator;
+use std::str::FromStr;
+
+pub use crate::ledger::{Account, ArithmeticMode, ClientId, LockReason, Overflow, TransactionId};
 
-/// Module defines transactor data model.
+impl TryFrom<u64> for ClientId {
+    type Error = proto::ParseError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        u16::try_from(value)
+            .map(ClientId::new)
+            .map_err(|_| proto::ParseError::ClientIdOutOfRange { client_id: value })
+    }
+}
 
-/// Type-safe client id.
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
-pub struct ClientId(u16);
+impl FromStr for ClientId {
+    type Err = proto::ParseError;
 
-impl ClientId {
-    pub fn new(inner: u16) -> ClientId {
-        ClientId(inner)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ClientId::try_from(parse_id(s)?)
     }
 }
 
-/// Type-safe transaction id.
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
-pub struct TransactionId(u32);
+impl TryFrom<u64> for TransactionId {
+    type Error = proto::ParseError;
 
-impl TransactionId {
-    pub fn new(inner: u32) -> TransactionId {
-        TransactionId(inner)
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        u32::try_from(value).map(TransactionId::new).map_err(|_| {
+            proto::ParseError::TransactionIdOutOfRange {
+                transaction_id: value,
+            }
+        })
     }
 }
 
+impl FromStr for TransactionId {
+    type Err = proto::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TransactionId::try_from(parse_id(s)?)
+    }
+}
+
+/// Parses the raw value of an id, the range is validated by the id type.
+fn parse_id(s: &str) -> Result<u64, proto::ParseError> {
+    s.trim().parse().map_err(|_| proto::ParseError::InvalidId {
+        value: s.to_string(),
+    })
+}
+
 /// Transaction meta information.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Meta {
     pub client_id: ClientId,
     pub transaction_id: TransactionId,
 }
 
 /// Transaction model.
-#[derive(Debug, Clone)]
+///
+/// A dispute may be limited to a portion of the disputed transaction by its
+/// `amount`, a resolve or chargeback with an `amount` settles the open dispute
+/// of that portion. Without an amount, a dispute covers the whole undisputed rest
+/// of the transaction and a resolve or chargeback settles all its open disputes.
+///
+/// A correction changes the amount of the deposit or withdrawal `original_tx`
+/// of the client to `new_amount`, adjusting available funds by the difference.
+///
+/// A deposit of negative amount reverses an earlier deposit, debiting available
+/// funds as a withdrawal does but keeping its type. Such deposits are only read
+/// if accepted (see `proto::ParseOptions::negative_deposits`) and are not disputable.
+///
+/// Transactions serialize tagged by their lowercase `type`, e.g. to JSON
+/// (see `Transaction::read_json`).
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum Transaction {
-    Deposit { meta: Meta, amount: Decimal },
-    Withdrawal { meta: Meta, amount: Decimal },
-    Dispute { meta: Meta },
-    Resolve { meta: Meta },
-    Chargeback { meta: Meta },
+    Deposit {
+        meta: Meta,
+        amount: Decimal,
+    },
+    Withdrawal {
+        meta: Meta,
+        amount: Decimal,
+    },
+    Correct {
+        meta: Meta,
+        original_tx: TransactionId,
+        new_amount: Decimal,
+    },
+    Dispute {
+        meta: Meta,
+        amount: Option<Decimal>,
+    },
+    Resolve {
+        meta: Meta,
+        amount: Option<Decimal>,
+    },
+    Chargeback {
+        meta: Meta,
+        amount: Option<Decimal>,
+    },
+    Freeze {
+        meta: Meta,
+    },
+    Unfreeze {
+        meta: Meta,
+    },
 }
 
 impl Transaction {
@@ -47,94 +119,183 @@ impl Transaction {
     pub fn read_many<'a, T: std::io::Read>(
         reader: &'a mut csv::Reader<T>,
     ) -> Box<dyn Iterator<Item = Result<Transaction, proto::ParseError>> + 'a> {
-        let records = proto::Transaction::read_many(reader);
-        let transactions = records.map(|result| {
+        Transaction::read_many_with(reader, &proto::ParseOptions::default())
+    }
+
+    /// Reads transactions from a given `csv::Reader` according to `options`.
+    pub fn read_many_with<'a, T: std::io::Read>(
+        reader: &'a mut csv::Reader<T>,
+        options: &proto::ParseOptions,
+    ) -> Box<dyn Iterator<Item = Result<Transaction, proto::ParseError>> + 'a> {
+        let options = options.clone();
+        let records = proto::Transaction::read_many_with(reader, &options);
+        let transactions = records.map(move |result| {
             let record = result?;
-            record.to_transaction()
+            record
+                .to_transaction_with(&options)
+                .map_err(|err| match &record.row {
+                    Some(row) => err.in_row(row.clone()),
+                    None => err,
+                })
         });
         Box::new(transactions)
     }
 
+    /// Reads a JSON array of transactions, already parsed and validated,
+    /// e.g. to replay the exact sequence of a bug report (see `crate::replay_into`).
+    pub fn read_json<T: std::io::Read>(reader: T) -> serde_json::Result<Vec<Transaction>> {
+        serde_json::from_reader(reader)
+    }
+
+    /// Converts a raw `proto::Transaction` record into a transaction.
+    pub fn from_proto(record: &proto::Transaction) -> Result<Transaction, proto::ParseError> {
+        record.to_transaction()
+    }
+
+    /// Converts the transaction into a raw `proto::Transaction` record as read
+    /// from the input. The client id namespace and timestamp are not kept.
+    pub fn to_proto(&self) -> proto::Transaction {
+        let meta = self.meta();
+        proto::Transaction {
+            kind: self.kind().into(),
+            client_id: meta.client_id.value().into(),
+            transaction_id: meta.transaction_id.value().into(),
+            amount: self.amount(),
+            original_transaction_id: match self {
+                Transaction::Correct { original_tx, .. } => Some(original_tx.value().into()),
+                _ => None,
+            },
+            timestamp: None,
+            row: None,
+        }
+    }
+
     /// Returns transaction metadata.
     pub fn meta(&self) -> &Meta {
         match self {
             Transaction::Deposit { meta: m, .. } => m,
             Transaction::Withdrawal { meta: m, .. } => m,
+            Transaction::Correct { meta: m, .. } => m,
             Transaction::Dispute { meta: m, .. } => m,
             Transaction::Resolve { meta: m, .. } => m,
             Transaction::Chargeback { meta: m, .. } => m,
+            Transaction::Freeze { meta: m, .. } => m,
+            Transaction::Unfreeze { meta: m, .. } => m,
         }
     }
-}
 
-/// Client Account model.
-#[derive(Debug, Clone)]
-pub struct Account {
-    available_funds: Decimal,
-    held_funds: Decimal,
-    is_locked: bool,
-}
+    /// Moves the transaction into the client id `namespace` (see `ClientId`).
+    pub fn namespaced(mut self, namespace: u16) -> Transaction {
+        let meta = match &mut self {
+            Transaction::Deposit { meta: m, .. } => m,
+            Transaction::Withdrawal { meta: m, .. } => m,
+            Transaction::Correct { meta: m, .. } => m,
+            Transaction::Dispute { meta: m, .. } => m,
+            Transaction::Resolve { meta: m, .. } => m,
+            Transaction::Chargeback { meta: m, .. } => m,
+            Transaction::Freeze { meta: m, .. } => m,
+            Transaction::Unfreeze { meta: m, .. } => m,
+        };
+        meta.client_id = ClientId::namespaced(namespace, meta.client_id.value());
+        self
+    }
 
-impl Account {
-    /// Creates new unlocked account with zero funds.
-    pub fn new() -> Account {
-        Account {
-            available_funds: Decimal::ZERO,
-            held_funds: Decimal::ZERO,
-            is_locked: false,
+    /// Returns transaction type name as used in the input.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Transaction::Deposit { .. } => "deposit",
+            Transaction::Withdrawal { .. } => "withdrawal",
+            Transaction::Correct { .. } => "correct",
+            Transaction::Dispute { .. } => "dispute",
+            Transaction::Resolve { .. } => "resolve",
+            Transaction::Chargeback { .. } => "chargeback",
+            Transaction::Freeze { .. } => "freeze",
+            Transaction::Unfreeze { .. } => "unfreeze",
         }
     }
 
-    pub fn is_frozen(&self) -> bool {
-        self.is_locked
+    /// Whether the transaction can be referenced by disputes and corrections,
+    /// i.e. is a withdrawal or a deposit but a negative one, which is a reversal.
+    /// Only these are kept in the history.
+    pub fn is_disputable(&self) -> bool {
+        match self {
+            Transaction::Deposit { amount, .. } => *amount >= Decimal::ZERO,
+            Transaction::Withdrawal { .. } => true,
+            _ => false,
+        }
     }
 
-    /// Returns available funds.
-    pub fn get_available_funds(&self) -> &Decimal {
-        &self.available_funds
+    /// Returns transaction amount if the transaction carries one.
+    pub fn amount(&self) -> Option<Decimal> {
+        match self {
+            Transaction::Deposit { amount: a, .. } => Some(*a),
+            Transaction::Withdrawal { amount: a, .. } => Some(*a),
+            Transaction::Correct { new_amount: a, .. } => Some(*a),
+            Transaction::Dispute { amount: a, .. } => *a,
+            Transaction::Resolve { amount: a, .. } => *a,
+            Transaction::Chargeback { amount: a, .. } => *a,
+            _ => None,
+        }
     }
+}
 
-    /// Deposits the given `amount` to the account.
-    pub fn deposit(&mut self, amount: &Decimal) {
-        self.available_funds += amount;
-    }
+impl TryFrom<proto::Transaction> for Transaction {
+    type Error = proto::ParseError;
 
-    /// Withdraws the given `amount` from the account.
-    pub fn withdraw(&mut self, amount: &Decimal) {
-        assert!(
-            self.available_funds >= *amount,
-            "Attempting to withdraw more than the account has"
-        );
-        self.available_funds -= amount;
+    fn try_from(record: proto::Transaction) -> Result<Self, Self::Error> {
+        Transaction::from_proto(&record)
     }
+}
 
-    /// Holds the specified fund amount.
-    pub fn hold_funds(&mut self, amount: &Decimal) {
-        self.available_funds -= amount;
-        self.held_funds += amount;
+impl Account {
+    /// Converts account to a proto representation.
+    /// A total overflowing the `Decimal` range saturates (see `to_proto_with`).
+    pub fn to_proto(&self, client_id: &ClientId) -> proto::Account {
+        match self.to_proto_with(client_id, ArithmeticMode::Saturating) {
+            Ok(record) => record,
+            Err(Overflow) => unreachable!("Saturating arithmetic never overflows"),
+        }
     }
 
-    /// Release the previously held specified fund amount.
-    pub fn release_funds(&mut self, amount: &Decimal) {
-        self.available_funds += amount;
-        self.held_funds -= amount;
+    /// Same as `to_proto` but computes the total according to `mode`, so it fails
+    /// if the available and held funds add up beyond the `Decimal` range in checked mode.
+    pub fn to_proto_with(
+        &self,
+        client_id: &ClientId,
+        mode: ArithmeticMode,
+    ) -> Result<proto::Account, Overflow> {
+        Ok(proto::Account {
+            file: client_id.namespace(),
+            client_id: client_id.value(),
+            available_funds: *self.get_available_funds(),
+            held_funds: *self.get_held_funds(),
+            total_funds: mode.add(*self.get_available_funds(), *self.get_held_funds())?,
+            is_locked: self.is_frozen(),
+            pending_credits: *self.get_pending_credits(),
+            fees: *self.get_fees(),
+            deposits: None,
+            withdrawals: None,
+            lock_reason: self.lock_reason().map(|r| r.to_string()),
+        })
     }
+}
 
-    /// Charges the previously held specified fund amount again and lock the account.
-    pub fn chargeback(&mut self, amount: &Decimal) {
-        self.held_funds -= amount;
-        self.is_locked = true;
-    }
+impl TryFrom<&proto::Account> for Account {
+    type Error = proto::ParseError;
 
-    /// Converts account to a proto representation.
-    pub fn to_proto(&self, client_id: &ClientId) -> proto::Account {
-        proto::Account {
-            client_id: client_id.0,
-            available_funds: self.available_funds,
-            held_funds: self.held_funds,
-            total_funds: self.available_funds + self.held_funds,
-            is_locked: self.is_locked,
+    /// Restores an account from its proto representation validating its total.
+    fn try_from(record: &proto::Account) -> Result<Self, Self::Error> {
+        if record.available_funds.checked_add(record.held_funds) != Some(record.total_funds) {
+            return Err(proto::ParseError::InconsistentTotal {
+                client_id: record.client_id,
+            });
         }
+
+        Ok(Account::restore(
+            record.available_funds,
+            record.held_funds,
+            record.is_locked,
+        ))
     }
 }
 
@@ -147,6 +308,66 @@ pub struct Record<T, U> {
 
 impl<T, U> Record<T, U> {
     pub fn new(item: T, id: U) -> Record<T, U> {
-        Record { item: item, id: id }
+        Record { item, id }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_ids() {
+        assert_eq!("7".parse::<ClientId>().unwrap(), ClientId::new(7));
+        assert_eq!(" 65535".parse::<ClientId>().unwrap(), ClientId::new(65535));
+        assert!(matches!(
+            "65536".parse::<ClientId>(),
+            Err(proto::ParseError::ClientIdOutOfRange { client_id: 65536 })
+        ));
+        assert!(matches!(
+            "-1".parse::<ClientId>(),
+            Err(proto::ParseError::InvalidId { .. })
+        ));
+
+        assert_eq!(
+            "4294967295".parse::<TransactionId>().unwrap(),
+            TransactionId::new(u32::MAX)
+        );
+        assert!(matches!(
+            "4294967296".parse::<TransactionId>(),
+            Err(proto::ParseError::TransactionIdOutOfRange {
+                transaction_id: 4294967296
+            })
+        ));
+        assert!(matches!(
+            "tx1".parse::<TransactionId>(),
+            Err(proto::ParseError::InvalidId { .. })
+        ));
+    }
+
+    #[test]
+    fn disputable_transactions() {
+        let meta = Meta {
+            client_id: ClientId::new(1),
+            transaction_id: TransactionId::new(1),
+        };
+        let deposit = |amount| Transaction::Deposit {
+            meta: meta.clone(),
+            amount,
+        };
+        assert!(deposit(Decimal::ONE).is_disputable());
+        assert!(!deposit(-Decimal::ONE).is_disputable());
+        assert!(Transaction::Withdrawal {
+            meta: meta.clone(),
+            amount: Decimal::ONE,
+        }
+        .is_disputable());
+        assert!(!Transaction::Freeze { meta }.is_disputable());
+    }
+
+    #[test]
+    fn displaying_ids() {
+        assert_eq!(ClientId::new(12).to_string(), "12");
+        assert_eq!(TransactionId::new(345).to_string(), "345");
     }
 }
@@ -1,114 +1,1500 @@
-use crate::models::{Account, ClientId, Record, Transaction, TransactionId};
+use crate::clock::{Clock, SystemClock};
+use crate::models::{
+    Account, ArithmeticMode, ClientId, Meta, Overflow, Record, Transaction, TransactionId,
+};
+use crate::proto;
+use crate::store::AccountStore;
 use rust_decimal::Decimal;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::hash::Hash;
 use std::hash::Hasher;
 use std::iter::Iterator;
+use std::panic;
 use std::rc::Rc;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, SystemTime};
 
 type Output = Vec<Record<Account, ClientId>>;
 
-fn disputed_amount(tr: &Transaction, client_id: ClientId) -> Option<Decimal> {
-    if tr.meta().client_id != client_id {
-        // It transaction does not belong to the given client account - it's not disputable by this client.
-        None
-    } else {
-        match tr {
-            // We can also support disputing withdrawals later
-            // and return a negative amount for that.
-            Transaction::Deposit { amount: a, .. } => Some(*a),
-            _ => None,
+/// Pause of a throttled submission before rechecking the queue (see `Config::throttle_depth`).
+const THROTTLE_PAUSE: Duration = Duration::from_micros(100);
+
+/// Number of events buffered before the workers wait for them to be taken
+/// (see `Processor::take_events`).
+const EVENT_BOUND: usize = 1024;
+
+/// Number of deposit times kept before they are first pruned
+/// (see `Partition::prune_deposit_times`).
+const PRUNE_MIN_DEPOSITS: usize = 1024;
+
+/// Policy of handling disputes of deposits whose funds are no longer available
+/// (e.g. have been withdrawn already).
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DisputePolicy {
+    /// Rejects the dispute.
+    Strict,
+    /// Holds the funds anyway letting available funds go negative.
+    #[default]
+    Permissive,
+}
+
+/// Policy of handling disputes of transactions whose earlier dispute has been resolved.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum RedisputePolicy {
+    /// Holds the funds again.
+    #[default]
+    Allow,
+    /// Rejects the dispute.
+    Reject,
+}
+
+/// Policy of handling deposits and withdrawals reusing the transaction id of an
+/// earlier one of the client.
+///
+/// Keeping the last one instead would need the effect of the earlier one
+/// reversed, which is left for the future.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DuplicateTxPolicy {
+    /// Rejects the duplicate as `Rejection::DuplicateId`.
+    #[default]
+    Reject,
+    /// Ignores the duplicate, keeping the first transaction of the id.
+    /// The duplicate leaves the account intact (see `Outcome::Ignored`).
+    KeepFirst,
+}
+
+/// Fee charged on a transaction.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Fee {
+    /// The given amount.
+    Flat(Decimal),
+    /// The given percentage of the transaction amount.
+    Percent(Decimal),
+}
+
+impl Fee {
+    /// Tells whether the fee is negative, crediting rather than charging.
+    pub fn is_negative(&self) -> bool {
+        match self {
+            Fee::Flat(fee) | Fee::Percent(fee) => *fee < Decimal::ZERO,
+        }
+    }
+
+    /// Returns the fee of a transaction of the `amount`, `None` if it exceeds the amount.
+    fn of(&self, amount: Decimal) -> Option<Decimal> {
+        let fee = match self {
+            Fee::Flat(fee) => *fee,
+            Fee::Percent(percent) => amount.checked_mul(*percent)? / Decimal::ONE_HUNDRED,
+        };
+        (fee <= amount).then_some(fee)
+    }
+}
+
+/// Span of recent withdrawals of a client a `WithdrawalLimit` applies to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LimitSpan {
+    /// Withdrawals processed within the given time.
+    Time(Duration),
+    /// The given number of latest withdrawals, the current one included.
+    Withdrawals(usize),
+}
+
+/// Cap of the total a client can withdraw within a span of withdrawals.
+///
+/// Only applied withdrawals count towards the cap.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct WithdrawalLimit {
+    pub amount: Decimal,
+    pub span: LimitSpan,
+}
+
+impl WithdrawalLimit {
+    /// Drops the `recent` withdrawals (times and amounts) fallen out of the span
+    /// at `now` and checks that withdrawing `amount` stays within the cap.
+    fn admits(
+        &self,
+        recent: &mut VecDeque<(SystemTime, Decimal)>,
+        now: SystemTime,
+        amount: Decimal,
+    ) -> bool {
+        match self.span {
+            LimitSpan::Time(span) => {
+                while recent
+                    .front()
+                    .is_some_and(|(at, _)| now.duration_since(*at).unwrap_or_default() > span)
+                {
+                    recent.pop_front();
+                }
+            }
+            LimitSpan::Withdrawals(n) => {
+                while recent.len() >= n.max(1) {
+                    recent.pop_front();
+                }
+            }
+        }
+        let total = recent
+            .iter()
+            .try_fold(amount, |total, (_, a)| total.checked_add(*a));
+        total.is_some_and(|total| total <= self.amount)
+    }
+}
+
+/// Processing configuration shared by all partitions.
+///
+/// * `dedup_fingerprints` - rejects transactions identical (by type, client,
+///   transaction id and amount) to a previously applied one. Rejected ones
+///   may be retried.
+/// * `dispute_policy` - handling of disputes exceeding available funds.
+/// * `redispute_policy` - handling of disputes after a resolve of the transaction.
+/// * `duplicate_tx_policy` - handling of deposits and withdrawals reusing
+///   the transaction id of an earlier deposit or withdrawal of the client.
+///   Detected by the ids kept along with the history, so not with `no_disputes`
+///   or once the client is evicted.
+/// * `no_disputes` - keeps no transaction history for inputs free of disputes,
+///   saving its memory and time. Disputes, resolves and chargebacks are then
+///   rejected, and so are corrections, as they reference history too.
+/// * `emit_events` - emits an `Event` for every applied transaction
+///   (see `Processor::take_events`).
+/// * `event_client` - if set, limits emitted events to transactions of the client.
+/// * `emit_dead_letters` - emits a `DeadLetter` for every transaction rejected
+///   as its account is frozen (see `Processor::take_dead_letters`).
+/// * `arithmetic_mode` - handling of overflows in account operations.
+/// * `max_scale` - if set, rounds the funds of an account to the given number
+///   of decimal places after every transaction applied to it. Amounts of
+///   disputes are not rounded, so the scale should cover the input amounts
+///   for disputes to settle exactly. Unbounded if not set.
+/// * `recover` - if a worker panics, drops its partition (accounts included)
+///   and carries on with the rest instead of propagating the panic.
+/// * `continue_on_panic` - if a transaction panics while being applied, e.g. on
+///   an unexpected decimal edge case, skips just the transaction, rejected as
+///   `Rejection::Panicked`, and carries on with the partition. State the
+///   transaction touched is rolled back, so it is never half applied.
+/// * `channel_bound` - if set, limits the number of commands queued for
+///   a worker, blocking submission until the worker catches up.
+/// * `throttle_depth` - if set, submission parks briefly while the queue of the
+///   target worker holds at least the given number of commands, rather than
+///   spinning on a saturated channel (see `Processor::queue_lengths`).
+/// * `guard_held` - rejects transactions that would make the funds held in
+///   a partition exceed the net funds deposited to it. Checking every
+///   partition guarantees the invariant system-wide.
+/// * `check_total` - reports transactions making the total funds (available
+///   plus held) of an account negative, e.g. a chargeback of withdrawn funds
+///   disputed under the permissive policy (see `RunMetrics::negative_totals`).
+///   Such transactions are applied still.
+/// * `dedupe_window` - if set, reports applied deposits and withdrawals of the
+///   type and amount of one of the given number of the latest deposits and
+///   withdrawals applied to the client, under another transaction id, as potential
+///   double submissions (see `RunMetrics::near_duplicates`).
+/// * `hot_client_percent` - if set, reports clients with more than the given
+///   percentage of all transactions (see `RunMetrics::hot_clients`).
+/// * `amount_histogram` - if set, tallies amounts of applied deposits and
+///   withdrawals into the buckets of the given histogram
+///   (see `RunMetrics::amount_histogram`).
+/// * `dispute_window` - if set, rejects disputes of deposits processed longer
+///   than the given time ago.
+/// * `withdrawal_limit` - if set, rejects withdrawals exceeding the cap of
+///   the client.
+/// * `deposit_fee` - if set, the fee is deducted from the amount credited by
+///   a deposit. Disputes still cover the whole amount, fees are never refunded.
+/// * `withdrawal_fee` - if set, the fee is debited on top of the amount
+///   of a withdrawal. With either fee, transactions with a fee exceeding their
+///   amount are rejected. Fees paid are tracked per account
+///   (see `Account::get_fees`), corrections leave them intact.
+/// * `count_transactions` - counts the deposits and withdrawals applied to
+///   every account (see `Account::get_deposit_count`).
+/// * `clock` - source of the current time, the system clock if not set.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub dedup_fingerprints: bool,
+    pub dispute_policy: DisputePolicy,
+    pub redispute_policy: RedisputePolicy,
+    pub duplicate_tx_policy: DuplicateTxPolicy,
+    pub no_disputes: bool,
+    pub emit_events: bool,
+    pub event_client: Option<ClientId>,
+    pub emit_dead_letters: bool,
+    pub arithmetic_mode: ArithmeticMode,
+    pub max_scale: Option<u32>,
+    pub recover: bool,
+    pub continue_on_panic: bool,
+    pub channel_bound: Option<usize>,
+    pub throttle_depth: Option<usize>,
+    pub guard_held: bool,
+    pub check_total: bool,
+    pub dedupe_window: Option<usize>,
+    pub hot_client_percent: Option<u8>,
+    pub amount_histogram: Option<Histogram>,
+    pub dispute_window: Option<Duration>,
+    pub withdrawal_limit: Option<WithdrawalLimit>,
+    pub deposit_fee: Option<Fee>,
+    pub withdrawal_fee: Option<Fee>,
+    pub count_transactions: bool,
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
+impl Config {
+    /// Returns the current time of the configured clock.
+    pub fn now(&self) -> SystemTime {
+        match &self.clock {
+            Some(clock) => clock.now(),
+            None => SystemClock.now(),
+        }
+    }
+}
+
+/// Metrics of a processing run.
+///
+/// * `processed` - number of transactions applied.
+/// * `rejected` - number of transactions rejected.
+/// * `duplicates` - number of the rejected transactions identical to a previous
+///   one (see `Config::dedup_fingerprints`).
+/// * `ignored` - number of transactions accepted but leaving their account
+///   intact (see `Outcome::Ignored`).
+/// * `skipped` - number of transactions skipped as their type is not selected,
+///   filled by the functions processing into an output (see `crate::ProcessConfig::only_types`).
+/// * `disputes` - number of disputes applied.
+/// * `failed_partitions` - number of partitions dropped due to a worker panic.
+/// * `hot_clients` - clients exceeding `Config::hot_client_percent` of all
+///   transactions with their number of transactions, by client id.
+/// * `amount_histogram` - amounts of applied deposits and withdrawals
+///   if configured (see `Config::amount_histogram`).
+/// * `frozen_accounts` - number of resulting accounts frozen, e.g. by a chargeback.
+/// * `summary` - totals of the output accounts, filled by the functions
+///   processing into an output (e.g. `crate::process`).
+/// * `negative_totals` - transactions that made the total funds of their account
+///   negative if checked (see `Config::check_total`), in no particular order.
+/// * `near_duplicates` - deposits and withdrawals resembling a recent one of
+///   their client if checked (see `Config::dedupe_window`), along with the id
+///   of the recent one, in no particular order.
+/// * `partial` - whether the run stopped at its deadline or transaction limit,
+///   or at an input error it can't read past, before reading all the input,
+///   so the output is incomplete (see `crate::ProcessConfig::deadline`,
+///   `crate::ProcessConfig::max_transactions` and `proto::ParseError::is_recoverable`).
+/// * `control_mismatches` - output totals differing from the control file
+///   if any, by client id (see `crate::ProcessConfig::control`).
+/// * `duration` - wall time of the run, filled by the functions processing
+///   into an output (e.g. `crate::process`).
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RunMetrics {
+    pub processed: u64,
+    pub rejected: u64,
+    pub duplicates: u64,
+    pub ignored: u64,
+    pub skipped: u64,
+    pub disputes: u64,
+    pub failed_partitions: usize,
+    pub hot_clients: Vec<(ClientId, u64)>,
+    pub amount_histogram: Option<Histogram>,
+    pub frozen_accounts: usize,
+    pub summary: proto::Summary,
+    pub negative_totals: Vec<(ClientId, TransactionId)>,
+    pub near_duplicates: Vec<(ClientId, TransactionId, TransactionId)>,
+    pub partial: bool,
+    pub control_mismatches: Vec<proto::Mismatch>,
+    pub duration: Duration,
+}
+
+impl RunMetrics {
+    /// Formats the metrics in the Prometheus text exposition format,
+    /// e.g. to be scraped by a sidecar or pushed to a gateway.
+    pub fn to_prometheus(&self) -> String {
+        let metrics = [
+            (
+                "transactor_transactions_processed_total",
+                "counter",
+                "Transactions applied.",
+                self.processed.to_string(),
+            ),
+            (
+                "transactor_transactions_rejected_total",
+                "counter",
+                "Transactions rejected.",
+                self.rejected.to_string(),
+            ),
+            (
+                "transactor_transactions_duplicate_total",
+                "counter",
+                "Transactions rejected as identical to a previous one.",
+                self.duplicates.to_string(),
+            ),
+            (
+                "transactor_transactions_ignored_total",
+                "counter",
+                "Transactions accepted but leaving their account intact.",
+                self.ignored.to_string(),
+            ),
+            (
+                "transactor_disputes_total",
+                "counter",
+                "Disputes applied.",
+                self.disputes.to_string(),
+            ),
+            (
+                "transactor_failed_partitions_total",
+                "counter",
+                "Partitions dropped due to a worker panic.",
+                self.failed_partitions.to_string(),
+            ),
+            (
+                "transactor_frozen_accounts",
+                "gauge",
+                "Output accounts frozen.",
+                self.frozen_accounts.to_string(),
+            ),
+            (
+                "transactor_run_duration_seconds",
+                "gauge",
+                "Wall time of the run.",
+                self.duration.as_secs_f64().to_string(),
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            output += &format!(
+                "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                name, help, name, kind, name, value
+            );
+        }
+        output
+    }
+
+    /// Adds up `other` metrics to these ones.
+    fn merge(&mut self, other: &RunMetrics) {
+        self.processed += other.processed;
+        self.rejected += other.rejected;
+        self.duplicates += other.duplicates;
+        self.ignored += other.ignored;
+        self.disputes += other.disputes;
+        self.failed_partitions += other.failed_partitions;
+        self.negative_totals.extend(&other.negative_totals);
+        self.near_duplicates.extend(&other.near_duplicates);
+        match (&mut self.amount_histogram, &other.amount_histogram) {
+            (Some(histogram), Some(other)) => histogram.merge(other),
+            (None, Some(other)) => self.amount_histogram = Some(other.clone()),
+            _ => (),
+        }
+    }
+}
+
+/// Histogram of amounts.
+///
+/// * `bounds` - inclusive upper bounds of the buckets in ascending order.
+/// * `counts` - number of amounts in each bucket, the last one counting
+///   amounts above the highest bound.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Histogram {
+    pub bounds: Vec<Decimal>,
+    pub counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// Creates an empty histogram with buckets up to the given `bounds`
+    /// and one more for amounts above them.
+    pub fn new(mut bounds: Vec<Decimal>) -> Histogram {
+        bounds.sort_unstable();
+        let counts = vec![0; bounds.len() + 1];
+        Histogram { bounds, counts }
+    }
+
+    /// Creates an empty histogram with `n_bounds` log-scale bounds:
+    /// `lowest`, ten times `lowest` and so forth.
+    pub fn log_scale(lowest: Decimal, n_bounds: usize) -> Histogram {
+        let bounds = std::iter::successors(Some(lowest), |b| b.checked_mul(Decimal::TEN));
+        Histogram::new(bounds.take(n_bounds).collect())
+    }
+
+    /// Counts the `amount` in its bucket.
+    pub fn record(&mut self, amount: Decimal) {
+        let bucket = self.bounds.partition_point(|bound| *bound < amount);
+        self.counts[bucket] += 1;
+    }
+
+    /// Adds up counts of the `other` histogram with the same bounds to these ones.
+    fn merge(&mut self, other: &Histogram) {
+        assert_eq!(self.bounds, other.bounds, "Histogram bounds differ!");
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rows: Vec<_> = self
+            .bounds
+            .iter()
+            .map(|bound| format!("<= {}", bound.normalize()))
+            .collect();
+        if let Some(highest) = self.bounds.last() {
+            rows.push(format!("> {}", highest.normalize()));
+        } else {
+            rows.push("any".to_string());
         }
+
+        let width = rows.iter().map(String::len).max().unwrap_or(0);
+        for (bucket, count) in rows.iter().zip(&self.counts) {
+            writeln!(f, "{:<width$}  {:>10}", bucket, count, width = width)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reason for a partition to reject a transaction.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Rejection {
+    /// An identical transaction has already been processed.
+    Duplicate,
+    /// A deposit or withdrawal of the transaction id of the client has already
+    /// been processed (see `Config::duplicate_tx_policy`).
+    DuplicateId,
+    /// The account doesn't have enough available funds.
+    InsufficientFunds,
+    /// The account is frozen.
+    FrozenAccount,
+    /// Applying the transaction would overflow the account funds.
+    Overflow,
+    /// Applying the transaction would make held funds exceed deposited ones
+    /// (see `Config::guard_held`).
+    HeldExceedsDeposits,
+    /// The transaction to correct is unknown, not a deposit or withdrawal, or
+    /// a negative deposit, or the correction is not positive.
+    UncorrectableOriginal,
+    /// The disputed deposit is older than the dispute window (see `Config::dispute_window`).
+    DisputeWindowExpired,
+    /// The fee exceeds the transaction amount (see `Config::deposit_fee`).
+    FeeExceedsAmount,
+    /// Disputes are disabled (see `Config::no_disputes`).
+    DisputesDisabled,
+    /// A dispute of the transaction has been resolved already
+    /// (see `Config::redispute_policy`).
+    AlreadyResolved,
+    /// The withdrawal exceeds the cap of the client (see `Config::withdrawal_limit`).
+    WithdrawalLimitExceeded,
+    /// The dispute exceeds the undisputed amount of the transaction, e.g. repeats
+    /// an open dispute of all of it.
+    AlreadyDisputed,
+    /// A resolve or chargeback has no open dispute to settle, e.g. arrives
+    /// before the dispute. It leaves no trace, so a later dispute still applies.
+    NotDisputed,
+    /// Applying the transaction panicked (see `Config::continue_on_panic`).
+    Panicked,
+}
+
+/// Outcome of a transaction a partition accepted.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Outcome {
+    /// The transaction has been applied.
+    Applied,
+    /// The transaction has been accepted but left the account intact,
+    /// e.g. a duplicate under `DuplicateTxPolicy::KeepFirst`.
+    Ignored,
+}
+
+impl From<Overflow> for Rejection {
+    fn from(_: Overflow) -> Self {
+        Rejection::Overflow
+    }
+}
+
+/// Event of a transaction applied by a partition.
+///
+/// * `sequence` - order number of the transaction submitted to the processor.
+/// * `transaction` - the applied transaction.
+/// * `account` - state of the account right after applying the transaction.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub sequence: u64,
+    pub transaction: Transaction,
+    pub account: Account,
+}
+
+impl Event {
+    /// Converts the event to a proto audit record.
+    pub fn to_proto(&self) -> proto::AuditRecord {
+        let meta = self.transaction.meta();
+        proto::AuditRecord {
+            sequence: self.sequence,
+            client_id: meta.client_id.value(),
+            transaction_id: meta.transaction_id.value(),
+            kind: self.transaction.kind().to_string(),
+            amount: self.transaction.amount(),
+            available_funds: *self.account.get_available_funds(),
+            held_funds: *self.account.get_held_funds(),
+        }
+    }
+}
+
+/// Describes the transaction and the resulting account state for a human reader.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let meta = self.transaction.meta();
+        write!(
+            f,
+            "#{} {} tx {}",
+            self.sequence,
+            self.transaction.kind(),
+            meta.transaction_id
+        )?;
+        if let Some(amount) = self.transaction.amount() {
+            write!(f, " of {}", amount)?;
+        }
+        write!(
+            f,
+            ": available {}, held {}",
+            self.account.get_available_funds(),
+            self.account.get_held_funds()
+        )?;
+        if self.account.is_frozen() {
+            write!(f, ", locked")?;
+        }
+        Ok(())
+    }
+}
+
+/// Transaction rejected as its account is frozen, e.g. to reprocess it once
+/// the account is unfrozen.
+///
+/// * `sequence` - order number of the transaction submitted to the processor.
+/// * `transaction` - the rejected transaction.
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub sequence: u64,
+    pub transaction: Transaction,
+}
+
+/// Returns the amount a dispute of the transaction covers, `None` if the transaction
+/// is not disputable (see `Transaction::is_disputable`).
+fn disputed_amount(tr: &Transaction) -> Option<Decimal> {
+    match tr.is_disputable() {
+        true => tr.amount(),
+        false => None,
+    }
+}
+
+/// Returns the dispute state of the transaction `key` along with the amount
+/// to settle for the `portion` (see `DisputeState::settled_amount`).
+///
+/// Rejected before the transaction is recorded in the history, so a resolve or
+/// chargeback with nothing to settle never shadows the transaction it references.
+fn open_dispute(
+    disputed_transactions: &mut HashMap<TransactionKey, DisputeState>,
+    key: TransactionKey,
+    portion: Option<Decimal>,
+) -> Result<(&mut DisputeState, Decimal), Rejection> {
+    let state = disputed_transactions
+        .get_mut(&key)
+        .ok_or(Rejection::NotDisputed)?;
+    let amount = state
+        .settled_amount(portion)
+        .ok_or(Rejection::NotDisputed)?;
+    Ok((state, amount))
+}
+
+/// Key of a transaction in a partition. Transactions are looked up per client,
+/// so transactions of different clients never match even if their ids repeat.
+type TransactionKey = (ClientId, TransactionId);
+
+/// Disputed state of a transaction.
+///
+/// A transaction may be disputed in several portions, each of them settled
+/// independently (see `Transaction`).
+#[derive(Clone)]
+struct DisputeState {
+    transaction: Rc<Transaction>,
+    /// Total of the open disputes, held on the account for a deposit
+    /// or pending credit for a withdrawal.
+    held: Decimal,
+    /// Amounts of the open disputes.
+    open: Vec<Decimal>,
+    /// Number of disputes resolved.
+    resolved: usize,
+}
+
+impl DisputeState {
+    fn new(transaction: Rc<Transaction>) -> DisputeState {
+        DisputeState {
+            transaction,
+            held: Decimal::ZERO,
+            open: Vec::new(),
+            resolved: 0,
+        }
+    }
+
+    /// Returns whether the disputed transaction is a withdrawal (see `Account`).
+    fn is_withdrawal(&self) -> bool {
+        matches!(*self.transaction, Transaction::Withdrawal { .. })
+    }
+
+    fn open(&mut self, portion: Decimal) {
+        self.held += portion;
+        self.open.push(portion);
+    }
+
+    /// Returns the amount to settle for the open dispute of the `portion`,
+    /// or for all the open disputes if `None`. `None` if there is nothing to settle.
+    fn settled_amount(&self, portion: Option<Decimal>) -> Option<Decimal> {
+        match portion {
+            Some(portion) => self.open.contains(&portion).then_some(portion),
+            None => (!self.open.is_empty()).then_some(self.held),
+        }
+    }
+
+    /// Closes the disputes selected by the `portion` (see `settled_amount`).
+    fn settle(&mut self, portion: Option<Decimal>) {
+        match portion {
+            Some(portion) => {
+                if let Some(i) = self.open.iter().position(|a| *a == portion) {
+                    self.held -= self.open.swap_remove(i);
+                }
+            }
+            None => {
+                self.held = Decimal::ZERO;
+                self.open.clear();
+            }
+        }
+    }
+}
+
+/// Running totals of the funds of a partition.
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+    /// Deposits less withdrawals and chargebacks.
+    deposited: Decimal,
+    held: Decimal,
+}
+
+impl Totals {
+    fn add(&mut self, deposited: Decimal, held: Decimal) {
+        self.deposited = self.deposited.saturating_add(deposited);
+        self.held = self.held.saturating_add(held);
     }
+
+    /// Checks that adding the given amounts keeps held funds within deposited ones.
+    fn guard(&self, deposited: Decimal, held: Decimal) -> Result<(), Rejection> {
+        let deposited = self.deposited.saturating_add(deposited);
+        let held = self.held.saturating_add(held);
+        if held > deposited {
+            eprintln!(
+                "Warning: held funds {} would exceed deposited funds {}",
+                held, deposited
+            );
+            return Err(Rejection::HeldExceedsDeposits);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the hash of the type, client, transaction id and amount of the
+/// transaction `tr` telling identical transactions (see `Config::dedup_fingerprints`).
+fn fingerprint(tr: &Transaction) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    tr.kind().hash(&mut hasher);
+    tr.meta().hash(&mut hasher);
+    tr.amount().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// State of a partition a transaction may change, saved to roll the transaction
+/// back if applying it panics (see `Config::continue_on_panic`).
+///
+/// The history entries are saved under the keys of the transaction and of
+/// the transaction it corrects, if any, as no other entries are touched.
+struct Savepoint {
+    client_id: ClientId,
+    account: Option<Account>,
+    history: Vec<(TransactionKey, Option<Rc<Transaction>>)>,
+    deposited_at: Vec<(TransactionKey, Option<SystemTime>)>,
+    disputes: Vec<(TransactionKey, Option<DisputeState>)>,
+    recent_transfers: Option<VecDeque<(TransactionId, bool, Decimal)>>,
+    recent_withdrawals: Option<VecDeque<(SystemTime, Decimal)>>,
+    /// Fingerprint of the transaction if deduplicated and new to the partition.
+    fingerprint: Option<u64>,
+    /// Key of the transaction if it is a deposit or withdrawal new to the partition.
+    transfer_id: Option<TransactionKey>,
+    totals: Totals,
+    amount_histogram: Option<Histogram>,
+    negative_totals: usize,
+    near_duplicates: usize,
 }
 
 /// Partition that processes transactions sequantially.
 struct Partition {
-    transaction_history: HashMap<TransactionId, Rc<Transaction>>,
-    disputed_transactions: HashMap<TransactionId, Rc<Transaction>>,
-    pub accounts: HashMap<ClientId, Account>,
+    config: Config,
+    totals: Totals,
+    amount_histogram: Option<Histogram>,
+    /// Transactions that made the total funds of their account negative if checked.
+    negative_totals: Vec<TransactionKey>,
+    /// Latest deposits and withdrawals by client if near duplicates are checked,
+    /// as their ids, whether they are withdrawals and their amounts.
+    recent_transfers: HashMap<ClientId, VecDeque<(TransactionId, bool, Decimal)>>,
+    /// Deposits and withdrawals resembling a recent one, with the id of the recent one.
+    near_duplicates: Vec<(ClientId, TransactionId, TransactionId)>,
+    transaction_history: HashMap<TransactionKey, Rc<Transaction>>,
+    /// Times deposits were processed at if disputes are limited to a window.
+    /// Times older than the window are pruned (see `prune_deposit_times`).
+    deposited_at: HashMap<TransactionKey, SystemTime>,
+    /// Number of deposit times kept by their latest pruning.
+    deposit_times_kept: usize,
+    /// Times and amounts of recent withdrawals by client if withdrawals are limited.
+    recent_withdrawals: HashMap<ClientId, VecDeque<(SystemTime, Decimal)>>,
+    disputed_transactions: HashMap<TransactionKey, DisputeState>,
+    /// Ids of the deposits and withdrawals applied, unless disputes are disabled,
+    /// to tell duplicates (see `Config::duplicate_tx_policy`). Unlike the history,
+    /// they are never shadowed by other transactions of the id.
+    transfer_ids: HashSet<TransactionKey>,
+    /// Fingerprints of the transactions applied to tell identical ones if
+    /// deduplicated (see `fingerprint`).
+    fingerprints: HashSet<u64>,
+    pub accounts: Box<dyn AccountStore + Send>,
 }
 
 impl Partition {
-    /// Creates a new empty partition.
-    pub fn new() -> Partition {
+    /// Creates a new empty partition keeping accounts in the given `accounts` store.
+    pub fn new(config: Config, accounts: Box<dyn AccountStore + Send>) -> Partition {
         Partition {
+            amount_histogram: config.amount_histogram.clone(),
+            config,
+            totals: Totals::default(),
+            negative_totals: Vec::new(),
+            recent_transfers: HashMap::new(),
+            near_duplicates: Vec::new(),
             transaction_history: HashMap::new(),
+            deposited_at: HashMap::new(),
+            deposit_times_kept: 0,
+            recent_withdrawals: HashMap::new(),
             disputed_transactions: HashMap::new(),
-            accounts: HashMap::new(),
+            transfer_ids: HashSet::new(),
+            fingerprints: HashSet::new(),
+            accounts,
         }
     }
 
-    /// Processes the given transaction.
+    /// Sets the initial state of the client account (see `Processor::seed`).
+    pub fn seed(&mut self, client_id: ClientId, account: Account) {
+        let held = *account.get_held_funds();
+        self.totals
+            .add(account.get_available_funds().saturating_add(held), held);
+        *self.accounts.get_or_create(client_id) = account;
+    }
+
+    /// Restores an open dispute of the transaction of the client holding `held`
+    /// funds (see `Processor::seed_dispute`).
+    ///
+    /// Balances are left intact, the held funds are restored with the account.
+    /// The disputed transaction is unknown beyond the amount held, so it is
+    /// taken for a deposit of that amount.
+    pub fn seed_dispute(
+        &mut self,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        held: Decimal,
+    ) {
+        let transaction = Transaction::Deposit {
+            meta: Meta {
+                client_id,
+                transaction_id,
+            },
+            amount: held,
+        };
+        let mut state = DisputeState::new(Rc::new(transaction));
+        state.open(held);
+        self.disputed_transactions
+            .insert((client_id, transaction_id), state);
+        // The deposit time is unknown, so the window starts with the seeding.
+        if self.config.dispute_window.is_some() {
+            self.deposited_at
+                .insert((client_id, transaction_id), self.config.now());
+        }
+    }
+
+    /// Drops the deposit times older than the dispute `window`, deposits
+    /// missing a time count as expired. Prunes only once the number of times
+    /// doubled since the latest pruning, so pruning takes amortized constant time.
+    fn prune_deposit_times(&mut self, window: Duration) {
+        if self.deposited_at.len() < 2 * self.deposit_times_kept.max(PRUNE_MIN_DEPOSITS) {
+            return;
+        }
+        let now = self.config.now();
+        self.deposited_at
+            .retain(|_, at| now.duration_since(*at).unwrap_or_default() <= window);
+        self.deposit_times_kept = self.deposited_at.len();
+    }
+
+    /// Saves the state the transaction `tr` may change (see `Savepoint`).
+    fn savepoint(&self, tr: &Transaction) -> Savepoint {
+        let meta = tr.meta();
+        let mut keys = vec![(meta.client_id, meta.transaction_id)];
+        if let Transaction::Correct { original_tx, .. } = tr {
+            keys.push((meta.client_id, *original_tx));
+        }
+        let fingerprint = Some(fingerprint(tr))
+            .filter(|hash| self.config.dedup_fingerprints && !self.fingerprints.contains(hash));
+        let transfer_id = Some(keys[0]).filter(|key| !self.transfer_ids.contains(key));
+
+        Savepoint {
+            client_id: meta.client_id,
+            account: self.accounts.get(meta.client_id).cloned(),
+            history: keys
+                .iter()
+                .map(|key| (*key, self.transaction_history.get(key).cloned()))
+                .collect(),
+            deposited_at: keys
+                .iter()
+                .map(|key| (*key, self.deposited_at.get(key).copied()))
+                .collect(),
+            disputes: keys
+                .iter()
+                .map(|key| (*key, self.disputed_transactions.get(key).cloned()))
+                .collect(),
+            recent_transfers: self.recent_transfers.get(&meta.client_id).cloned(),
+            recent_withdrawals: self.recent_withdrawals.get(&meta.client_id).cloned(),
+            fingerprint,
+            transfer_id,
+            totals: self.totals,
+            amount_histogram: self.amount_histogram.clone(),
+            negative_totals: self.negative_totals.len(),
+            near_duplicates: self.near_duplicates.len(),
+        }
+    }
+
+    /// Rolls the state back to the `savepoint`.
+    fn restore(&mut self, savepoint: Savepoint) {
+        /// Sets the entry of the `key` in the `map` to the saved `value`.
+        fn put<V>(map: &mut HashMap<TransactionKey, V>, key: TransactionKey, value: Option<V>) {
+            match value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+
+        let client_id = savepoint.client_id;
+        match savepoint.account {
+            Some(account) => *self.accounts.get_or_create(client_id) = account,
+            None => {
+                self.accounts.remove(client_id);
+            }
+        }
+        for (key, value) in savepoint.history {
+            put(&mut self.transaction_history, key, value);
+        }
+        for (key, value) in savepoint.deposited_at {
+            put(&mut self.deposited_at, key, value);
+        }
+        for (key, value) in savepoint.disputes {
+            put(&mut self.disputed_transactions, key, value);
+        }
+        match savepoint.recent_transfers {
+            Some(transfers) => self.recent_transfers.insert(client_id, transfers),
+            None => self.recent_transfers.remove(&client_id),
+        };
+        match savepoint.recent_withdrawals {
+            Some(withdrawals) => self.recent_withdrawals.insert(client_id, withdrawals),
+            None => self.recent_withdrawals.remove(&client_id),
+        };
+        if let Some(hash) = savepoint.fingerprint {
+            self.fingerprints.remove(&hash);
+        }
+        if let Some(key) = savepoint.transfer_id {
+            self.transfer_ids.remove(&key);
+        }
+        self.totals = savepoint.totals;
+        self.amount_histogram = savepoint.amount_histogram;
+        self.negative_totals.truncate(savepoint.negative_totals);
+        self.near_duplicates.truncate(savepoint.near_duplicates);
+    }
+
+    /// Removes the accounts of the clients along with the history of their
+    /// transactions, returning the accounts present (see `Processor::flush_completed`).
+    ///
+    /// Totals of the partition still account for the funds of the clients,
+    /// and so do the fingerprints of their transactions if deduplicated.
+    fn evict(&mut self, client_ids: &HashSet<ClientId>) -> Vec<(ClientId, Account)> {
+        self.transaction_history
+            .retain(|(id, _), _| !client_ids.contains(id));
+        self.deposited_at
+            .retain(|(id, _), _| !client_ids.contains(id));
+        self.disputed_transactions
+            .retain(|(id, _), _| !client_ids.contains(id));
+        self.transfer_ids.retain(|(id, _)| !client_ids.contains(id));
+        client_ids
+            .iter()
+            .filter_map(|&client_id| {
+                self.recent_transfers.remove(&client_id);
+                self.recent_withdrawals.remove(&client_id);
+                Some((client_id, self.accounts.remove(client_id)?))
+            })
+            .collect()
+    }
+
+    /// Reports the applied deposit or withdrawal `tr` if it has the type and amount
+    /// of one of the `window` latest deposits and withdrawals applied to its client,
+    /// then records it.
+    fn check_near_duplicate(&mut self, tr: &Transaction, window: usize) {
+        let (is_withdrawal, amount) = match tr {
+            Transaction::Deposit { amount, .. } => (false, *amount),
+            Transaction::Withdrawal { amount, .. } => (true, *amount),
+            _ => return,
+        };
+        let meta = tr.meta();
+        let recent = self.recent_transfers.entry(meta.client_id).or_default();
+        let similar = recent.iter().find(|(id, withdrawal, recent_amount)| {
+            *id != meta.transaction_id && *withdrawal == is_withdrawal && *recent_amount == amount
+        });
+        if let Some((similar_id, _, _)) = similar {
+            eprintln!(
+                "Warning: {} tx {} of client {} may double submit tx {}",
+                tr.kind(),
+                meta.transaction_id,
+                meta.client_id,
+                similar_id
+            );
+            self.near_duplicates
+                .push((meta.client_id, meta.transaction_id, *similar_id));
+        }
+        if recent.len() == window {
+            recent.pop_front();
+        }
+        if window > 0 {
+            recent.push_back((meta.transaction_id, is_withdrawal, amount));
+        }
+    }
+
+    /// Processes the given transaction regardless of its outcome.
+    #[cfg(test)]
+    pub fn process(&mut self, tr: Transaction) -> Result<(), Rejection> {
+        self.apply(tr).map(|_| ())
+    }
+
+    /// Processes the given transaction, telling whether it has been applied
+    /// or ignored (see `Outcome`).
+    #[cfg(test)]
+    pub fn apply(&mut self, tr: Transaction) -> Result<Outcome, Rejection> {
+        self.try_apply(tr).map_err(|(rejection, _)| rejection)
+    }
+
+    /// Processes the given transaction, telling whether it has been applied
+    /// or ignored (see `Outcome`). A rejected transaction is handed back along
+    /// with the rejection, e.g. to keep it as a dead letter.
     ///
     /// A partition keeps the history of all transactions it has processed
     /// for handling of disputes.
     /// TODO: some prunning logic or moving history to exernal store may be required in the future.
-    pub fn process(&mut self, tr: Transaction) {
+    pub fn try_apply(&mut self, tr: Transaction) -> Result<Outcome, (Rejection, Transaction)> {
+        match self.run(&tr) {
+            Ok(Outcome::Applied) => {
+                // Rejected transactions may be retried, so only applied ones count.
+                if self.config.dedup_fingerprints {
+                    self.fingerprints.insert(fingerprint(&tr));
+                }
+                // Other rows are never looked up, though they still shadow the
+                // transaction of their key, as a dispute does.
+                if !self.config.no_disputes {
+                    let key = (tr.meta().client_id, tr.meta().transaction_id);
+                    if matches!(
+                        tr,
+                        Transaction::Deposit { .. } | Transaction::Withdrawal { .. }
+                    ) {
+                        self.transfer_ids.insert(key);
+                    }
+                    match tr.is_disputable() {
+                        true => self.transaction_history.insert(key, Rc::new(tr)),
+                        false => self.transaction_history.remove(&key),
+                    };
+                }
+                Ok(Outcome::Applied)
+            }
+            Ok(Outcome::Ignored) => Ok(Outcome::Ignored),
+            Err(rejection) => Err((rejection, tr)),
+        }
+    }
+
+    /// Applies the transaction `tr` to the state but the history (see `try_apply`).
+    fn run(&mut self, tr: &Transaction) -> Result<Outcome, Rejection> {
+        if self.config.dedup_fingerprints && self.fingerprints.contains(&fingerprint(tr)) {
+            return Err(Rejection::Duplicate);
+        }
+
+        // Zero deposits and withdrawals only open the account, if accepted at all
+        // (see `proto::ZeroAmountPolicy::Accept`).
+        let zero = matches!(
+            *tr,
+            Transaction::Deposit { amount, .. } | Transaction::Withdrawal { amount, .. }
+                if amount.is_zero()
+        );
+
         let meta = tr.meta();
-        let acc = self
-            .accounts
-            .entry(meta.client_id)
-            .or_insert_with(|| Account::new());
+        let key = (meta.client_id, meta.transaction_id);
+        let mode = self.config.arithmetic_mode;
+        let guarded = self.config.guard_held;
+        let acc = self.accounts.get_or_create(meta.client_id);
 
-        if acc.is_frozen() {
-            return;
+        // Frozen accounts only accept explicit unfreezing.
+        if acc.is_frozen() && !matches!(tr, Transaction::Unfreeze { .. }) {
+            return Err(Rejection::FrozenAccount);
+        }
+        if zero {
+            return Ok(Outcome::Ignored);
         }
 
-        match tr {
-            Transaction::Deposit { amount: a, .. } => acc.deposit(&a),
-            Transaction::Withdrawal { amount: a, .. } => {
-                if acc.get_available_funds() >= &a {
-                    acc.withdraw(&a);
+        // Open disputes seeded from a prior run hold the ids of their transactions too.
+        let is_transfer = matches!(
+            tr,
+            Transaction::Deposit { .. } | Transaction::Withdrawal { .. }
+        );
+        if is_transfer
+            && (self.transfer_ids.contains(&key) || self.disputed_transactions.contains_key(&key))
+        {
+            return match self.config.duplicate_tx_policy {
+                DuplicateTxPolicy::Reject => Err(Rejection::DuplicateId),
+                DuplicateTxPolicy::KeepFirst => Ok(Outcome::Ignored),
+            };
+        }
+
+        let total_before = match self.config.check_total {
+            true => Some(
+                acc.get_available_funds()
+                    .saturating_add(*acc.get_held_funds()),
+            ),
+            false => None,
+        };
+
+        if self.config.no_disputes
+            && matches!(
+                tr,
+                Transaction::Dispute { .. }
+                    | Transaction::Resolve { .. }
+                    | Transaction::Chargeback { .. }
+            )
+        {
+            return Err(Rejection::DisputesDisabled);
+        }
+
+        match *tr {
+            Transaction::Deposit { amount: a, .. } if a < Decimal::ZERO => {
+                // A negative deposit reverses an earlier one, debiting the account free of fees.
+                if acc.get_available_funds() < &-a {
+                    return Err(Rejection::InsufficientFunds);
+                }
+                if guarded {
+                    self.totals.guard(a, Decimal::ZERO)?;
+                }
+                acc.deposit(&a, mode)?;
+                self.totals.add(a, Decimal::ZERO);
+                if self.config.count_transactions {
+                    acc.count_deposit();
+                }
+            }
+            Transaction::Deposit { amount: a, .. } => {
+                let fee = match self.config.deposit_fee {
+                    Some(fee) => fee.of(a).ok_or(Rejection::FeeExceedsAmount)?,
+                    None => Decimal::ZERO,
+                };
+                // Applied to a copy, so an overflowing fee leaves the account intact.
+                let mut credited = acc.clone();
+                credited.deposit(&a, mode)?;
+                if !fee.is_zero() {
+                    credited.pay_fee(&fee, mode)?;
+                }
+                *acc = credited;
+                self.totals.add(a - fee, Decimal::ZERO);
+                if self.config.count_transactions {
+                    acc.count_deposit();
+                }
+                if self.config.dispute_window.is_some() && !self.config.no_disputes {
+                    self.deposited_at.insert(key, self.config.now());
+                }
+                if let Some(histogram) = &mut self.amount_histogram {
+                    histogram.record(a);
                 }
-                // TODO: log the `else` case
             }
-            Transaction::Dispute { .. } => {
-                for disputed_tr in self.transaction_history.get(&meta.transaction_id) {
-                    for amount in disputed_amount(disputed_tr, meta.client_id) {
-                        acc.hold_funds(&amount);
-                        self.disputed_transactions
-                            .insert(disputed_tr.meta().transaction_id, Rc::clone(disputed_tr));
+            Transaction::Withdrawal { amount: a, .. } => {
+                let fee = match self.config.withdrawal_fee {
+                    Some(fee) => fee.of(a).ok_or(Rejection::FeeExceedsAmount)?,
+                    None => Decimal::ZERO,
+                };
+                if acc.get_available_funds() < &a.saturating_add(fee) {
+                    return Err(Rejection::InsufficientFunds);
+                }
+                let recent = match &self.config.withdrawal_limit {
+                    Some(limit) => {
+                        let now = self.config.now();
+                        let recent = self.recent_withdrawals.entry(meta.client_id).or_default();
+                        if !limit.admits(recent, now, a) {
+                            eprintln!(
+                                "Warning: withdrawal tx {} of client {} exceeds the limit",
+                                meta.transaction_id, meta.client_id
+                            );
+                            return Err(Rejection::WithdrawalLimitExceeded);
+                        }
+                        Some((recent, now))
                     }
+                    None => None,
+                };
+                if guarded {
+                    self.totals.guard(-a - fee, Decimal::ZERO)?;
+                }
+                let mut debited = acc.clone();
+                debited.withdraw(&a, mode)?;
+                if !fee.is_zero() {
+                    debited.pay_fee(&fee, mode)?;
+                }
+                *acc = debited;
+                self.totals.add(-a - fee, Decimal::ZERO);
+                if self.config.count_transactions {
+                    acc.count_withdrawal();
+                }
+                if let Some((recent, now)) = recent {
+                    recent.push_back((now, a));
+                }
+                if let Some(histogram) = &mut self.amount_histogram {
+                    histogram.record(a);
                 }
             }
-            Transaction::Resolve { .. } => {
-                for disputed_tr in self.disputed_transactions.get(&meta.transaction_id) {
-                    for amount in disputed_amount(disputed_tr, meta.client_id) {
-                        acc.release_funds(&amount);
+            Transaction::Correct {
+                original_tx,
+                new_amount,
+                ..
+            } => {
+                // The difference applies directly, there is no hold phase as for disputes.
+                // Disputed transactions are uncorrectable as their history entry
+                // is dropped by the dispute. So are negative deposits, which are
+                // reversals kept out of the history, and corrections would flip
+                // the sign of an amount.
+                let original_key = (meta.client_id, original_tx);
+                if new_amount <= Decimal::ZERO {
+                    return Err(Rejection::UncorrectableOriginal);
+                }
+                let (corrected, delta) = match self.transaction_history.get(&original_key) {
+                    Some(original) => match original.as_ref() {
+                        Transaction::Deposit { meta, amount } => (
+                            Transaction::Deposit {
+                                meta: meta.clone(),
+                                amount: new_amount,
+                            },
+                            new_amount - amount,
+                        ),
+                        Transaction::Withdrawal { meta, amount } => (
+                            Transaction::Withdrawal {
+                                meta: meta.clone(),
+                                amount: new_amount,
+                            },
+                            amount - new_amount,
+                        ),
+                        _ => return Err(Rejection::UncorrectableOriginal),
+                    },
+                    None => return Err(Rejection::UncorrectableOriginal),
+                };
+
+                if delta < Decimal::ZERO {
+                    if acc.get_available_funds() < &-delta {
+                        return Err(Rejection::InsufficientFunds);
                     }
+                    if guarded {
+                        self.totals.guard(delta, Decimal::ZERO)?;
+                    }
+                    acc.withdraw(&-delta, mode)?;
+                } else {
+                    acc.deposit(&delta, mode)?;
                 }
+                self.totals.add(delta, Decimal::ZERO);
+                // The original is superseded, so later disputes and corrections see
+                // the corrected amount. The correction links to it by `original_tx`.
+                self.transaction_history
+                    .insert(original_key, Rc::new(corrected));
             }
-            Transaction::Chargeback { .. } => {
-                for disputed_tr in self.disputed_transactions.get(&meta.transaction_id) {
-                    for amount in disputed_amount(disputed_tr, meta.client_id) {
-                        acc.chargeback(&amount);
+            Transaction::Dispute {
+                amount: portion, ..
+            } => {
+                // A transaction once disputed is tracked by its dispute state,
+                // the history entry is dropped once the dispute is recorded.
+                let disputed_tr = match self.disputed_transactions.get(&key) {
+                    Some(state) => Rc::clone(&state.transaction),
+                    None => match self.transaction_history.get(&key) {
+                        Some(tr) => Rc::clone(tr),
+                        // A dispute of an unknown transaction takes no effect.
+                        None => return Ok(Outcome::Ignored),
+                    },
+                };
+                if let (Some(window), Transaction::Deposit { .. }) =
+                    (self.config.dispute_window, &*disputed_tr)
+                {
+                    // Times of deposits past the window may have been pruned.
+                    let now = self.config.now();
+                    let expired = self
+                        .deposited_at
+                        .get(&key)
+                        .is_none_or(|at| now.duration_since(*at).unwrap_or_default() > window);
+                    if expired {
+                        return Err(Rejection::DisputeWindowExpired);
+                    }
+                }
+                if let Some(amount) = disputed_amount(&disputed_tr) {
+                    let state = self
+                        .disputed_transactions
+                        .entry(key)
+                        .or_insert_with(|| DisputeState::new(Rc::clone(&disputed_tr)));
+                    if state.resolved > 0 && self.config.redispute_policy == RedisputePolicy::Reject
+                    {
+                        return Err(Rejection::AlreadyResolved);
+                    }
+                    let undisputed = amount - state.held;
+                    let portion = portion.unwrap_or(undisputed);
+                    if portion <= Decimal::ZERO || portion > undisputed {
+                        return Err(Rejection::AlreadyDisputed);
                     }
+                    // The money of a withdrawal has left already, nothing to hold.
+                    if state.is_withdrawal() {
+                        acc.add_pending_credit(&portion, mode)?;
+                    } else {
+                        if self.config.dispute_policy == DisputePolicy::Strict
+                            && acc.get_available_funds() < &portion
+                        {
+                            return Err(Rejection::InsufficientFunds);
+                        }
+                        if guarded {
+                            self.totals.guard(Decimal::ZERO, portion)?;
+                        }
+                        acc.hold_funds(&portion, mode)?;
+                        self.totals.add(Decimal::ZERO, portion);
+                    }
+                    state.open(portion);
+                }
+            }
+            Transaction::Resolve {
+                amount: portion, ..
+            } => {
+                let (state, amount) = open_dispute(&mut self.disputed_transactions, key, portion)?;
+                if state.is_withdrawal() {
+                    acc.drop_pending_credit(&amount, mode)?;
+                } else {
+                    acc.release_funds(&amount, mode)?;
+                    self.totals.add(Decimal::ZERO, -amount);
+                }
+                state.settle(portion);
+                state.resolved += 1;
+            }
+            Transaction::Chargeback {
+                amount: portion, ..
+            } => {
+                let (state, amount) = open_dispute(&mut self.disputed_transactions, key, portion)?;
+                if state.is_withdrawal() {
+                    acc.refund(&amount, meta.transaction_id, mode)?;
+                    self.totals.add(amount, Decimal::ZERO);
+                } else {
+                    acc.chargeback(&amount, meta.transaction_id, mode)?;
+                    self.totals.add(-amount, -amount);
                 }
+                state.settle(portion);
+            }
+            Transaction::Freeze { .. } => acc.freeze(),
+            Transaction::Unfreeze { .. } => acc.unfreeze(),
+        }
+        if let Some(scale) = self.config.max_scale {
+            acc.limit_scale(scale);
+        }
+        if let Some(total_before) = total_before {
+            let total = acc
+                .get_available_funds()
+                .saturating_add(*acc.get_held_funds());
+            if total < Decimal::ZERO && total_before >= Decimal::ZERO {
+                eprintln!(
+                    "Warning: {} tx {} of client {} makes the total funds negative: {}",
+                    tr.kind(),
+                    meta.transaction_id,
+                    meta.client_id,
+                    total
+                );
+                self.negative_totals.push(key);
             }
         }
+        if let (Some(window), Transaction::Deposit { .. }) = (self.config.dispute_window, tr) {
+            self.prune_deposit_times(window);
+        }
+        // Only applied transactions resemble or are resembled by others.
+        if let Some(window) = self.config.dedupe_window {
+            self.check_near_duplicate(tr, window);
+        }
+        Ok(Outcome::Applied)
+    }
+}
 
-        self.transaction_history
-            .insert(meta.transaction_id, Rc::new(tr));
+/// Returns a client with more than one account in the `output` sorted by client id.
+///
+/// Partitions own distinct clients, so that only happens if transactions
+/// of a client submitted with `Processor::process_grouped` were not contiguous.
+pub fn find_duplicate(output: &[Record<Account, ClientId>]) -> Option<ClientId> {
+    output
+        .windows(2)
+        .find(|pair| pair[0].id == pair[1].id)
+        .map(|pair| pair[0].id)
+}
+
+/// Same as `find_duplicate` but for outputs of separate workers (see `Processor::wait_shards`).
+pub fn find_duplicate_in_shards(shards: &[Output]) -> Option<ClientId> {
+    let mut ids: Vec<_> = shards.iter().flatten().map(|r| r.id).collect();
+    ids.sort_unstable();
+    ids.windows(2)
+        .find(|pair| pair[0] == pair[1])
+        .map(|pair| pair[0])
+}
+
+/// Merges outputs each sorted by client id into a single output sorted by client id.
+pub fn merge_sorted(outputs: Vec<Output>) -> Output {
+    let mut merged = Output::with_capacity(outputs.iter().map(Vec::len).sum());
+    let mut sources: Vec<_> = outputs
+        .into_iter()
+        .map(|output| output.into_iter().peekable())
+        .collect();
+    let mut heads: BinaryHeap<_> = sources
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(i, source)| source.peek().map(|r| Reverse((r.id, i))))
+        .collect();
+
+    while let Some(Reverse((_, i))) = heads.pop() {
+        merged.push(sources[i].next().unwrap());
+        if let Some(next) = sources[i].peek() {
+            heads.push(Reverse((next.id, i)));
+        }
     }
+
+    merged
 }
 
 /// Worker thread command.
+///
+/// There is no halt command: a worker halts once its channel is disconnected
+/// and drained (see `Processor::wait`).
 enum Command {
-    Job(Transaction),
-    Halt,
+    /// Process a transaction with the given sequence number.
+    Job(u64, Transaction),
+    /// Replace the account of the client.
+    Seed(ClientId, Account),
+    /// Restore an open dispute of the transaction of the client holding the amount.
+    SeedDispute(ClientId, TransactionId, Decimal),
+    /// Reply with the current account of the client, if any.
+    Query(ClientId, mpsc::Sender<Option<Account>>),
+    /// Evict the clients, replying with their accounts sorted by client id.
+    Evict(Vec<ClientId>, mpsc::Sender<Output>),
+    /// Reply with copies of all the current accounts sorted by client id.
+    Snapshot(mpsc::Sender<Output>),
+}
+
+/// Sending end of a worker command channel, either unbounded or bounded.
+enum CommandSender {
+    Unbounded(mpsc::Sender<Command>),
+    Bounded(mpsc::SyncSender<Command>),
+}
+
+impl CommandSender {
+    /// Creates a command channel, bounded if `bound` is set.
+    fn channel(bound: Option<usize>) -> (CommandSender, mpsc::Receiver<Command>) {
+        match bound {
+            Some(bound) => {
+                let (sender, receiver) = mpsc::sync_channel(bound);
+                (CommandSender::Bounded(sender), receiver)
+            }
+            None => {
+                let (sender, receiver) = mpsc::channel();
+                (CommandSender::Unbounded(sender), receiver)
+            }
+        }
+    }
+
+    /// Sends the command, blocking while a bounded channel is full.
+    /// Fails if the worker has gone.
+    fn send(&self, cmd: Command) -> Result<(), mpsc::SendError<Command>> {
+        match self {
+            CommandSender::Unbounded(sender) => sender.send(cmd),
+            CommandSender::Bounded(sender) => sender.send(cmd),
+        }
+    }
+}
+
+/// Processes a single job on the `partition` reporting an event of
+/// the applied transaction to `events` (if any), and the transaction
+/// rejected as its account is frozen to `dead_letters` (if any).
+fn run_job(
+    partition: &mut Partition,
+    events: &Option<mpsc::SyncSender<Event>>,
+    dead_letters: &Option<mpsc::Sender<DeadLetter>>,
+    sequence: u64,
+    tr: Transaction,
+) -> Result<Outcome, Rejection> {
+    let client_id = tr.meta().client_id;
+    let events = events
+        .as_ref()
+        .filter(|_| partition.config.event_client.is_none_or(|c| c == client_id));
+    let transaction = events.map(|_| tr.clone());
+    match partition.try_apply(tr) {
+        Ok(Outcome::Applied) => {
+            if let (Some(events), Some(transaction)) = (events, transaction) {
+                let account = partition.accounts.get_or_create(client_id).clone();
+                // Events are dropped once nobody receives them (see `Processor::wait`).
+                let _ = events.send(Event {
+                    sequence,
+                    transaction,
+                    account,
+                });
+            }
+            Ok(Outcome::Applied)
+        }
+        Ok(Outcome::Ignored) => Ok(Outcome::Ignored),
+        Err((rejection, transaction)) => {
+            if let (Rejection::FrozenAccount, Some(dead_letters)) = (rejection, dead_letters) {
+                // Dead letters are dropped once nobody receives them.
+                let _ = dead_letters.send(DeadLetter {
+                    sequence,
+                    transaction,
+                });
+            }
+            Err(rejection)
+        }
+    }
+}
+
+/// Same as `run_job` but a panic applying the transaction rolls it back and
+/// rejects it as `Rejection::Panicked` (see `Config::continue_on_panic`).
+fn run_isolated_job(
+    partition: &mut Partition,
+    events: &Option<mpsc::SyncSender<Event>>,
+    dead_letters: &Option<mpsc::Sender<DeadLetter>>,
+    sequence: u64,
+    tr: Transaction,
+) -> Result<Outcome, Rejection> {
+    let savepoint = partition.savepoint(&tr);
+    let (client_id, transaction_id) = (tr.meta().client_id, tr.meta().transaction_id);
+    // The partition is rolled back on a panic, so it is never observed broken.
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        run_job(partition, events, dead_letters, sequence, tr)
+    }));
+    result.unwrap_or_else(|_| {
+        partition.restore(savepoint);
+        eprintln!(
+            "Warning: transaction {} of client {} panicked, skipped",
+            transaction_id, client_id
+        );
+        Err(Rejection::Panicked)
+    })
 }
 
 /// Worker thread running a single partition.
 ///
 /// * `handle` - a thread handle.
-/// * `sender` - input chanel for sending task to the worker.
+/// * `sender` - input chanel for sending task to the worker. The worker
+///   halts once it's dropped and all the commands sent are processed.
 struct Worker {
     handle: thread::JoinHandle<()>,
-    sender: mpsc::Sender<Box<Command>>,
+    sender: CommandSender,
+    /// Estimate of the number of commands queued for the worker.
+    queued: Arc<AtomicUsize>,
 }
 
 /// Transaction processor. Works by distributing transactions between
@@ -119,92 +1505,1977 @@ struct Worker {
 /// This is important to ensure no withdrawals happen before deposits, no double
 /// withdrowals etc.
 ///
+/// As a consequence a single very active client loads a single worker however
+/// many there are. Such clients are reported if configured
+/// (see `Config::hot_client_percent`), so their input can be sharded up front.
+///
 /// The processor is created by spawning it (see `spawn`)
 ///
 /// TODO: ensure the struct constructor is private.
 pub struct Processor {
     workers: Vec<Worker>,
-    receiver: mpsc::Receiver<Box<Output>>,
+    /// Outputs and metrics of the workers by worker id.
+    receiver: mpsc::Receiver<Box<(usize, Output, RunMetrics)>>,
+    events: Option<mpsc::Receiver<Event>>,
+    dead_letters: Option<mpsc::Receiver<DeadLetter>>,
+    sequence: Cell<u64>,
+    recover: bool,
+    throttle_depth: Option<usize>,
+    metrics: RunMetrics,
+    /// Client and worker of the current run of grouped input (see `process_grouped`).
+    run: Option<(ClientId, usize)>,
+    /// Clients and workers of the runs of grouped input completed since
+    /// the last flush (see `flush_completed`).
+    completed: Vec<(ClientId, usize)>,
+    hot_client_percent: Option<u8>,
+    /// Number of transactions by client if hot clients are reported.
+    client_counts: RefCell<HashMap<ClientId, u64>>,
 }
 
 impl Processor {
     /// Creates a new processor with the specified number of cores (threads).
     /// The processor spawns the treads immediately.
     pub fn spawn(n_cores: usize) -> Processor {
-        let (acc_sender, acc_receiver) = mpsc::channel::<Box<Output>>();
+        Processor::spawn_with_config(n_cores, Config::default())
+    }
+
+    /// Same as `spawn` but partitions process transactions according to `config`.
+    pub fn spawn_with_config(n_cores: usize, config: Config) -> Processor {
+        Processor::spawn_with_stores(n_cores, config, || Box::new(HashMap::new()))
+    }
+
+    /// Same as `spawn_with_config` but each partition keeps its accounts
+    /// in a store created by `make_store`.
+    pub fn spawn_with_stores<F>(n_cores: usize, config: Config, make_store: F) -> Processor
+    where
+        F: Fn() -> Box<dyn AccountStore + Send>,
+    {
+        let (acc_sender, acc_receiver) = mpsc::channel::<Box<(usize, Output, RunMetrics)>>();
+        let (event_sender, event_receiver) = match config.emit_events {
+            true => {
+                let (sender, receiver) = mpsc::sync_channel::<Event>(EVENT_BOUND);
+                (Some(sender), Some(receiver))
+            }
+            false => (None, None),
+        };
+        let (dead_letter_sender, dead_letter_receiver) = match config.emit_dead_letters {
+            true => {
+                let (sender, receiver) = mpsc::channel::<DeadLetter>();
+                (Some(sender), Some(receiver))
+            }
+            false => (None, None),
+        };
 
         let workers: Vec<Worker> = (0..n_cores)
-            .map(|_| {
-                let (cmd_sender, cmd_receiver) = mpsc::channel::<Box<Command>>();
+            .map(|worker_id| {
+                let (cmd_sender, cmd_receiver) = CommandSender::channel(config.channel_bound);
+                let queued = Arc::new(AtomicUsize::new(0));
+                let worker_queued = Arc::clone(&queued);
                 let acc_sender = acc_sender.clone();
+                let event_sender = event_sender.clone();
+                let dead_letter_sender = dead_letter_sender.clone();
+                let store = make_store();
+                let config = config.clone();
+                // Workers report to the subscriber of the spawning thread.
+                #[cfg(feature = "tracing")]
+                let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
 
                 let handle = thread::spawn(move || {
-                    let mut partition = Partition::new();
-                    loop {
-                        let cmd = *cmd_receiver.recv().unwrap();
+                    #[cfg(feature = "tracing")]
+                    let _dispatch = tracing::dispatcher::set_default(&dispatch);
+                    #[cfg(feature = "tracing")]
+                    let span = tracing::info_span!(
+                        "worker",
+                        worker = worker_id,
+                        processed = tracing::field::Empty,
+                        rejected = tracing::field::Empty,
+                    )
+                    .entered();
+                    let mut partition = Partition::new(config, store);
+                    let mut metrics = RunMetrics::default();
+                    while let Ok(cmd) = cmd_receiver.recv() {
+                        worker_queued.fetch_sub(1, Ordering::Relaxed);
                         match cmd {
-                            Command::Job(tr) => partition.process(tr),
-                            Command::Halt => break,
+                            Command::Job(sequence, tr) => {
+                                #[cfg(feature = "tracing")]
+                                let (client_id, transaction_id) =
+                                    (tr.meta().client_id, tr.meta().transaction_id);
+                                let is_dispute = matches!(tr, Transaction::Dispute { .. });
+                                let result = match partition.config.continue_on_panic {
+                                    true => run_isolated_job(
+                                        &mut partition,
+                                        &event_sender,
+                                        &dead_letter_sender,
+                                        sequence,
+                                        tr,
+                                    ),
+                                    false => run_job(
+                                        &mut partition,
+                                        &event_sender,
+                                        &dead_letter_sender,
+                                        sequence,
+                                        tr,
+                                    ),
+                                };
+                                match result {
+                                    Ok(Outcome::Applied) => {
+                                        metrics.processed += 1;
+                                        metrics.disputes += is_dispute as u64;
+                                    }
+                                    Ok(Outcome::Ignored) => metrics.ignored += 1,
+                                    Err(rejection) => {
+                                        metrics.rejected += 1;
+                                        metrics.duplicates +=
+                                            (rejection == Rejection::Duplicate) as u64;
+                                        #[cfg(feature = "tracing")]
+                                        tracing::debug!(
+                                            client = %client_id,
+                                            tx = %transaction_id,
+                                            reason = ?rejection,
+                                            "transaction rejected"
+                                        );
+                                    }
+                                }
+                            }
+                            Command::Seed(client_id, account) => partition.seed(client_id, account),
+                            Command::SeedDispute(client_id, transaction_id, held) => {
+                                partition.seed_dispute(client_id, transaction_id, held)
+                            }
+                            Command::Query(client_id, reply) => {
+                                // The querying side may have given up waiting.
+                                let _ = reply.send(partition.accounts.get(client_id).cloned());
+                            }
+                            Command::Evict(client_ids, reply) => {
+                                let client_ids = client_ids.into_iter().collect();
+                                let mut accs: Vec<_> = partition
+                                    .evict(&client_ids)
+                                    .into_iter()
+                                    .map(|(client_id, account)| Record::new(account, client_id))
+                                    .collect();
+                                accs.sort_unstable_by_key(|r| r.id);
+                                let _ = reply.send(accs);
+                            }
+                            Command::Snapshot(reply) => {
+                                let mut accs: Vec<_> = partition
+                                    .accounts
+                                    .snapshot()
+                                    .into_iter()
+                                    .map(|(client_id, account)| Record::new(account, client_id))
+                                    .collect();
+                                accs.sort_unstable_by_key(|r| r.id);
+                                let _ = reply.send(accs);
+                            }
                         }
                     }
 
-                    let accs: Vec<_> = partition
+                    #[cfg(feature = "tracing")]
+                    span.record("processed", metrics.processed)
+                        .record("rejected", metrics.rejected);
+                    metrics.amount_histogram = partition.amount_histogram.take();
+                    metrics.negative_totals = std::mem::take(&mut partition.negative_totals);
+                    metrics.near_duplicates = std::mem::take(&mut partition.near_duplicates);
+                    let mut accs: Vec<_> = partition
                         .accounts
+                        .drain()
                         .into_iter()
                         .map(|(client_id, account)| Record::new(account, client_id))
                         .collect();
-                    acc_sender.send(Box::new(accs)).unwrap();
+                    accs.sort_unstable_by_key(|r| r.id);
+                    acc_sender
+                        .send(Box::new((worker_id, accs, metrics)))
+                        .unwrap();
                 });
 
                 Worker {
-                    handle: handle,
+                    handle,
                     sender: cmd_sender,
+                    queued,
                 }
             })
             .collect();
 
         Processor {
-            workers: workers,
+            workers,
             receiver: acc_receiver,
+            events: event_receiver,
+            dead_letters: dead_letter_receiver,
+            sequence: Cell::new(0),
+            recover: config.recover,
+            throttle_depth: config.throttle_depth,
+            metrics: RunMetrics::default(),
+            run: None,
+            completed: Vec::new(),
+            hot_client_percent: config.hot_client_percent,
+            client_counts: RefCell::new(HashMap::new()),
         }
     }
 
-    /// Submits transaction `tr` for processing.
-    pub fn process(&self, tr: Transaction) {
+    /// Returns metrics of the run. Complete once `wait` has returned.
+    pub fn metrics(&self) -> &RunMetrics {
+        &self.metrics
+    }
+
+    /// Takes the receiving end of the events channel if the processor has been
+    /// configured to emit events (see `Config::emit_events`).
+    ///
+    /// The channel is closed once the processor has finished (see `wait`).
+    /// It buffers a bounded number of events, so it must be drained while
+    /// transactions are processed, the workers wait otherwise. Events not
+    /// taken are discarded by `wait`.
+    /// Events of different partitions arrive in no particular order, so they
+    /// arrive in order of submission only with a single worker.
+    pub fn take_events(&mut self) -> Option<mpsc::Receiver<Event>> {
+        self.events.take()
+    }
+
+    /// Takes the receiving end of the dead letters channel if the processor has
+    /// been configured to emit them (see `Config::emit_dead_letters`).
+    ///
+    /// As with events, the channel is closed once the processor has finished
+    /// and dead letters of different partitions arrive in no particular order.
+    pub fn take_dead_letters(&mut self) -> Option<mpsc::Receiver<DeadLetter>> {
+        self.dead_letters.take()
+    }
+
+    /// Returns the number of partitions, one per worker. Zero once halted (see `wait`).
+    pub fn num_partitions(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Returns the index of the partition `process` submits transactions of the client to.
+    /// Transactions submitted with `process_grouped` are routed independently of it.
+    pub fn partition_of(&self, client_id: ClientId) -> usize {
         let n_workers = self.workers.len();
         assert!(n_workers > 0, "Processor is halted!");
 
         let mut hasher = DefaultHasher::new();
-        tr.meta().client_id.hash(&mut hasher);
-        let worker_id = (hasher.finish() % n_workers as u64) as usize;
-        self.workers[worker_id]
-            .sender
-            .send(Box::new(Command::Job(tr)))
-            .unwrap();
+        client_id.hash(&mut hasher);
+        (hasher.finish() % n_workers as u64) as usize
+    }
+
+    /// Sends the command to the worker running the partition of the client.
+    fn send(&self, client_id: ClientId, cmd: Command) {
+        self.send_to(self.partition_of(client_id), cmd);
+    }
+
+    /// Returns estimates of the number of commands queued for each worker.
+    pub fn queue_lengths(&self) -> Vec<usize> {
+        self.workers
+            .iter()
+            .map(|w| w.queued.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Sends the command to the worker `worker_id`.
+    fn send_to(&self, worker_id: usize, cmd: Command) {
+        let worker = &self.workers[worker_id];
+        if let Some(depth) = self.throttle_depth {
+            // A finished worker never drains its queue.
+            while worker.queued.load(Ordering::Relaxed) >= depth && !worker.handle.is_finished() {
+                thread::park_timeout(THROTTLE_PAUSE);
+            }
+        }
+
+        worker.queued.fetch_add(1, Ordering::Relaxed);
+        let sent = worker.sender.send(cmd);
+        // A failed worker is reported by `wait`, its transactions are lost.
+        assert!(sent.is_ok() || self.recover, "Worker has failed!");
+    }
+
+    /// Counts the transaction of the client if hot clients are reported.
+    fn count(&self, client_id: ClientId) {
+        if self.hot_client_percent.is_some() {
+            *self
+                .client_counts
+                .borrow_mut()
+                .entry(client_id)
+                .or_default() += 1;
+        }
+    }
+
+    /// Returns the sequence number of the next transaction and counts it.
+    fn next_sequence(&self) -> u64 {
+        let sequence = self.sequence.get();
+        self.sequence.set(sequence + 1);
+        sequence
+    }
+
+    /// Returns a snapshot of the current account of the client, `None` if the
+    /// client has no account (yet).
+    ///
+    /// The snapshot is a point-in-time read: it reflects all the transactions
+    /// submitted before the query and none after, and goes stale as processing
+    /// carries on. The query waits for the workers to get through their queues.
+    /// All workers are asked, so clients of grouped input are found as well
+    /// (see `process_grouped`).
+    pub fn query(&self, client_id: ClientId) -> Option<Account> {
+        let (reply, replies) = mpsc::channel();
+        for worker_id in 0..self.workers.len() {
+            self.send_to(worker_id, Command::Query(client_id, reply.clone()));
+        }
+        drop(reply);
+        // Failed workers drop their reply senders, so the iteration ends.
+        replies.iter().flatten().next()
+    }
+
+    /// Returns a snapshot of all the current accounts sorted by client id,
+    /// while processing carries on (see `query` for the consistency of snapshots).
+    pub fn snapshot(&self) -> Output {
+        let (reply, replies) = mpsc::channel();
+        for worker_id in 0..self.workers.len() {
+            self.send_to(worker_id, Command::Snapshot(reply.clone()));
+        }
+        drop(reply);
+        // Failed workers drop their reply senders, so the iteration ends.
+        merge_sorted(replies.iter().collect())
+    }
+
+    /// Submits transaction `tr` for processing.
+    pub fn process(&self, tr: Transaction) {
+        let client_id = tr.meta().client_id;
+        self.count(client_id);
+        self.send(client_id, Command::Job(self.next_sequence(), tr));
+    }
+
+    /// Same as `process` but for input grouped by client: instead of hashing
+    /// the client id, each run of transactions of one client goes to the next
+    /// worker in turn, switching workers whenever the client changes.
+    ///
+    /// All transactions of a client must be contiguous in the input, otherwise
+    /// the client's account is split between partitions. Transactions of a processor
+    /// must be submitted either all with `process_grouped` or none, and accounts
+    /// must not be seeded, as both are routed by hash.
+    pub fn process_grouped(&mut self, tr: Transaction) {
+        assert!(!self.workers.is_empty(), "Processor is halted!");
+
+        let client_id = tr.meta().client_id;
+        let worker_id = match self.run {
+            Some((run_client_id, worker_id)) if run_client_id == client_id => worker_id,
+            Some(run) => {
+                self.completed.push(run);
+                (run.1 + 1) % self.workers.len()
+            }
+            None => 0,
+        };
+        self.run = Some((client_id, worker_id));
+        self.count(client_id);
+
+        self.send_to(worker_id, Command::Job(self.next_sequence(), tr));
+    }
+
+    /// Returns the number of clients of grouped input whose run of transactions
+    /// has completed since the last flush (see `flush_completed`).
+    pub fn completed_clients(&self) -> usize {
+        self.completed.len()
+    }
+
+    /// Evicts the clients of grouped input whose run of transactions has
+    /// completed, returning their accounts sorted by client id. Memory of
+    /// the processor is then bounded by the number of clients between flushes.
+    ///
+    /// Only safe for input with all transactions of a client contiguous, as
+    /// required by `process_grouped` anyway: the evicted clients are forgotten,
+    /// history included, so a later transaction of one of them starts a new
+    /// account from scratch and disputes of its earlier transactions are
+    /// ignored. Such a split account is neither merged nor detected.
+    ///
+    /// The call waits for the workers to get through their queues.
+    pub fn flush_completed(&mut self) -> Output {
+        let mut by_worker = vec![Vec::new(); self.workers.len()];
+        for (client_id, worker_id) in self.completed.drain(..) {
+            by_worker[worker_id].push(client_id);
+        }
+
+        let (reply, replies) = mpsc::channel();
+        for (worker_id, client_ids) in by_worker.into_iter().enumerate() {
+            if !client_ids.is_empty() {
+                self.send_to(worker_id, Command::Evict(client_ids, reply.clone()));
+            }
+        }
+        drop(reply);
+        // Failed workers drop their reply senders, so the iteration ends.
+        merge_sorted(replies.iter().collect())
+    }
+
+    /// Sets the initial state of the client account, e.g. restored from a prior run.
+    ///
+    /// Only balances are restored: transactions of prior runs are unknown to
+    /// the processor, so disputes referencing them are ignored unless their
+    /// open disputes are restored too (see `seed_dispute`).
+    pub fn seed(&mut self, client_id: ClientId, account: Account) {
+        self.send(client_id, Command::Seed(client_id, account));
+    }
+
+    /// Restores an open dispute of a prior run, so a resolve or chargeback of it
+    /// settles the `held` funds. Balances are left intact: the held funds are
+    /// expected to be restored with the account (see `seed`).
+    pub fn seed_dispute(
+        &mut self,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        held: Decimal,
+    ) {
+        self.send(
+            client_id,
+            Command::SeedDispute(client_id, transaction_id, held),
+        );
     }
 
     /// Waits for processor to finish running all submitted transactions.
-    /// Returns the resulting account. Account order is unspecified.
+    /// Returns the resulting accounts sorted by client id.
+    ///
+    /// Each worker sorts its own accounts, so only a merge of the sorted
+    /// sequences is left to the calling thread (see `wait_shards`).
+    ///
+    /// A worker panic is propagated unless the processor is configured to
+    /// recover (see `Config::recover`), in which case the accounts of the failed
+    /// partition are excluded from the result.
+    ///
+    /// Workers are halted by disconnecting their channels rather than sending
+    /// them a command, so halting never blocks on a full channel of a failed worker.
+    /// A disconnected channel still yields every command queued before it was
+    /// disconnected, so a worker halts only after running all the commands sent
+    /// to it, bounded channels included.
     pub fn wait(&mut self) -> Output {
+        merge_sorted(self.wait_shards())
+    }
+
+    /// Same as `wait` but returns the accounts of every worker separately,
+    /// each sorted by client id, by worker id. Clients are partitioned between
+    /// the workers, so the shards are disjoint unless a grouped input had
+    /// transactions of a client apart (see `process_grouped`).
+    /// The shard of a failed worker is empty.
+    pub fn wait_shards(&mut self) -> Vec<Output> {
+        // Workers waiting on the full channel of events never taken resume once
+        // it is disconnected.
+        self.events = None;
         let n_workers = self.workers.len();
+        for (i, worker) in self.workers.drain(..).enumerate() {
+            drop(worker.sender);
+            if let Err(err) = worker.handle.join() {
+                if !self.recover {
+                    panic::resume_unwind(err);
+                }
+                eprintln!("Warning: worker {} failed, its accounts are excluded", i);
+                self.metrics.failed_partitions += 1;
+            }
+        }
+
+        let mut shards: Vec<Output> = (0..n_workers).map(|_| Output::new()).collect();
+        for result in self.receiver.try_iter() {
+            let (worker_id, output, metrics) = *result;
+            self.metrics.merge(&metrics);
+            shards[worker_id] = output;
+        }
+        self.report_hot_clients();
+        self.metrics.frozen_accounts = shards
+            .iter()
+            .flatten()
+            .filter(|r| r.item.is_frozen())
+            .count();
+        shards
+    }
+
+    /// Records clients exceeding `Config::hot_client_percent` of all transactions to metrics.
+    fn report_hot_clients(&mut self) {
+        let Some(percent) = self.hot_client_percent else {
+            return;
+        };
+
+        let total = self.sequence.get();
+        let mut hot_clients: Vec<_> = self
+            .client_counts
+            .get_mut()
+            .iter()
+            .filter(|(_, count)| **count * 100 > total * percent as u64)
+            .map(|(client_id, count)| (*client_id, *count))
+            .collect();
+        hot_clients.sort_unstable();
+        for (client_id, count) in &hot_clients {
+            eprintln!(
+                "Warning: client {} has {} of {} transactions, consider sharding its input",
+                client_id, count, total
+            );
+        }
+        self.metrics.hot_clients = hot_clients;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Meta;
+    use indoc::indoc;
+    use rust_decimal_macros::dec;
+
+    fn meta(client_id: u16, transaction_id: u32) -> Meta {
+        Meta {
+            client_id: ClientId::new(client_id),
+            transaction_id: TransactionId::new(transaction_id),
+        }
+    }
+
+    fn deposit(client_id: u16, transaction_id: u32, amount: Decimal) -> Transaction {
+        Transaction::Deposit {
+            meta: meta(client_id, transaction_id),
+            amount,
+        }
+    }
+
+    fn withdrawal(client_id: u16, transaction_id: u32, amount: Decimal) -> Transaction {
+        Transaction::Withdrawal {
+            meta: meta(client_id, transaction_id),
+            amount,
+        }
+    }
+
+    fn dispute(client_id: u16, transaction_id: u32) -> Transaction {
+        Transaction::Dispute {
+            meta: meta(client_id, transaction_id),
+            amount: None,
+        }
+    }
+
+    fn new_partition(config: Config) -> Partition {
+        Partition::new(config, Box::new(HashMap::new()))
+    }
+
+    fn account(partition: &mut Partition, client_id: u16) -> &mut Account {
+        partition.accounts.get_or_create(ClientId::new(client_id))
+    }
+
+    fn available(partition: &mut Partition, client_id: u16) -> Decimal {
+        *account(partition, client_id).get_available_funds()
+    }
+
+    #[test]
+    fn rejecting_duplicate_ids() {
+        let mut partition = new_partition(Config {
+            duplicate_tx_policy: DuplicateTxPolicy::Reject,
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(4))).unwrap();
+        assert_eq!(
+            partition.process(deposit(1, 1, dec!(5))),
+            Err(Rejection::DuplicateId)
+        );
+        assert_eq!(
+            partition.process(withdrawal(1, 1, dec!(1))),
+            Err(Rejection::DuplicateId)
+        );
+        // Ids are per client, and a disputed deposit still holds its id.
+        assert_eq!(partition.process(deposit(2, 1, dec!(2))), Ok(()));
+        partition.process(dispute(1, 1)).unwrap();
+        assert_eq!(
+            partition.process(deposit(1, 1, dec!(5))),
+            Err(Rejection::DuplicateId)
+        );
+        assert_eq!(available(&mut partition, 1), dec!(0));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(4));
+
+        // Other transactions reusing the id don't make it free again.
+        partition.process(deposit(3, 2, dec!(2))).unwrap();
+        partition
+            .process(Transaction::Freeze { meta: meta(3, 2) })
+            .unwrap();
+        partition
+            .process(Transaction::Unfreeze { meta: meta(3, 2) })
+            .unwrap();
+        assert_eq!(
+            partition.process(deposit(3, 2, dec!(2))),
+            Err(Rejection::DuplicateId)
+        );
+
+        // A frozen account rejects the duplicate as frozen first.
+        partition
+            .process(Transaction::Freeze { meta: meta(3, 3) })
+            .unwrap();
+        assert_eq!(
+            partition.process(deposit(3, 2, dec!(2))),
+            Err(Rejection::FrozenAccount)
+        );
+        assert_eq!(available(&mut partition, 3), dec!(2));
+    }
+
+    #[test]
+    fn ignoring_zero_amounts() {
+        let mut partition = new_partition(Config {
+            deposit_fee: Some(Fee::Flat(dec!(1))),
+            count_transactions: true,
+            ..Config::default()
+        });
+        assert_eq!(
+            partition.apply(deposit(1, 1, dec!(0))),
+            Ok(Outcome::Ignored)
+        );
+        assert_eq!(
+            partition.apply(withdrawal(1, 2, dec!(0))),
+            Ok(Outcome::Ignored)
+        );
+        assert!(partition.accounts.get(ClientId::new(1)).is_some());
+        assert_eq!(account(&mut partition, 1).get_deposit_count(), 0);
+        assert!(partition.transaction_history.is_empty());
+
+        // The ids of ignored transactions are free to take.
+        assert_eq!(
+            partition.apply(deposit(1, 1, dec!(5))),
+            Ok(Outcome::Applied)
+        );
+        assert_eq!(available(&mut partition, 1), dec!(4));
+    }
+
+    #[test]
+    fn keeping_first_of_duplicate_ids() {
+        let mut partition = new_partition(Config {
+            duplicate_tx_policy: DuplicateTxPolicy::KeepFirst,
+            ..Config::default()
+        });
+        assert_eq!(
+            partition.apply(deposit(1, 1, dec!(4))),
+            Ok(Outcome::Applied)
+        );
+        assert_eq!(
+            partition.apply(deposit(1, 1, dec!(5))),
+            Ok(Outcome::Ignored)
+        );
+        assert_eq!(
+            partition.apply(withdrawal(1, 1, dec!(1))),
+            Ok(Outcome::Ignored)
+        );
+        assert_eq!(available(&mut partition, 1), dec!(4));
+
+        // The dispute applies to the first deposit of the id.
+        partition.process(dispute(1, 1)).unwrap();
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(4));
+    }
+
+    #[test]
+    fn ignoring_duplicate_ids() {
+        let config = Config {
+            duplicate_tx_policy: DuplicateTxPolicy::KeepFirst,
+            emit_events: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(2, config);
+        let events = processor.take_events().unwrap();
+        processor.process(deposit(1, 1, dec!(4)));
+        processor.process(deposit(1, 1, dec!(5)));
+        processor.process(withdrawal(1, 2, dec!(1)));
+        processor.wait();
+
+        let sequences: Vec<_> = events.iter().map(|e| e.sequence).collect();
+        assert_eq!(sequences, vec![0, 2]);
+        assert_eq!(processor.metrics().processed, 2);
+        assert_eq!(processor.metrics().ignored, 1);
+        assert_eq!(processor.metrics().rejected, 0);
+    }
+
+    #[test]
+    fn fingerprint_dedup() {
+        // Without history, repeated ids are no duplicates (see `Config::duplicate_tx_policy`).
+        let mut partition = new_partition(Config {
+            no_disputes: true,
+            ..Config::default()
+        });
+        assert_eq!(partition.process(deposit(1, 1, dec!(4))), Ok(()));
+        assert_eq!(partition.process(deposit(1, 1, dec!(4))), Ok(()));
+        assert_eq!(available(&mut partition, 1), dec!(8));
+
+        let mut partition = new_partition(Config {
+            dedup_fingerprints: true,
+            no_disputes: true,
+            ..Config::default()
+        });
+        assert_eq!(partition.process(deposit(1, 1, dec!(4))), Ok(()));
+        assert_eq!(
+            partition.process(deposit(1, 1, dec!(4))),
+            Err(Rejection::Duplicate)
+        );
+        assert_eq!(partition.process(deposit(1, 1, dec!(5))), Ok(()));
+        assert_eq!(available(&mut partition, 1), dec!(9));
+
+        // A rejected transaction is no duplicate once retried.
+        assert_eq!(
+            partition.process(withdrawal(1, 2, dec!(10))),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(partition.process(deposit(1, 3, dec!(1))), Ok(()));
+        assert_eq!(partition.process(withdrawal(1, 2, dec!(10))), Ok(()));
+        assert_eq!(
+            partition.process(withdrawal(1, 2, dec!(10))),
+            Err(Rejection::Duplicate)
+        );
+        assert_eq!(available(&mut partition, 1), dec!(0));
+    }
+
+    #[test]
+    fn counting_duplicates() {
+        let config = Config {
+            dedup_fingerprints: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(2, config);
+        processor.process(deposit(1, 1, dec!(4)));
+        processor.process(deposit(1, 1, dec!(4)));
+        processor.process(withdrawal(1, 2, dec!(5)));
+        processor.wait();
+
+        assert_eq!(processor.metrics().rejected, 2);
+        assert_eq!(processor.metrics().duplicates, 1);
+    }
+
+    #[test]
+    fn correcting_deposits() {
+        let correct = |transaction_id, original_tx, new_amount| Transaction::Correct {
+            meta: meta(1, transaction_id),
+            original_tx: TransactionId::new(original_tx),
+            new_amount,
+        };
+        let mut partition = new_partition(Config::default());
+        assert_eq!(partition.process(deposit(1, 1, dec!(10))), Ok(()));
+
+        assert_eq!(partition.process(correct(2, 1, dec!(15))), Ok(()));
+        assert_eq!(available(&mut partition, 1), dec!(15));
+        assert_eq!(partition.process(correct(3, 1, dec!(4))), Ok(()));
+        assert_eq!(available(&mut partition, 1), dec!(4));
+
+        // Corrections of corrections or unknown transactions are rejected.
+        assert_eq!(
+            partition.process(correct(4, 3, dec!(5))),
+            Err(Rejection::UncorrectableOriginal)
+        );
+        assert_eq!(
+            partition.process(correct(5, 9, dec!(5))),
+            Err(Rejection::UncorrectableOriginal)
+        );
+
+        // Reversals are not corrected, nor amounts flipped.
+        assert_eq!(partition.process(deposit(1, 8, dec!(1))), Ok(()));
+        assert_eq!(partition.process(deposit(1, 10, dec!(-1))), Ok(()));
+        assert_eq!(
+            partition.process(correct(11, 10, dec!(1))),
+            Err(Rejection::UncorrectableOriginal)
+        );
+        assert_eq!(
+            partition.process(correct(12, 1, dec!(-4))),
+            Err(Rejection::UncorrectableOriginal)
+        );
+        assert_eq!(available(&mut partition, 1), dec!(4));
+
+        assert_eq!(partition.process(withdrawal(1, 6, dec!(3))), Ok(()));
+        assert_eq!(
+            partition.process(correct(7, 1, dec!(2))),
+            Err(Rejection::InsufficientFunds)
+        );
+
+        // A dispute holds the corrected amount.
+        assert_eq!(partition.process(dispute(1, 1)), Ok(()));
+        assert_eq!(available(&mut partition, 1), dec!(-3));
+        assert_eq!(*account(&mut partition, 1).get_held_funds(), dec!(4));
+    }
+
+    #[test]
+    fn dispute_of_withdrawn_deposit() {
+        let mut partition = new_partition(Config {
+            dispute_policy: DisputePolicy::Strict,
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(10))).unwrap();
+        partition.process(withdrawal(1, 2, dec!(8))).unwrap();
+        assert_eq!(
+            partition.process(dispute(1, 1)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(available(&mut partition, 1), dec!(2));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(0));
+
+        let mut partition = new_partition(Config {
+            dispute_policy: DisputePolicy::Permissive,
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(10))).unwrap();
+        partition.process(withdrawal(1, 2, dec!(8))).unwrap();
+        assert_eq!(partition.process(dispute(1, 1)), Ok(()));
+        assert_eq!(available(&mut partition, 1), dec!(-8));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(10));
+    }
+
+    #[test]
+    fn partial_disputes() {
+        let mut partition = new_partition(Config::default());
+        partition.process(deposit(1, 1, dec!(10))).unwrap();
+        for amount in [dec!(3), dec!(4)] {
+            let tr = Transaction::Dispute {
+                meta: meta(1, 1),
+                amount: Some(amount),
+            };
+            partition.process(tr).unwrap();
+        }
+        assert_eq!(available(&mut partition, 1), dec!(3));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(7));
+
+        let tr = Transaction::Resolve {
+            meta: meta(1, 1),
+            amount: Some(dec!(3)),
+        };
+        partition.process(tr).unwrap();
+        assert_eq!(available(&mut partition, 1), dec!(6));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(4));
+
+        let tr = Transaction::Chargeback {
+            meta: meta(1, 1),
+            amount: Some(dec!(4)),
+        };
+        partition.process(tr).unwrap();
+        assert_eq!(available(&mut partition, 1), dec!(6));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(0));
+        assert!(account(&mut partition, 1).is_frozen());
+    }
+
+    #[test]
+    fn dispute_window() {
+        let clock = Arc::new(crate::clock::FixedClock::new(SystemTime::UNIX_EPOCH));
+        let mut partition = new_partition(Config {
+            dispute_window: Some(Duration::from_secs(60)),
+            clock: Some(clock.clone()),
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(5))).unwrap();
+        partition.process(deposit(1, 2, dec!(3))).unwrap();
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(partition.process(dispute(1, 1)), Ok(()));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(5));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            partition.process(dispute(1, 2)),
+            Err(Rejection::DisputeWindowExpired)
+        );
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(5));
+    }
+
+    #[test]
+    fn pruning_deposit_times() {
+        let clock = Arc::new(crate::clock::FixedClock::new(SystemTime::UNIX_EPOCH));
+        let mut partition = new_partition(Config {
+            dispute_window: Some(Duration::from_secs(60)),
+            clock: Some(clock.clone()),
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(5))).unwrap();
+        clock.advance(Duration::from_secs(61));
+        let count = 2 * PRUNE_MIN_DEPOSITS as u32;
+        for id in 2..=count {
+            partition.process(deposit(1, id, dec!(1))).unwrap();
+        }
+        assert_eq!(partition.deposited_at.len(), count as usize - 1);
+        assert!(!partition
+            .deposited_at
+            .contains_key(&(ClientId::new(1), TransactionId::new(1))));
+
+        // Deposits past the window stay undisputable once their time is pruned.
+        assert_eq!(
+            partition.process(dispute(1, 1)),
+            Err(Rejection::DisputeWindowExpired)
+        );
+        assert_eq!(partition.process(dispute(1, 2)), Ok(()));
+    }
+
+    fn resolve(client_id: u16, transaction_id: u32) -> Transaction {
+        Transaction::Resolve {
+            meta: meta(client_id, transaction_id),
+            amount: None,
+        }
+    }
+
+    #[test]
+    fn disputing_withdrawals() {
+        let mut partition = new_partition(Config::default());
+        partition.process(deposit(1, 1, dec!(10))).unwrap();
+        partition.process(withdrawal(1, 2, dec!(4))).unwrap();
+
+        partition.process(dispute(1, 2)).unwrap();
+        let acc = account(&mut partition, 1);
+        assert_eq!(acc.get_available_funds(), &dec!(6));
+        assert_eq!(acc.get_held_funds(), &dec!(0));
+        assert_eq!(acc.get_pending_credits(), &dec!(4));
+
+        partition.process(resolve(1, 2)).unwrap();
+        let acc = account(&mut partition, 1);
+        assert_eq!(acc.get_available_funds(), &dec!(6));
+        assert_eq!(acc.get_pending_credits(), &dec!(0));
+
+        partition.process(dispute(1, 2)).unwrap();
+        let chargeback = Transaction::Chargeback {
+            meta: meta(1, 2),
+            amount: None,
+        };
+        partition.process(chargeback).unwrap();
+        let acc = account(&mut partition, 1);
+        assert_eq!(acc.get_available_funds(), &dec!(10));
+        assert_eq!(acc.get_held_funds(), &dec!(0));
+        assert_eq!(acc.get_pending_credits(), &dec!(0));
+        assert!(acc.is_frozen());
+    }
+
+    #[test]
+    fn limiting_scale() {
+        let mut partition = new_partition(Config {
+            max_scale: Some(6),
+            ..Config::default()
+        });
+        for tx in 1..=100 {
+            partition
+                .process(deposit(1, tx, dec!(0.333333333333)))
+                .unwrap();
+            assert!(available(&mut partition, 1).scale() <= 6);
+        }
+        assert_eq!(available(&mut partition, 1), dec!(33.3333));
+
+        let mut partition = new_partition(Config::default());
+        for tx in 1..=100 {
+            partition
+                .process(deposit(1, tx, dec!(0.333333333333)))
+                .unwrap();
+        }
+        assert_eq!(available(&mut partition, 1), dec!(33.3333333333));
+        assert_eq!(available(&mut partition, 1).scale(), 12);
+    }
+
+    #[test]
+    fn disabling_disputes() {
+        let mut partition = new_partition(Config {
+            no_disputes: true,
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(5))).unwrap();
+        partition.process(withdrawal(1, 2, dec!(2))).unwrap();
+
+        assert_eq!(
+            partition.process(dispute(1, 1)),
+            Err(Rejection::DisputesDisabled)
+        );
+        assert_eq!(
+            partition.process(resolve(1, 1)),
+            Err(Rejection::DisputesDisabled)
+        );
+        assert!(partition.transaction_history.is_empty());
+        assert!(partition.disputed_transactions.is_empty());
+        assert_eq!(available(&mut partition, 1), dec!(3));
+    }
+
+    #[test]
+    fn checking_total() {
+        let mut partition = new_partition(Config {
+            check_total: true,
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(5))).unwrap();
+        partition.process(withdrawal(1, 2, dec!(5))).unwrap();
+        partition.process(dispute(1, 1)).unwrap();
+        assert!(partition.negative_totals.is_empty());
+
+        let chargeback = Transaction::Chargeback {
+            meta: meta(1, 1),
+            amount: None,
+        };
+        partition.process(chargeback).unwrap();
+        assert_eq!(
+            partition.negative_totals,
+            vec![(ClientId::new(1), TransactionId::new(1))]
+        );
+        assert_eq!(available(&mut partition, 1), dec!(-5));
+    }
+
+    #[test]
+    fn checking_near_duplicates() {
+        let mut partition = new_partition(Config {
+            dedupe_window: Some(2),
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(5))).unwrap();
+        partition.process(deposit(2, 2, dec!(5))).unwrap();
+        partition.process(withdrawal(1, 3, dec!(5))).unwrap();
+        assert!(partition.near_duplicates.is_empty());
+
+        partition.process(deposit(1, 4, dec!(5))).unwrap();
+        assert_eq!(
+            partition.near_duplicates,
+            vec![(
+                ClientId::new(1),
+                TransactionId::new(4),
+                TransactionId::new(1)
+            )]
+        );
+        assert_eq!(available(&mut partition, 1), dec!(5));
+
+        // Deposit 4 is out of the window by then, it holds deposit 5 and withdrawal 6.
+        partition.process(deposit(1, 5, dec!(7))).unwrap();
+        partition.process(withdrawal(1, 6, dec!(1))).unwrap();
+        partition.process(deposit(1, 7, dec!(5))).unwrap();
+        assert_eq!(partition.near_duplicates.len(), 1);
+
+        // Rejected transactions neither resemble nor are resembled by others.
+        assert_eq!(
+            partition.process(withdrawal(2, 8, dec!(50))),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(
+            partition.process(withdrawal(2, 9, dec!(50))),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(partition.near_duplicates.len(), 1);
+    }
+
+    #[test]
+    fn charging_fees() {
+        let mut partition = new_partition(Config {
+            deposit_fee: Some(Fee::Percent(dec!(1))),
+            withdrawal_fee: Some(Fee::Flat(dec!(2))),
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(100))).unwrap();
+        assert_eq!(available(&mut partition, 1), dec!(99));
+        assert_eq!(account(&mut partition, 1).get_fees(), &dec!(1));
+
+        partition.process(withdrawal(1, 2, dec!(10))).unwrap();
+        assert_eq!(available(&mut partition, 1), dec!(87));
+        assert_eq!(account(&mut partition, 1).get_fees(), &dec!(3));
+
+        assert_eq!(
+            partition.process(withdrawal(1, 3, dec!(1))),
+            Err(Rejection::FeeExceedsAmount)
+        );
+        assert_eq!(
+            partition.process(withdrawal(1, 4, dec!(86))),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(available(&mut partition, 1), dec!(87));
+
+        // A fee overflowing the fees paid rejects the transaction as a whole.
+        let fee = Decimal::MAX - dec!(1);
+        let mut partition = new_partition(Config {
+            deposit_fee: Some(Fee::Flat(fee)),
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, fee)).unwrap();
+        assert_eq!(
+            partition.process(deposit(1, 2, fee)),
+            Err(Rejection::Overflow)
+        );
+        assert_eq!(available(&mut partition, 1), dec!(0));
+        assert_eq!(account(&mut partition, 1).get_fees(), &fee);
+        assert_eq!(partition.totals.deposited, dec!(0));
+    }
+
+    #[test]
+    fn redisputing_allowed() {
+        let mut partition = new_partition(Config::default());
+        partition.process(deposit(1, 1, dec!(5))).unwrap();
+        partition.process(dispute(1, 1)).unwrap();
+        partition.process(resolve(1, 1)).unwrap();
 
-        for worker in &self.workers {
-            worker.sender.send(Box::new(Command::Halt)).unwrap();
+        assert_eq!(partition.process(dispute(1, 1)), Ok(()));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(5));
+        assert_eq!(available(&mut partition, 1), dec!(0));
+    }
+
+    #[test]
+    fn redisputing_rejected() {
+        let mut partition = new_partition(Config {
+            redispute_policy: RedisputePolicy::Reject,
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(5))).unwrap();
+        partition.process(dispute(1, 1)).unwrap();
+        partition.process(resolve(1, 1)).unwrap();
+
+        assert_eq!(
+            partition.process(dispute(1, 1)),
+            Err(Rejection::AlreadyResolved)
+        );
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(0));
+        assert_eq!(available(&mut partition, 1), dec!(5));
+    }
+
+    #[test]
+    fn withdrawal_limit() {
+        let clock = Arc::new(crate::clock::FixedClock::new(SystemTime::UNIX_EPOCH));
+        let mut partition = new_partition(Config {
+            withdrawal_limit: Some(WithdrawalLimit {
+                amount: dec!(10),
+                span: LimitSpan::Time(Duration::from_secs(60)),
+            }),
+            clock: Some(clock.clone()),
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(100))).unwrap();
+        partition.process(withdrawal(1, 2, dec!(4))).unwrap();
+        partition.process(withdrawal(1, 3, dec!(4))).unwrap();
+
+        assert_eq!(
+            partition.process(withdrawal(1, 4, dec!(4))),
+            Err(Rejection::WithdrawalLimitExceeded)
+        );
+        assert_eq!(available(&mut partition, 1), dec!(92));
+        partition.process(deposit(2, 5, dec!(10))).unwrap();
+        assert_eq!(partition.process(withdrawal(2, 6, dec!(10))), Ok(()));
+
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(partition.process(withdrawal(1, 7, dec!(4))), Ok(()));
+        assert_eq!(available(&mut partition, 1), dec!(88));
+    }
+
+    #[test]
+    fn withdrawal_limit_per_withdrawals() {
+        let mut partition = new_partition(Config {
+            withdrawal_limit: Some(WithdrawalLimit {
+                amount: dec!(10),
+                span: LimitSpan::Withdrawals(2),
+            }),
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(100))).unwrap();
+        partition.process(withdrawal(1, 2, dec!(6))).unwrap();
+
+        assert_eq!(
+            partition.process(withdrawal(1, 3, dec!(5))),
+            Err(Rejection::WithdrawalLimitExceeded)
+        );
+        assert_eq!(partition.process(withdrawal(1, 4, dec!(4))), Ok(()));
+        assert_eq!(partition.process(withdrawal(1, 5, dec!(6))), Ok(()));
+        assert_eq!(available(&mut partition, 1), dec!(84));
+    }
+
+    #[test]
+    fn resolve_before_dispute() {
+        let mut partition = new_partition(Config::default());
+        partition.process(deposit(1, 2, dec!(5))).unwrap();
+
+        let resolve = Transaction::Resolve {
+            meta: meta(1, 2),
+            amount: None,
+        };
+        assert_eq!(partition.process(resolve), Err(Rejection::NotDisputed));
+        assert!(partition.disputed_transactions.is_empty());
+        assert!(matches!(
+            partition.transaction_history[&(ClientId::new(1), TransactionId::new(2))].as_ref(),
+            Transaction::Deposit { .. }
+        ));
+
+        assert_eq!(partition.process(dispute(1, 2)), Ok(()));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(5));
+
+        let chargeback = Transaction::Chargeback {
+            meta: meta(1, 2),
+            amount: None,
+        };
+        assert_eq!(partition.process(chargeback.clone()), Ok(()));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(0));
+        assert!(account(&mut partition, 1).is_frozen());
+
+        // Nothing is left to settle once the dispute is charged back.
+        account(&mut partition, 1).unfreeze();
+        assert_eq!(partition.process(chargeback), Err(Rejection::NotDisputed));
+    }
+
+    #[test]
+    fn keeping_disputable_history() {
+        let mut partition = new_partition(Config::default());
+        partition.process(deposit(1, 1, dec!(5))).unwrap();
+        partition.process(withdrawal(1, 2, dec!(2))).unwrap();
+        partition.process(dispute(1, 1)).unwrap();
+        let resolve = Transaction::Resolve {
+            meta: meta(1, 1),
+            amount: None,
+        };
+        partition.process(resolve).unwrap();
+        let freeze = Transaction::Freeze { meta: meta(1, 3) };
+        partition.process(freeze).unwrap();
+        let unfreeze = Transaction::Unfreeze { meta: meta(1, 3) };
+        partition.process(unfreeze).unwrap();
+
+        let history: Vec<_> = partition.transaction_history.keys().collect();
+        assert_eq!(history, vec![&(ClientId::new(1), TransactionId::new(2))]);
+
+        // The dispute state keeps the resolved deposit disputable.
+        assert_eq!(partition.process(dispute(1, 1)), Ok(()));
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(5));
+        assert_eq!(partition.process(dispute(1, 2)), Ok(()));
+        assert_eq!(account(&mut partition, 1).get_pending_credits(), &dec!(2));
+    }
+
+    #[test]
+    fn same_transaction_id_of_different_clients() {
+        let mut partition = new_partition(Config::default());
+        partition.process(deposit(1, 1, dec!(10))).unwrap();
+        partition.process(deposit(2, 1, dec!(5))).unwrap();
+
+        partition.process(dispute(1, 1)).unwrap();
+        partition.process(dispute(2, 1)).unwrap();
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(10));
+        assert_eq!(account(&mut partition, 2).get_held_funds(), &dec!(5));
+
+        partition.process(dispute(3, 1)).unwrap();
+        assert_eq!(account(&mut partition, 3).get_held_funds(), &dec!(0));
+    }
+
+    #[test]
+    fn guarding_held_funds() {
+        let mut partition = new_partition(Config {
+            guard_held: true,
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(10))).unwrap();
+        // A duplicate dispute doesn't hold the funds twice.
+        partition.process(dispute(1, 1)).unwrap();
+        assert_eq!(
+            partition.process(dispute(1, 1)),
+            Err(Rejection::AlreadyDisputed)
+        );
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(10));
+
+        let mut partition = new_partition(Config {
+            guard_held: true,
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, dec!(10))).unwrap();
+        partition.process(withdrawal(1, 2, dec!(8))).unwrap();
+        assert_eq!(
+            partition.process(dispute(1, 1)),
+            Err(Rejection::HeldExceedsDeposits)
+        );
+        assert_eq!(account(&mut partition, 1).get_held_funds(), &dec!(0));
+    }
+
+    #[test]
+    fn arithmetic_overflow() {
+        let mut partition = new_partition(Config::default());
+        partition.process(deposit(1, 1, Decimal::MAX)).unwrap();
+        assert_eq!(
+            partition.process(deposit(1, 2, dec!(1))),
+            Err(Rejection::Overflow)
+        );
+        assert_eq!(available(&mut partition, 1), Decimal::MAX);
+
+        let mut partition = new_partition(Config {
+            arithmetic_mode: ArithmeticMode::Saturating,
+            ..Config::default()
+        });
+        partition.process(deposit(1, 1, Decimal::MAX)).unwrap();
+        assert_eq!(partition.process(deposit(1, 2, dec!(1))), Ok(()));
+        assert_eq!(available(&mut partition, 1), Decimal::MAX);
+        partition.process(withdrawal(1, 3, dec!(1))).unwrap();
+        assert_eq!(available(&mut partition, 1), Decimal::MAX - dec!(1));
+    }
+
+    #[test]
+    fn merging_sorted_outputs() {
+        let output = |ids: &[u16]| -> Output {
+            ids.iter()
+                .map(|id| Record::new(Account::new(), ClientId::new(*id)))
+                .collect()
+        };
+        let merged = merge_sorted(vec![output(&[2, 5, 6]), output(&[]), output(&[1, 3, 7, 9])]);
+        let ids: Vec<_> = merged.iter().map(|r| r.id.value()).collect();
+        assert_eq!(ids, vec![1, 2, 3, 5, 6, 7, 9]);
+    }
+
+    /// Account store failing on access to the given client.
+    struct FailingStore {
+        accounts: HashMap<ClientId, Account>,
+        failing_client: ClientId,
+    }
+
+    impl AccountStore for FailingStore {
+        fn get_or_create(&mut self, id: ClientId) -> &mut Account {
+            assert!(id != self.failing_client, "Injected failure");
+            self.accounts.get_or_create(id)
+        }
+
+        fn get(&self, id: ClientId) -> Option<&Account> {
+            self.accounts.get(&id)
+        }
+
+        fn remove(&mut self, id: ClientId) -> Option<Account> {
+            self.accounts.remove(&id)
         }
 
-        // TODO: it should probably be possible to do it simpler
-        while self.workers.len() != 0 {
-            let worker = self.workers.pop().unwrap();
-            worker.handle.join().unwrap();
+        fn snapshot(&self) -> Vec<(ClientId, Account)> {
+            self.accounts.snapshot()
         }
 
-        let mut output = Output::new();
-        for _ in 0..n_workers {
-            for acc in self.receiver.recv().unwrap().into_iter() {
-                output.push(acc);
+        fn drain(self: Box<Self>) -> Vec<(ClientId, Account)> {
+            Box::new(self.accounts).drain()
+        }
+    }
+
+    /// Store panicking on the `failing_call`-th access to the `failing_client`.
+    struct FlakyStore {
+        accounts: HashMap<ClientId, Account>,
+        failing_client: ClientId,
+        failing_call: usize,
+        calls: usize,
+    }
+
+    impl AccountStore for FlakyStore {
+        fn get_or_create(&mut self, id: ClientId) -> &mut Account {
+            if id == self.failing_client {
+                self.calls += 1;
+                assert!(self.calls != self.failing_call, "Injected failure");
             }
+            self.accounts.get_or_create(id)
         }
 
-        output
+        fn get(&self, id: ClientId) -> Option<&Account> {
+            self.accounts.get(&id)
+        }
+
+        fn remove(&mut self, id: ClientId) -> Option<Account> {
+            self.accounts.remove(&id)
+        }
+
+        fn snapshot(&self) -> Vec<(ClientId, Account)> {
+            self.accounts.snapshot()
+        }
+
+        fn drain(self: Box<Self>) -> Vec<(ClientId, Account)> {
+            Box::new(self.accounts).drain()
+        }
+    }
+
+    #[test]
+    fn processing_grouped_input() {
+        let transactions: Vec<_> = (0..1_000)
+            .map(|tx| match tx % 3 {
+                2 => withdrawal((tx % 10) as u16, tx, dec!(1)),
+                _ => deposit((tx % 10) as u16, tx, dec!(2)),
+            })
+            .collect();
+        let mut grouped = transactions.clone();
+        grouped.sort_by_key(|tr| tr.meta().client_id);
+
+        let mut processor = Processor::spawn(4);
+        for tr in transactions {
+            processor.process(tr);
+        }
+        let expected = processor.wait();
+        let expected_metrics = processor.metrics().clone();
+
+        let mut processor = Processor::spawn(4);
+        for tr in grouped {
+            processor.process_grouped(tr);
+        }
+        let accounts = processor.wait();
+
+        assert_eq!(accounts.len(), 10);
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|r| r.item.to_proto(&r.id))
+                .collect::<Vec<_>>(),
+            expected
+                .iter()
+                .map(|r| r.item.to_proto(&r.id))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(processor.metrics(), &expected_metrics);
+    }
+
+    #[test]
+    fn querying_mid_stream() {
+        let mut processor = Processor::spawn(3);
+        processor.process(deposit(1, 1, dec!(5)));
+        processor.process(deposit(2, 2, dec!(3)));
+        processor.process(withdrawal(1, 3, dec!(2)));
+
+        let snapshot = processor.query(ClientId::new(1)).unwrap();
+        assert_eq!(snapshot.get_available_funds(), &dec!(3));
+        assert!(processor.query(ClientId::new(3)).is_none());
+
+        processor.process(deposit(1, 4, dec!(10)));
+        assert_eq!(snapshot.get_available_funds(), &dec!(3));
+        assert_eq!(
+            processor
+                .query(ClientId::new(1))
+                .unwrap()
+                .get_available_funds(),
+            &dec!(13)
+        );
+
+        let accounts = processor.wait();
+        assert_eq!(accounts.len(), 2);
+    }
+
+    #[test]
+    fn waiting_for_shards() {
+        let mut processor = Processor::spawn(3);
+        for tx in 0..100 {
+            processor.process(deposit(tx as u16 % 20, tx, dec!(1)));
+        }
+        let shards = processor.wait_shards();
+        assert_eq!(shards.len(), 3);
+
+        let mut clients = HashSet::new();
+        for shard in &shards {
+            assert!(shard.windows(2).all(|pair| pair[0].id < pair[1].id));
+            for r in shard {
+                assert!(clients.insert(r.id), "client {} in several shards", r.id);
+            }
+        }
+        assert_eq!(clients.len(), 20);
+        assert_eq!(processor.metrics().processed, 100);
+    }
+
+    #[test]
+    fn streaming_events() {
+        const COUNT: u32 = 10_000;
+        let config = Config {
+            emit_events: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(4, config);
+        let events = processor.take_events().unwrap();
+        let consumer = thread::spawn(move || events.iter().count());
+        for tx in 0..COUNT {
+            processor.process(deposit((tx % 64) as u16, tx, dec!(1)));
+        }
+        processor.process(dispute(1, COUNT));
+        processor.wait();
+
+        // More events than buffered, and none for the dispute of an unknown transaction.
+        assert_eq!(consumer.join().unwrap(), COUNT as usize);
+        assert_eq!(processor.metrics().ignored, 1);
+    }
+
+    #[test]
+    fn dropping_dead_letters() {
+        let config = Config {
+            emit_dead_letters: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(1, config);
+        drop(processor.take_dead_letters());
+        processor.process(deposit(1, 1, dec!(4)));
+        processor.process(Transaction::Freeze { meta: meta(1, 2) });
+        processor.process(deposit(1, 3, dec!(1)));
+        let accounts = processor.wait();
+
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(processor.metrics().failed_partitions, 0);
+        assert_eq!(processor.metrics().rejected, 1);
+    }
+
+    #[test]
+    fn discarding_events_not_taken() {
+        let config = Config {
+            emit_events: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(1, config);
+        let count = 2 * EVENT_BOUND as u32;
+        for tx in 0..count {
+            processor.process(deposit(1, tx, dec!(1)));
+        }
+        let accounts = processor.wait();
+
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(processor.metrics().processed, count as u64);
+    }
+
+    #[test]
+    fn draining_queues_at_shutdown() {
+        const COUNT: u32 = 100_000;
+        for channel_bound in [None, Some(1)] {
+            let config = Config {
+                channel_bound,
+                ..Config::default()
+            };
+            let mut processor = Processor::spawn_with_config(4, config);
+            for tx in 0..COUNT {
+                processor.process(deposit((tx % 64) as u16, tx, dec!(1)));
+            }
+            let accounts = processor.wait();
+
+            assert_eq!(processor.metrics().processed, COUNT as u64);
+            let total: Decimal = accounts.iter().map(|r| r.item.get_available_funds()).sum();
+            assert_eq!(total, Decimal::from(COUNT));
+        }
+    }
+
+    #[test]
+    fn collecting_events() {
+        let config = Config {
+            emit_events: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(3, config);
+        let events = processor.take_events().unwrap();
+        processor.process(deposit(1, 1, dec!(5)));
+        processor.process(deposit(2, 2, dec!(3)));
+        processor.process(withdrawal(2, 3, dec!(4)));
+        processor.process(withdrawal(1, 4, dec!(1.5)));
+        processor.process(deposit(3, 5, dec!(2)));
+        processor.wait();
+
+        let mut events: Vec<_> = events.iter().map(|e| e.to_proto()).collect();
+        events.sort_by_key(|e| e.sequence);
+        let records: Vec<_> = events
+            .iter()
+            .map(|e| {
+                let (client, tx, kind) = (e.client_id, e.transaction_id, &*e.kind);
+                (e.sequence, client, tx, kind, e.amount, e.available_funds)
+            })
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (0, 1, 1, "deposit", Some(dec!(5)), dec!(5)),
+                (1, 2, 2, "deposit", Some(dec!(3)), dec!(3)),
+                (3, 1, 4, "withdrawal", Some(dec!(1.5)), dec!(3.5)),
+                (4, 3, 5, "deposit", Some(dec!(2)), dec!(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn ordering_events_of_single_worker() {
+        let config = Config {
+            emit_events: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(1, config);
+        let events = processor.take_events().unwrap();
+        for tx in 0..100 {
+            processor.process(deposit(tx as u16 % 7, tx, dec!(1)));
+        }
+        processor.wait();
+
+        let sequences: Vec<_> = events.iter().map(|e| e.sequence).collect();
+        assert_eq!(sequences, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn tracing_a_client() {
+        let config = Config {
+            emit_events: true,
+            event_client: Some(ClientId::new(1)),
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(2, config);
+        let events = processor.take_events().unwrap();
+        processor.process(deposit(1, 1, dec!(5)));
+        processor.process(deposit(2, 2, dec!(3)));
+        processor.process(deposit(1, 3, dec!(2)));
+        processor.process(dispute(1, 1));
+        processor.process(Transaction::Chargeback {
+            meta: meta(1, 1),
+            amount: None,
+        });
+        processor.wait();
+
+        let mut events: Vec<_> = events.iter().collect();
+        events.sort_by_key(|e| e.sequence);
+        let trace: Vec<_> = events.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            trace,
+            vec![
+                "#0 deposit tx 1 of 5: available 5, held 0",
+                "#2 deposit tx 3 of 2: available 7, held 0",
+                "#3 dispute tx 1: available 2, held 5",
+                "#4 chargeback tx 1: available 2, held 0, locked",
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshotting_mid_stream() {
+        let mut processor = Processor::spawn(3);
+        processor.process(deposit(1, 1, dec!(5)));
+        processor.process(deposit(2, 2, dec!(3)));
+        processor.process(withdrawal(1, 3, dec!(2)));
+
+        let snapshot = processor.snapshot();
+        let balances: Vec<_> = snapshot
+            .iter()
+            .map(|r| (r.id.value(), *r.item.get_available_funds()))
+            .collect();
+        assert_eq!(balances, vec![(1, dec!(3)), (2, dec!(3))]);
+
+        processor.process(deposit(3, 4, dec!(1)));
+        assert_eq!(processor.snapshot().len(), 3);
+        assert_eq!(processor.wait().len(), 3);
+    }
+
+    #[test]
+    fn flushing_completed_clients() {
+        let mut processor = Processor::spawn(2);
+        processor.process_grouped(deposit(2, 1, dec!(5)));
+        processor.process_grouped(deposit(1, 2, dec!(3)));
+        processor.process_grouped(deposit(1, 3, dec!(1)));
+        assert_eq!(processor.completed_clients(), 1);
+        processor.process_grouped(deposit(3, 4, dec!(2)));
+        assert_eq!(processor.completed_clients(), 2);
+
+        let flushed = processor.flush_completed();
+        let ids: Vec<_> = flushed.iter().map(|r| r.id.value()).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(flushed[0].item.get_available_funds(), &dec!(4));
+        assert_eq!(processor.completed_clients(), 0);
+        assert!(processor.query(ClientId::new(1)).is_none());
+        assert!(processor.flush_completed().is_empty());
+
+        // An evicted client starts from scratch, its history forgotten.
+        processor.process_grouped(dispute(2, 1));
+        let accounts = processor.wait();
+        let ids: Vec<_> = accounts.iter().map(|r| r.id.value()).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(accounts[0].item.get_held_funds(), &dec!(0));
+    }
+
+    #[test]
+    fn detecting_duplicate_clients() {
+        // The three runs go to the three workers in turn.
+        let mut processor = Processor::spawn(3);
+        for (tx, client_id) in (0..).zip([1, 2, 1]) {
+            processor.process_grouped(deposit(client_id, tx, dec!(1)));
+        }
+        let accounts = processor.wait();
+
+        let ids: Vec<_> = accounts.iter().map(|r| r.id.value()).collect();
+        assert_eq!(ids, vec![1, 1, 2]);
+        assert_eq!(find_duplicate(&accounts), Some(ClientId::new(1)));
+        assert_eq!(find_duplicate(&accounts[1..]), None);
+    }
+
+    #[test]
+    fn detecting_hot_clients() {
+        let config = Config {
+            hot_client_percent: Some(50),
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(4, config);
+        for tx in 0..100 {
+            let client_id = if tx < 60 { 1 } else { 2 + tx as u16 % 4 };
+            processor.process(deposit(client_id, tx, dec!(1)));
+        }
+        processor.wait();
+
+        assert_eq!(
+            processor.metrics().hot_clients,
+            vec![(ClientId::new(1), 60)]
+        );
+    }
+
+    #[test]
+    fn amount_histogram() {
+        let config = Config {
+            amount_histogram: Some(Histogram::log_scale(dec!(0.01), 3)),
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(4, config);
+        let amounts = [
+            dec!(0.001),
+            dec!(0.01),
+            dec!(0.5),
+            dec!(1),
+            dec!(2),
+            dec!(1000),
+        ];
+        for (tx, amount) in (0..).zip(amounts) {
+            processor.process(deposit(tx as u16, tx, amount));
+        }
+        processor.process(withdrawal(5, 6, dec!(50)));
+        // Rejected for insufficient funds, not counted.
+        processor.process(withdrawal(0, 7, dec!(50)));
+        processor.wait();
+
+        let histogram = processor.metrics().amount_histogram.as_ref().unwrap();
+        assert_eq!(histogram.bounds, vec![dec!(0.01), dec!(0.1), dec!(1)]);
+        assert_eq!(histogram.counts, vec![2, 0, 2, 3]);
+        assert_eq!(
+            histogram.to_string(),
+            indoc! {"
+                <= 0.01           2
+                <= 0.1            0
+                <= 1              2
+                > 1               3
+            "}
+        );
+    }
+
+    /// Store recording the clients it has been asked for along with its partition index.
+    struct TracingStore {
+        index: usize,
+        trace: Arc<std::sync::Mutex<Vec<(usize, ClientId)>>>,
+        accounts: HashMap<ClientId, Account>,
+    }
+
+    impl AccountStore for TracingStore {
+        fn get_or_create(&mut self, id: ClientId) -> &mut Account {
+            self.trace.lock().unwrap().push((self.index, id));
+            self.accounts.get_or_create(id)
+        }
+
+        fn get(&self, id: ClientId) -> Option<&Account> {
+            self.accounts.get(&id)
+        }
+
+        fn remove(&mut self, id: ClientId) -> Option<Account> {
+            self.accounts.remove(&id)
+        }
+
+        fn snapshot(&self) -> Vec<(ClientId, Account)> {
+            self.accounts.snapshot()
+        }
+
+        fn drain(self: Box<Self>) -> Vec<(ClientId, Account)> {
+            Box::new(self.accounts).drain()
+        }
+    }
+
+    #[test]
+    fn partition_of_client() {
+        let trace = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let next_index = AtomicUsize::new(0);
+        let mut processor = Processor::spawn_with_stores(4, Config::default(), || {
+            Box::new(TracingStore {
+                index: next_index.fetch_add(1, Ordering::Relaxed),
+                trace: Arc::clone(&trace),
+                accounts: HashMap::new(),
+            })
+        });
+        assert_eq!(processor.num_partitions(), 4);
+
+        for client_id in 0..100 {
+            processor.process(deposit(client_id, client_id as u32, dec!(1)));
+        }
+        let expected: Vec<_> = (0..100)
+            .map(|client_id| {
+                let client_id = ClientId::new(client_id);
+                (processor.partition_of(client_id), client_id)
+            })
+            .collect();
+        processor.wait();
+
+        let mut trace = trace.lock().unwrap().clone();
+        trace.sort_unstable_by_key(|(_, client_id)| *client_id);
+        assert_eq!(trace, expected);
+        assert_eq!(processor.num_partitions(), 0);
+    }
+
+    #[test]
+    fn recovering_from_worker_panic() {
+        let config = Config {
+            recover: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_stores(4, config, || {
+            Box::new(FailingStore {
+                accounts: HashMap::new(),
+                failing_client: ClientId::new(13),
+            })
+        });
+
+        for client_id in 1..=20 {
+            processor.process(deposit(client_id, client_id as u32, dec!(1)));
+        }
+        let accounts = processor.wait();
+
+        assert_eq!(processor.metrics().failed_partitions, 1);
+        assert!(!accounts.is_empty() && accounts.len() < 20);
+        assert!(accounts.iter().all(|r| r.id != ClientId::new(13)));
+        assert_eq!(processor.metrics().processed, accounts.len() as u64);
+    }
+
+    #[test]
+    fn continuing_on_panic() {
+        let config = Config {
+            continue_on_panic: true,
+            emit_events: true,
+            dedup_fingerprints: true,
+            ..Config::default()
+        };
+        // With events, an applied transaction accesses the account twice, so
+        // the second deposit panics once fully applied and must be rolled back.
+        let mut processor = Processor::spawn_with_stores(1, config, || {
+            Box::new(FlakyStore {
+                accounts: HashMap::new(),
+                failing_client: ClientId::new(1),
+                failing_call: 4,
+                calls: 0,
+            })
+        });
+        let events = processor.take_events().unwrap();
+        processor.process(deposit(1, 1, dec!(5)));
+        processor.process(deposit(1, 2, dec!(3)));
+        processor.process(deposit(2, 3, dec!(2)));
+        // The retry is no duplicate, as the panicked deposit left no trace.
+        processor.process(deposit(1, 2, dec!(3)));
+        let accounts = processor.wait();
+
+        let balances: Vec<_> = accounts
+            .iter()
+            .map(|r| (r.id, *r.item.get_available_funds()))
+            .collect();
+        assert_eq!(
+            balances,
+            vec![(ClientId::new(1), dec!(8)), (ClientId::new(2), dec!(2))]
+        );
+        assert_eq!(processor.metrics().processed, 3);
+        assert_eq!(processor.metrics().rejected, 1);
+        assert_eq!(processor.metrics().failed_partitions, 0);
+        let sequences: Vec<_> = events.iter().map(|e| e.sequence).collect();
+        assert_eq!(sequences, vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn rolling_back_new_account() {
+        let config = Config {
+            continue_on_panic: true,
+            emit_events: true,
+            ..Config::default()
+        };
+        // The first deposit of client 3 panics once applied, so the account
+        // it opened must be removed again.
+        let mut processor = Processor::spawn_with_stores(1, config, || {
+            Box::new(FlakyStore {
+                accounts: HashMap::new(),
+                failing_client: ClientId::new(3),
+                failing_call: 2,
+                calls: 0,
+            })
+        });
+        let _events = processor.take_events().unwrap();
+        processor.process(deposit(3, 1, dec!(5)));
+        processor.process(deposit(1, 2, dec!(1)));
+        let accounts = processor.wait();
+
+        let ids: Vec<_> = accounts.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![ClientId::new(1)]);
+        assert_eq!(processor.metrics().processed, 1);
+        assert_eq!(processor.metrics().rejected, 1);
+    }
+
+    #[test]
+    fn formatting_prometheus_metrics() {
+        let mut processor = Processor::spawn(2);
+        processor.process(deposit(1, 1, dec!(5)));
+        processor.process(dispute(1, 1));
+        processor.process(withdrawal(2, 2, dec!(1)));
+        processor.wait();
+
+        let mut metrics = processor.metrics().clone();
+        metrics.duration = Duration::from_millis(1500);
+        assert_eq!(
+            metrics.to_prometheus(),
+            indoc! {"
+                # HELP transactor_transactions_processed_total Transactions applied.
+                # TYPE transactor_transactions_processed_total counter
+                transactor_transactions_processed_total 2
+                # HELP transactor_transactions_rejected_total Transactions rejected.
+                # TYPE transactor_transactions_rejected_total counter
+                transactor_transactions_rejected_total 1
+                # HELP transactor_transactions_duplicate_total Transactions rejected as identical to a previous one.
+                # TYPE transactor_transactions_duplicate_total counter
+                transactor_transactions_duplicate_total 0
+                # HELP transactor_transactions_ignored_total Transactions accepted but leaving their account intact.
+                # TYPE transactor_transactions_ignored_total counter
+                transactor_transactions_ignored_total 0
+                # HELP transactor_disputes_total Disputes applied.
+                # TYPE transactor_disputes_total counter
+                transactor_disputes_total 1
+                # HELP transactor_failed_partitions_total Partitions dropped due to a worker panic.
+                # TYPE transactor_failed_partitions_total counter
+                transactor_failed_partitions_total 0
+                # HELP transactor_frozen_accounts Output accounts frozen.
+                # TYPE transactor_frozen_accounts gauge
+                transactor_frozen_accounts 0
+                # HELP transactor_run_duration_seconds Wall time of the run.
+                # TYPE transactor_run_duration_seconds gauge
+                transactor_run_duration_seconds 1.5
+            "}
+        );
+    }
+
+    #[test]
+    fn bounded_channel_shutdown() {
+        let config = Config {
+            channel_bound: Some(1),
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(4, config);
+        for tx in 0..10_000 {
+            processor.process(deposit((tx % 10) as u16, tx, dec!(1)));
+        }
+        let accounts = processor.wait();
+
+        assert_eq!(accounts.len(), 10);
+        assert!(accounts
+            .iter()
+            .all(|r| r.item.get_available_funds() == &dec!(1000)));
+        assert_eq!(processor.metrics().processed, 10_000);
+    }
+
+    #[test]
+    fn throttled_submission() {
+        let config = Config {
+            throttle_depth: Some(2),
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_config(2, config);
+        for tx in 0..10_000 {
+            processor.process(deposit((tx % 10) as u16, tx, dec!(1)));
+            let depth = processor.queue_lengths().into_iter().max().unwrap();
+            assert!(depth <= 2, "{} commands queued", depth);
+        }
+        processor.wait();
+        assert_eq!(processor.metrics().processed, 10_000);
+
+        // A failed worker never drains its queue, submission goes on regardless.
+        let config = Config {
+            throttle_depth: Some(2),
+            recover: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_stores(2, config, || {
+            Box::new(FailingStore {
+                accounts: HashMap::new(),
+                failing_client: ClientId::new(0),
+            })
+        });
+        for tx in 0..10_000 {
+            processor.process(deposit((tx % 10) as u16, tx, dec!(1)));
+        }
+        assert_eq!(processor.queue_lengths().len(), 2);
+        let accounts = processor.wait();
+
+        assert_eq!(processor.metrics().failed_partitions, 1);
+        assert!(accounts
+            .iter()
+            .all(|r| r.item.get_available_funds() == &dec!(1000)));
+    }
+
+    #[test]
+    fn bounded_channel_shutdown_after_worker_panic() {
+        let config = Config {
+            channel_bound: Some(1),
+            recover: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::spawn_with_stores(2, config, || {
+            Box::new(FailingStore {
+                accounts: HashMap::new(),
+                failing_client: ClientId::new(0),
+            })
+        });
+        for tx in 0..10_000 {
+            processor.process(deposit((tx % 10) as u16, tx, dec!(1)));
+        }
+        let accounts = processor.wait();
+
+        assert_eq!(processor.metrics().failed_partitions, 1);
+        assert!(accounts.iter().all(|r| r.id != ClientId::new(0)));
     }
 }
@@ -1,66 +1,705 @@
+//! Module defines proto models for IO purposes. The models defined
+//! are to be used for reading/writing data from external sources.
+//! They should not be used for processing directly but can be
+//! converted to/from models from `models` module.
+
 use crate::models;
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
 use std::iter::Iterator;
+use std::str::FromStr;
+
+/// Transaction types known to the parser.
+const KNOWN_TYPES: [&str; 8] = [
+    "deposit",
+    "withdrawal",
+    "correct",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "freeze",
+    "unfreeze",
+];
+
+/// Currency symbols tolerated in front of amounts (see `ParseOptions::tolerant_amounts`).
+const CURRENCY_SYMBOLS: [char; 4] = ['$', '€', '£', '¥'];
+
+/// Header columns of transaction inputs.
+const EXPECTED_HEADERS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Header columns transaction inputs may omit.
+const OPTIONAL_HEADERS: [&str; 2] = ["original", "timestamp"];
+
+/// Number of decimal places of output amounts.
+pub const OUTPUT_DECIMALS: u32 = 4;
+
+/// Options controlling reading of raw records and their conversion into `models`.
+///
+/// * `preserve_scale` - keep amounts at the scale they were given in the input
+///   (e.g. `4.00`) rather than normalizing them (e.g. `4`).
+/// * `strict_headers` - fail reading on header issues instead of warning about them.
+/// * `grouping` - if set, amounts may group thousands in the given style
+///   (e.g. `"1,000.50"`), the separators are stripped before parsing.
+/// * `tolerant_amounts` - strip a leading currency symbol (`$`, `€`, `£` or `¥`)
+///   and a trailing three-letter currency code off amounts (e.g. `$4.00` or
+///   `4.00 USD`) before parsing. Anything else around the number is invalid still.
+/// * `window` - if set, only transactions timestamped within the window are
+///   read (see `TimeWindow`).
+/// * `keep_rows` - keep the input row of every record, so errors are reported
+///   with their row as `ParseError::InRow`.
+/// * `zero_amounts` - handling of deposits and withdrawals of zero amount.
+/// * `negative_deposits` - accept deposits of negative amount, e.g. reversals,
+///   debiting the account (see `models::Transaction`) instead of rejecting them.
+/// * `amount_scale` - amounts are divided by ten to the given power, at most 28,
+///   when converted into `models`, e.g. 2 for feeds of integer cents reading
+///   `400` as `4.00`. Amounts with a fraction are then rejected as
+///   `ParseError::FractionalAmount`. No scaling by default.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    pub preserve_scale: bool,
+    pub strict_headers: bool,
+    pub grouping: Option<Grouping>,
+    pub tolerant_amounts: bool,
+    pub window: Option<TimeWindow>,
+    pub keep_rows: bool,
+    pub zero_amounts: ZeroAmountPolicy,
+    pub negative_deposits: bool,
+    pub amount_scale: u32,
+}
+
+impl ParseOptions {
+    /// Whether raw amounts need rewriting before parsing.
+    fn rewrites_amounts(&self) -> bool {
+        self.grouping.is_some() || self.tolerant_amounts
+    }
+
+    /// Rewrites the `raw` amount into a form parsed by `Decimal`, validating it parses.
+    fn rewrite_amount(&self, raw: &str) -> Result<String, ParseError> {
+        check_finite(raw)?;
+        let mut amount = raw.trim();
+        if self.tolerant_amounts {
+            amount = amount.strip_prefix(CURRENCY_SYMBOLS).unwrap_or(amount);
+            if let Some((number, code)) = amount.rsplit_once(' ') {
+                if code.len() == 3 && code.bytes().all(|b| b.is_ascii_uppercase()) {
+                    amount = number;
+                }
+            }
+            amount = amount.trim();
+        }
+        let amount = match self.grouping {
+            Some(grouping) => grouping.ungroup(amount),
+            None => amount.to_string(),
+        };
+
+        if !amount.is_empty() && Decimal::from_str(&amount).is_err() {
+            return Err(ParseError::InvalidAmount {
+                raw: raw.to_string(),
+            });
+        }
+        Ok(amount)
+    }
+}
+
+/// Fails with `ParseError::InvalidAmount` if the `raw` amount is a token of
+/// a number `Decimal` has no notion of, e.g. `inf`, `NaN` or `1/0`, rather than
+/// leaving it to a generic error of deserialization.
+fn check_finite(raw: &str) -> Result<(), ParseError> {
+    let token = raw
+        .trim()
+        .trim_start_matches(['+', '-'])
+        .to_ascii_lowercase();
+    match matches!(token.as_str(), "inf" | "infinity" | "nan") || token.contains('/') {
+        true => Err(ParseError::InvalidAmount {
+            raw: raw.to_string(),
+        }),
+        false => Ok(()),
+    }
+}
+
+/// Window of transaction timestamps, in seconds since the Unix epoch.
+///
+/// * `since` - if set, the earliest timestamp admitted.
+/// * `until` - if set, the timestamp admitted no more, so consecutive windows
+///   (e.g. months) don't overlap.
+///
+/// The window filters which transactions are applied, not which ones are known:
+/// a dispute, resolve, chargeback or correction within the window referencing
+/// a transaction outside it finds no transaction to act on and is warned about,
+/// as long as the transaction is among the latest left out (see `LEFT_OUT_MEMORY`).
+/// Transactions without a timestamp are always admitted.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct TimeWindow {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+impl TimeWindow {
+    /// Whether the `timestamp` falls within the window.
+    pub fn contains(&self, timestamp: u64) -> bool {
+        self.since.is_none_or(|since| since <= timestamp)
+            && self.until.is_none_or(|until| timestamp < until)
+    }
+
+    /// Whether the `record` is admitted, remembering the deposits and withdrawals
+    /// left out in `outside` to warn about references to them.
+    fn admits(&self, record: &Transaction, outside: &mut LeftOut) -> bool {
+        let Some(timestamp) = record.timestamp else {
+            return true;
+        };
+
+        if !self.contains(timestamp) {
+            if matches!(&*record.kind, "deposit" | "withdrawal") {
+                outside.insert((record.client_id, record.transaction_id));
+            }
+            return false;
+        }
+        if let Some(warning) = TimeWindow::reference_warning(record, outside) {
+            eprintln!("Warning: {}", warning);
+        }
+        true
+    }
+
+    /// Returns a warning if the admitted `record` references a transaction
+    /// left out (see `admits`).
+    fn reference_warning(record: &Transaction, outside: &LeftOut) -> Option<String> {
+        let referenced = match &*record.kind {
+            "dispute" | "resolve" | "chargeback" => Some(record.transaction_id),
+            "correct" => record.original_transaction_id,
+            _ => None,
+        };
+        let transaction_id = referenced.filter(|id| outside.contains((record.client_id, *id)))?;
+        Some(format!(
+            "{} of client {} references tx {} outside the time window",
+            record.kind, record.client_id, transaction_id
+        ))
+    }
+}
+
+/// Number of the latest deposits and withdrawals left out by a `TimeWindow`
+/// remembered to warn about references to them. References to earlier ones
+/// are not warned about, so memory stays bounded however long the input.
+const LEFT_OUT_MEMORY: usize = 100_000;
+
+/// Keys of the latest deposits and withdrawals left out by a `TimeWindow`
+/// (see `LEFT_OUT_MEMORY`).
+#[derive(Debug, Default)]
+struct LeftOut {
+    keys: HashSet<(u64, u64)>,
+    order: VecDeque<(u64, u64)>,
+}
+
+impl LeftOut {
+    fn insert(&mut self, key: (u64, u64)) {
+        if !self.keys.insert(key) {
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > LEFT_OUT_MEMORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+    }
 
-/// Module defines proto models for IO purposes. The models defined
-/// are to be used for reading/writing data from external sources.
-/// They should not be used for processing directly but can be
-/// converted to/from models from `models` module.
+    fn contains(&self, key: (u64, u64)) -> bool {
+        self.keys.contains(&key)
+    }
+}
+
+/// Policy of handling deposits and withdrawals of zero amount.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ZeroAmountPolicy {
+    /// Rejected as invalid with `ParseError::NonpositiveAmount`.
+    #[default]
+    Reject,
+    /// Accepted as no-ops leaving the balances unchanged, e.g. for pings or
+    /// adjustments sent by an upstream. Processing ignores them, charging no fees
+    /// (see `crate::processing::Outcome::Ignored`), though the account of
+    /// a new client is opened still.
+    Accept,
+}
+
+/// Style of amounts with grouped thousands.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Grouping {
+    /// Thousands separated by `,` and decimals by `.`, e.g. `1,000.50`.
+    Us,
+    /// Thousands separated by `.` and decimals by `,`, e.g. `1.000,50`.
+    Eu,
+}
+
+impl Grouping {
+    /// Strips the thousands separators off the `raw` amount, leaving `.` as the decimal point.
+    pub fn ungroup(self, raw: &str) -> String {
+        match self {
+            Grouping::Us => raw.replace(',', ""),
+            Grouping::Eu => raw.replace('.', "").replace(',', "."),
+        }
+    }
+}
+
+/// Structural problem of an input header row.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HeaderIssue {
+    Unexpected(String),
+    Duplicate(String),
+    Missing(&'static str),
+}
+
+impl fmt::Display for HeaderIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderIssue::Unexpected(name) => write!(f, "unexpected column '{}'", name),
+            HeaderIssue::Duplicate(name) => write!(f, "duplicate column '{}'", name),
+            HeaderIssue::Missing(name) => write!(f, "missing column '{}'", name),
+        }
+    }
+}
+
+/// Validates the header row of the `reader` against the expected transaction columns.
+pub fn header_issues<T: std::io::Read>(
+    reader: &mut csv::Reader<T>,
+) -> Result<Vec<HeaderIssue>, csv::Error> {
+    let headers = reader.headers()?;
+    if headers.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut issues = vec![];
+    for (i, name) in headers.iter().enumerate() {
+        if !EXPECTED_HEADERS.contains(&name) && !OPTIONAL_HEADERS.contains(&name) {
+            issues.push(HeaderIssue::Unexpected(name.to_string()));
+        } else if headers.iter().take(i).any(|other| other == name) {
+            issues.push(HeaderIssue::Duplicate(name.to_string()));
+        }
+    }
+    for name in EXPECTED_HEADERS {
+        if !headers.iter().any(|other| other == name) {
+            issues.push(HeaderIssue::Missing(name));
+        }
+    }
+
+    Ok(issues)
+}
 
 /// Transaction model for IO use.
-#[derive(Deserialize, Debug)]
+///
+/// Ids are read wider than their model types to report out of range values
+/// distinctly (see `ParseError::ClientIdOutOfRange`).
+///
+/// The `original` column is optional, it is only used by corrections to
+/// reference the corrected transaction. So is the `timestamp` column,
+/// in seconds since the Unix epoch (see `TimeWindow`).
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Transaction {
+    /// Type of the transaction, borrowed if it is a known one.
     #[serde(rename = "type")]
-    pub kind: String,
+    pub kind: Cow<'static, str>,
     #[serde(rename = "client")]
-    pub client_id: u16,
+    pub client_id: u64,
     #[serde(rename = "tx")]
-    pub transaction_id: u32,
+    pub transaction_id: u64,
+    #[serde(default, deserialize_with = "deserialize_amount")]
     pub amount: Option<Decimal>,
+    #[serde(rename = "original", default, skip_serializing_if = "Option::is_none")]
+    pub original_transaction_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Input row of the record if kept (see `ParseOptions::keep_rows`).
+    #[serde(skip)]
+    pub row: Option<Row>,
+}
+
+/// Indexes of the transaction columns in byte records (see `Transaction::read_byte_records`).
+struct ByteColumns {
+    kind: Option<usize>,
+    client_id: Option<usize>,
+    transaction_id: Option<usize>,
+    amount: Option<usize>,
+    original_transaction_id: Option<usize>,
+    timestamp: Option<usize>,
+}
+
+impl ByteColumns {
+    /// Locates the columns in the `headers`, `None` if any of them repeats.
+    fn of(headers: &csv::ByteRecord) -> Option<ByteColumns> {
+        let position = |name: &str| -> Result<Option<usize>, ()> {
+            let mut positions = headers
+                .iter()
+                .enumerate()
+                .filter(|(_, h)| *h == name.as_bytes());
+            match (positions.next(), positions.next()) {
+                (_, Some(_)) => Err(()),
+                (position, None) => Ok(position.map(|(i, _)| i)),
+            }
+        };
+        Some(ByteColumns {
+            kind: position("type").ok()?,
+            client_id: position("client").ok()?,
+            transaction_id: position("tx").ok()?,
+            amount: position("amount").ok()?,
+            original_transaction_id: position("original").ok()?,
+            timestamp: position("timestamp").ok()?,
+        })
+    }
+
+    /// Parses the `record`, `None` if it is invalid in any way.
+    fn parse(&self, record: &csv::ByteRecord) -> Option<Transaction> {
+        let text = |column: Option<usize>| -> Option<Option<&str>> {
+            match column.map(|i| record.get(i)) {
+                Some(Some(field)) => std::str::from_utf8(field).ok().map(Some),
+                Some(None) => None,
+                None => Some(None),
+            }
+        };
+        let id = |column: Option<usize>| -> Option<u64> { text(column)??.parse().ok() };
+        let optional_id = |column: Option<usize>| -> Option<Option<u64>> {
+            match text(column)? {
+                Some("") | None => Some(None),
+                Some(raw) => raw.parse().ok().map(Some),
+            }
+        };
+
+        let amount = match text(self.amount)? {
+            Some(raw) if !raw.trim().is_empty() => Some(Decimal::from_str(raw.trim()).ok()?),
+            _ => None,
+        };
+        // Known types are compared as bytes to not allocate them for every record.
+        let kind = record.get(self.kind?)?;
+        let kind = match KNOWN_TYPES.iter().find(|known| known.as_bytes() == kind) {
+            Some(known) => Cow::Borrowed(*known),
+            None => Cow::Owned(std::str::from_utf8(kind).ok()?.to_string()),
+        };
+        Some(Transaction {
+            kind,
+            client_id: id(self.client_id)?,
+            transaction_id: id(self.transaction_id)?,
+            amount,
+            original_transaction_id: optional_id(self.original_transaction_id)?,
+            timestamp: optional_id(self.timestamp)?,
+            row: None,
+        })
+    }
+}
+
+/// Input row of a record.
+///
+/// * `line` - line number of the row in the input, starting from 1.
+/// * `raw` - text of the row as CSV, re-quoted where needed and without
+///   the line terminator. Empty if the row could not be read at all.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Row {
+    pub line: u64,
+    pub raw: String,
+}
+
+impl Row {
+    fn of(record: &csv::StringRecord) -> Row {
+        let mut writer = csv::WriterBuilder::new()
+            .terminator(csv::Terminator::Any(b'\n'))
+            .from_writer(vec![]);
+        // Writing a valid UTF-8 record to memory never fails.
+        writer.write_record(record).unwrap();
+        let raw = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        Row {
+            line: record.position().map_or(0, |p| p.line()),
+            raw: raw.trim_end_matches('\n').to_string(),
+        }
+    }
+}
+
+/// Deserializes an optional amount from its textual form.
+///
+/// Going through the string keeps the scale of the amount exactly as written,
+/// otherwise `csv` infers a float for values like `4.0`.
+fn deserialize_amount<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Decimal>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(raw) if raw.trim().is_empty() => Ok(None),
+        Some(raw) => Decimal::from_str(raw.trim())
+            .map(Some)
+            .map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Deserializes a decimal from its textual form (see `deserialize_amount`).
+fn deserialize_decimal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    Decimal::from_str(raw.trim()).map_err(de::Error::custom)
 }
 
 impl Transaction {
     /// Reads transactions from a `csv::Reader`.
+    ///
+    /// Other errors than of the csv reader, e.g. invalid headers, are wrapped
+    /// in it (see `read_many_with` for the `ParseError`s themselves).
     pub fn read_many<'a, T: std::io::Read>(
         reader: &'a mut csv::Reader<T>,
     ) -> Box<dyn Iterator<Item = Result<Transaction, csv::Error>> + 'a> {
-        let records = reader.deserialize::<Transaction>();
-        let it = records.map(|result| -> Result<Transaction, csv::Error> {
-            let record = result?;
-            Ok(record)
+        let records = Transaction::read_many_with(reader, &ParseOptions::default());
+        Box::new(records.map(|result| result.map_err(csv::Error::from)))
+    }
+
+    /// Reads transactions from a `csv::Reader` according to `options`.
+    ///
+    /// The header row is validated up front. Issues found are reported to stderr,
+    /// or as a single `ParseError::InvalidHeaders` error in strict mode.
+    ///
+    /// Records failing to be read are yielded as errors and reading goes on with
+    /// the next record. Reading stops after an unrecoverable error, e.g. of I/O,
+    /// which the reader would otherwise repeat (see `ParseError::is_recoverable`).
+    pub fn read_many_with<'a, T: std::io::Read>(
+        reader: &'a mut csv::Reader<T>,
+        options: &ParseOptions,
+    ) -> Box<dyn Iterator<Item = Result<Transaction, ParseError>> + 'a> {
+        match header_issues(reader) {
+            Ok(issues) if issues.is_empty() => {}
+            Ok(issues) if options.strict_headers => {
+                return Box::new(std::iter::once(Err(ParseError::InvalidHeaders(issues))));
+            }
+            Ok(issues) => {
+                for issue in issues {
+                    eprintln!("Warning: {}", issue);
+                }
+            }
+            Err(err) => return Box::new(std::iter::once(Err(err.into()))),
+        }
+
+        let records = if options.rewrites_amounts() || options.keep_rows {
+            Transaction::read_records(reader, options.clone())
+        } else {
+            Transaction::read_byte_records(reader)
+        };
+        let records = Box::new(records.scan(false, |failed, result| {
+            if *failed {
+                return None;
+            }
+            if let Err(err) = &result {
+                if !err.is_recoverable() {
+                    eprintln!("Warning: stopped reading the input: {}", err);
+                    *failed = true;
+                }
+            }
+            Some(result)
+        }));
+
+        match options.window {
+            Some(window) => {
+                let mut outside = LeftOut::default();
+                Box::new(records.filter(move |result| match result {
+                    Ok(record) => window.admits(record, &mut outside),
+                    Err(_) => true,
+                }))
+            }
+            None => records,
+        }
+    }
+
+    /// Reads transactions into a reused `csv::ByteRecord`, parsing the fields
+    /// directly rather than through serde.
+    ///
+    /// Records failing to be parsed are deserialized with serde after all,
+    /// so their errors are the same as of `csv::Reader::deserialize`, except for
+    /// non-finite amounts (see `check_finite`). So are all the records if
+    /// the headers repeat a column.
+    fn read_byte_records<'a, T: std::io::Read>(
+        reader: &'a mut csv::Reader<T>,
+    ) -> Box<dyn Iterator<Item = Result<Transaction, ParseError>> + 'a> {
+        let headers = match reader.byte_headers() {
+            Ok(headers) => headers.clone(),
+            Err(err) => return Box::new(std::iter::once(Err(err.into()))),
+        };
+        let columns = ByteColumns::of(&headers);
+        let mut record = csv::ByteRecord::new();
+
+        Box::new(std::iter::from_fn(move || {
+            match reader.read_byte_record(&mut record) {
+                Ok(false) => return None,
+                Ok(true) => {}
+                Err(err) => return Some(Err(err.into())),
+            }
+            let parsed = columns.as_ref().and_then(|columns| columns.parse(&record));
+            if parsed.is_none() {
+                let amount = columns.as_ref().and_then(|c| record.get(c.amount?));
+                if let Err(err) = check_finite(&String::from_utf8_lossy(amount.unwrap_or(b""))) {
+                    return Some(Err(err));
+                }
+            }
+            Some(match parsed {
+                Some(transaction) => Ok(transaction),
+                None => record
+                    .deserialize::<Transaction>(Some(&headers))
+                    .map_err(ParseError::from),
+            })
+        }))
+    }
+
+    /// Reads transactions record by record, rewriting their amounts
+    /// (see `ParseOptions::rewrite_amount`) and keeping their rows as configured by `options`.
+    fn read_records<'a, T: std::io::Read>(
+        reader: &'a mut csv::Reader<T>,
+        options: ParseOptions,
+    ) -> Box<dyn Iterator<Item = Result<Transaction, ParseError>> + 'a> {
+        let headers = match reader.headers() {
+            Ok(headers) => headers.clone(),
+            Err(err) => return Box::new(std::iter::once(Err(err.into()))),
+        };
+        let amount_index = headers.iter().position(|h| h.trim() == "amount");
+
+        let it = reader.records().map(move |result| {
+            let record = match result {
+                Ok(record) => record,
+                Err(err) if options.keep_rows => {
+                    let row = Row {
+                        line: err.position().map_or(0, |p| p.line()),
+                        raw: String::new(),
+                    };
+                    return Err(ParseError::from(err).in_row(row));
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let row = options.keep_rows.then(|| Row::of(&record));
+
+            let parsed = match amount_index {
+                Some(i) if !options.rewrites_amounts() => {
+                    check_finite(record.get(i).unwrap_or("")).map(|_| record)
+                }
+                Some(i) => record
+                    .iter()
+                    .enumerate()
+                    .map(|(j, field)| match j == i {
+                        true => options.rewrite_amount(field),
+                        false => Ok(field.to_string()),
+                    })
+                    .collect::<Result<csv::StringRecord, _>>(),
+                None => Ok(record),
+            }
+            .and_then(|record| Ok(record.deserialize::<Transaction>(Some(&headers))?));
+
+            match (parsed, row) {
+                (Ok(transaction), row) => Ok(Transaction { row, ..transaction }),
+                (Err(err), Some(row)) => Err(err.in_row(row)),
+                (Err(err), None) => Err(err),
+            }
         });
 
         Box::new(it)
     }
 
-    fn meta(&self) -> models::Meta {
-        models::Meta {
-            client_id: models::ClientId::new(self.client_id),
-            transaction_id: models::TransactionId::new(self.transaction_id),
+    fn meta(&self) -> Result<models::Meta, ParseError> {
+        Ok(models::Meta {
+            client_id: models::ClientId::try_from(self.client_id)?,
+            transaction_id: models::TransactionId::try_from(self.transaction_id)?,
+        })
+    }
+
+    /// Returns the id of the transaction a correction references, which must be present.
+    fn original_transaction_id(&self) -> Result<models::TransactionId, ParseError> {
+        match self.original_transaction_id {
+            Some(id) => models::TransactionId::try_from(id),
+            None => Err(ParseError::MissingOriginal),
+        }
+    }
+
+    /// Returns the amount scaled by `options` (see `ParseOptions::amount_scale`),
+    /// normalized unless they ask to preserve its scale.
+    ///
+    /// Panics if the scale exceeds 28 (see `crate::ProcessConfig::validate`).
+    fn amount(&self, options: &ParseOptions) -> Result<Option<Decimal>, ParseError> {
+        let amount = match (options.amount_scale, self.amount) {
+            (0, amount) | (_, amount @ None) => amount,
+            (_, Some(a)) if !a.fract().is_zero() => {
+                return Err(ParseError::FractionalAmount { raw: a.to_string() })
+            }
+            (scale, Some(a)) => Some(a * Decimal::new(1, scale)),
+        };
+        if options.preserve_scale {
+            Ok(amount)
+        } else {
+            Ok(amount.map(|a| a.normalize()))
+        }
+    }
+
+    /// Returns the amount of a deposit or withdrawal, which must be present and
+    /// positive, or zero if accepted by `options` (see `ZeroAmountPolicy`).
+    fn transfer_amount(&self, options: &ParseOptions) -> Result<Decimal, ParseError> {
+        match self.amount(options)? {
+            Some(a) if a.is_zero() && options.zero_amounts == ZeroAmountPolicy::Accept => Ok(a),
+            _ => self.positive_amount(options),
+        }
+    }
+
+    /// Returns the amount of a deposit, which may be negative if accepted by `options`
+    /// (see `ParseOptions::negative_deposits`).
+    fn deposit_amount(&self, options: &ParseOptions) -> Result<Decimal, ParseError> {
+        match self.amount(options)? {
+            Some(a) if a.is_sign_negative() && !a.is_zero() && options.negative_deposits => Ok(a),
+            _ => self.transfer_amount(options),
+        }
+    }
+
+    /// Returns the amount of a deposit, withdrawal or correction, which must be present and positive.
+    fn positive_amount(&self, options: &ParseOptions) -> Result<Decimal, ParseError> {
+        match self.amount(options)? {
+            Some(a) if a > Decimal::ZERO => Ok(a),
+            Some(_) => Err(ParseError::NonpositiveAmount),
+            None => Err(ParseError::MissingAmount),
+        }
+    }
+
+    /// Returns the optional amount of a dispute, resolve or chargeback,
+    /// which must be positive if present.
+    fn portion_amount(&self, options: &ParseOptions) -> Result<Option<Decimal>, ParseError> {
+        match self.amount(options)? {
+            Some(a) if a <= Decimal::ZERO => Err(ParseError::NonpositiveAmount),
+            amount => Ok(amount),
         }
     }
 
     /// Converts raw record into a `models::Transaction`.
     pub fn to_transaction(&self) -> Result<models::Transaction, ParseError> {
-        match self.kind.as_str() {
-            "deposit" => match self.amount {
-                Some(a) if a > Decimal::ZERO => Ok(models::Transaction::Deposit {
-                    meta: self.meta(),
-                    amount: a,
-                }),
-                _ => Err(ParseError::NonpositiveAmount),
-            },
-            "withdrawal" => match self.amount {
-                Some(a) if a > Decimal::ZERO => Ok(models::Transaction::Withdrawal {
-                    meta: self.meta(),
-                    amount: a,
-                }),
-                _ => Err(ParseError::NonpositiveAmount),
-            },
-            "dispute" => Ok(models::Transaction::Dispute { meta: self.meta() }),
-            "resolve" => Ok(models::Transaction::Resolve { meta: self.meta() }),
-            "chargeback" => Ok(models::Transaction::Chargeback { meta: self.meta() }),
+        self.to_transaction_with(&ParseOptions::default())
+    }
+
+    /// Converts raw record into a `models::Transaction` according to `options`.
+    pub fn to_transaction_with(
+        &self,
+        options: &ParseOptions,
+    ) -> Result<models::Transaction, ParseError> {
+        match &*self.kind {
+            "deposit" => Ok(models::Transaction::Deposit {
+                meta: self.meta()?,
+                amount: self.deposit_amount(options)?,
+            }),
+            "withdrawal" => Ok(models::Transaction::Withdrawal {
+                meta: self.meta()?,
+                amount: self.transfer_amount(options)?,
+            }),
+            "correct" => Ok(models::Transaction::Correct {
+                meta: self.meta()?,
+                original_tx: self.original_transaction_id()?,
+                new_amount: self.positive_amount(options)?,
+            }),
+            "dispute" => Ok(models::Transaction::Dispute {
+                meta: self.meta()?,
+                amount: self.portion_amount(options)?,
+            }),
+            "resolve" => Ok(models::Transaction::Resolve {
+                meta: self.meta()?,
+                amount: self.portion_amount(options)?,
+            }),
+            "chargeback" => Ok(models::Transaction::Chargeback {
+                meta: self.meta()?,
+                amount: self.portion_amount(options)?,
+            }),
+            "freeze" => Ok(models::Transaction::Freeze { meta: self.meta()? }),
+            "unfreeze" => Ok(models::Transaction::Unfreeze { meta: self.meta()? }),
+            other if other.trim().is_empty() => Err(ParseError::MissingType),
             other => Err(ParseError::UnknownType {
                 kind: other.to_string(),
             }),
@@ -69,25 +708,581 @@ impl Transaction {
 }
 
 /// Client Account model for IO use.
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+///
+/// A disputed withdrawal leaves the default columns unchanged: its amount is
+/// a pending credit (see `models::Account`), added to available and total funds
+/// only by a chargeback. Select `Column::Pending` to output pending credits.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct Account {
+    /// Input file of the client if clients are namespaced by file.
+    /// Output only as `Column::File` when selected.
+    #[serde(skip)]
+    pub file: u16,
     #[serde(rename = "client")]
     pub client_id: u16,
-    #[serde(rename = "available")]
+    #[serde(rename = "available", deserialize_with = "deserialize_decimal")]
     pub available_funds: Decimal,
-    #[serde(rename = "held")]
+    #[serde(rename = "held", deserialize_with = "deserialize_decimal")]
     pub held_funds: Decimal,
-    #[serde(rename = "total")]
+    #[serde(rename = "total", deserialize_with = "deserialize_decimal")]
     pub total_funds: Decimal,
     #[serde(rename = "locked")]
     pub is_locked: bool,
+    /// Credits pending from disputed withdrawals, not part of the total.
+    /// Output only as `Column::Pending` when selected.
+    #[serde(skip)]
+    pub pending_credits: Decimal,
+    /// Fees paid in total. Output only as `Column::Fees` when selected.
+    #[serde(skip)]
+    pub fees: Decimal,
+    /// Numbers of deposits and withdrawals if counted (see
+    /// `processing::Config::count_transactions`). Output only as
+    /// `Column::Deposits` and `Column::Withdrawals` when selected, empty if not counted.
+    #[serde(skip)]
+    pub deposits: Option<u64>,
+    #[serde(skip)]
+    pub withdrawals: Option<u64>,
+    /// Output only as `Column::LockReason` when selected.
+    #[serde(skip)]
+    pub lock_reason: Option<String>,
+}
+
+/// Rounding of output amounts exceeding `OUTPUT_DECIMALS` places.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Rounding {
+    /// Midpoints are rounded away from zero.
+    HalfUp,
+    /// Midpoints are rounded to the nearest even number (banker's rounding).
+    #[default]
+    HalfEven,
+    /// Extra decimal places are dropped.
+    Truncate,
+}
+
+impl Rounding {
+    /// Rounds `amount` to `OUTPUT_DECIMALS` places, amounts with fewer places are unchanged.
+    pub fn apply(self, amount: Decimal) -> Decimal {
+        let strategy = match self {
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+        };
+        amount.round_dp_with_strategy(OUTPUT_DECIMALS, strategy)
+    }
+}
+
+/// Output column of `Account`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Column {
+    File,
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    Pending,
+    Fees,
+    Deposits,
+    Withdrawals,
+    LockReason,
+}
+
+impl Column {
+    /// All the columns, in order of the wide output consolidating them.
+    pub const ALL: [Column; 11] = [
+        Column::File,
+        Column::Client,
+        Column::Available,
+        Column::Held,
+        Column::Total,
+        Column::Locked,
+        Column::Pending,
+        Column::Fees,
+        Column::Deposits,
+        Column::Withdrawals,
+        Column::LockReason,
+    ];
+
+    /// Columns output by default, in order.
+    pub const DEFAULT: [Column; 5] = [
+        Column::Client,
+        Column::Available,
+        Column::Held,
+        Column::Total,
+        Column::Locked,
+    ];
+
+    /// Returns the column name as used in the output header.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::File => "file",
+            Column::Client => "client",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Total => "total",
+            Column::Locked => "locked",
+            Column::Pending => "pending",
+            Column::Fees => "fees",
+            Column::Deposits => "deposits",
+            Column::Withdrawals => "withdrawals",
+            Column::LockReason => "lock_reason",
+        }
+    }
+
+    /// Whether the column holds an amount (see `Account::amount`).
+    pub fn is_amount(&self) -> bool {
+        matches!(
+            self,
+            Column::Available | Column::Held | Column::Total | Column::Pending | Column::Fees
+        )
+    }
+
+    /// Parses a comma separated list of column names.
+    pub fn parse_list(names: &str) -> Result<Vec<Column>, String> {
+        names.split(',').map(|name| name.trim().parse()).collect()
+    }
+}
+
+/// Whether `name` is the input name of a transaction type, e.g. `deposit`.
+pub fn is_known_type(name: &str) -> bool {
+    KNOWN_TYPES.contains(&name)
+}
+
+/// Parses a comma separated list of transaction type names as used in the input.
+pub fn parse_type_list(names: &str) -> Result<Vec<String>, String> {
+    names
+        .split(',')
+        .map(|name| match is_known_type(name.trim()) {
+            true => Ok(name.trim().to_string()),
+            false => Err(format!(
+                "unknown transaction type '{}', expected one of {}",
+                name.trim(),
+                KNOWN_TYPES.join(",")
+            )),
+        })
+        .collect()
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Column::ALL
+            .iter()
+            .find(|column| column.name() == name)
+            .copied()
+            .ok_or_else(|| {
+                let known: Vec<_> = Column::ALL.iter().map(Column::name).collect();
+                format!(
+                    "unknown column '{}', expected one of {}",
+                    name,
+                    known.join(",")
+                )
+            })
+    }
+}
+
+impl Account {
+    /// Reads accounts from a `csv::Reader`.
+    pub fn read_many<'a, T: std::io::Read>(
+        reader: &'a mut csv::Reader<T>,
+    ) -> Box<dyn Iterator<Item = Result<Account, ParseError>> + 'a> {
+        Box::new(reader.deserialize::<Account>().map(|r| Ok(r?)))
+    }
+
+    /// Reads accounts written by `sink::BincodeSink`.
+    pub fn read_bincode<T: std::io::Read>(reader: T) -> bincode::Result<Vec<Account>> {
+        let accounts: Vec<(u16, Account)> = bincode::deserialize_from(reader)?;
+        let accounts = accounts
+            .into_iter()
+            .map(|(file, account)| Account { file, ..account });
+        Ok(accounts.collect())
+    }
+
+    /// Rounds the available and held funds, pending credits and fees to `OUTPUT_DECIMALS`
+    /// places and recomputes the total from them so the columns stay consistent.
+    /// The total saturates at the bounds of the `Decimal` range.
+    pub fn round(&mut self, rounding: Rounding) {
+        self.available_funds = rounding.apply(self.available_funds);
+        self.held_funds = rounding.apply(self.held_funds);
+        self.pending_credits = rounding.apply(self.pending_credits);
+        self.fees = rounding.apply(self.fees);
+        self.total_funds = self.available_funds.saturating_add(self.held_funds);
+    }
+
+    /// Returns the amount of the given `column`, `None` if it is no amount column.
+    pub fn amount(&self, column: Column) -> Option<Decimal> {
+        match column {
+            Column::Available => Some(self.available_funds),
+            Column::Held => Some(self.held_funds),
+            Column::Total => Some(self.total_funds),
+            Column::Pending => Some(self.pending_credits),
+            Column::Fees => Some(self.fees),
+            _ => None,
+        }
+    }
+
+    /// Returns the textual value of the given `column`.
+    pub fn field(&self, column: Column) -> String {
+        match column {
+            Column::File => self.file.to_string(),
+            Column::Client => self.client_id.to_string(),
+            Column::Available => self.available_funds.to_string(),
+            Column::Held => self.held_funds.to_string(),
+            Column::Total => self.total_funds.to_string(),
+            Column::Locked => self.is_locked.to_string(),
+            Column::Pending => self.pending_credits.to_string(),
+            Column::Fees => self.fees.to_string(),
+            Column::Deposits => self.deposits.map(|n| n.to_string()).unwrap_or_default(),
+            Column::Withdrawals => self.withdrawals.map(|n| n.to_string()).unwrap_or_default(),
+            Column::LockReason => self.lock_reason.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Splits the `amount` into its integer part and its fraction scaled to
+/// `OUTPUT_DECIMALS` digits, e.g. `2.5` into `2` and `5000`, for fixed-point
+/// consumers. The fraction has the sign of the amount, e.g. `-0.5` splits into
+/// `0` and `-5000`. Returns `None` if the fraction has more places.
+pub fn split_amount(amount: Decimal) -> Option<(String, String)> {
+    if amount.normalize().scale() > OUTPUT_DECIMALS {
+        return None;
+    }
+    let integer = amount.trunc();
+    let mut fraction = (amount - integer) * Decimal::from(10u32.pow(OUTPUT_DECIMALS));
+    fraction.rescale(0);
+    let fraction = fraction.mantissa();
+    let width = OUTPUT_DECIMALS as usize + (fraction < 0) as usize;
+    Some((
+        integer.mantissa().to_string(),
+        format!("{:0width$}", fraction),
+    ))
+}
+
+/// Aggregate totals of output accounts for reconciliation.
+///
+/// Amounts are added up with checked arithmetic. A total exceeding the
+/// `Decimal` range saturates at its bounds and flags `overflow`, so the
+/// totals are not to be trusted for reconciliation then.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Summary {
+    pub accounts: usize,
+    pub available_funds: Decimal,
+    pub held_funds: Decimal,
+    pub total_funds: Decimal,
+    pub locked_accounts: usize,
+    pub overflow: bool,
+}
+
+impl Summary {
+    /// Adds the `account` to the totals.
+    pub fn add(&mut self, account: &Account) {
+        self.accounts += 1;
+        for (total, amount) in [
+            (&mut self.available_funds, account.available_funds),
+            (&mut self.held_funds, account.held_funds),
+            (&mut self.total_funds, account.total_funds),
+        ] {
+            *total = total.checked_add(amount).unwrap_or_else(|| {
+                self.overflow = true;
+                total.saturating_add(amount)
+            });
+        }
+        self.locked_accounts += account.is_locked as usize;
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "accounts {}, available {}, held {}, total {}, locked {}",
+            self.accounts,
+            self.available_funds.normalize(),
+            self.held_funds.normalize(),
+            self.total_funds.normalize(),
+            self.locked_accounts
+        )
+    }
+}
+
+/// Total of a client differing from the one expected by a control file.
+///
+/// Clients missing on either side count as having zero total.
+/// Clients are told apart by their input file too (see `Account::file`).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Mismatch {
+    pub file: u16,
+    pub client_id: u16,
+    pub expected: Decimal,
+    pub actual: Decimal,
+}
+
+impl Mismatch {
+    /// Compares the `actual` totals by input file and client id with the totals
+    /// of the `control` accounts, returning the clients whose totals differ by
+    /// more than `tolerance`, by input file and client id.
+    pub fn find(
+        actual: &BTreeMap<(u16, u16), Decimal>,
+        control: &[Account],
+        tolerance: Decimal,
+    ) -> Vec<Mismatch> {
+        let mut totals: BTreeMap<(u16, u16), (Decimal, Decimal)> = BTreeMap::new();
+        for account in control {
+            totals
+                .entry((account.file, account.client_id))
+                .or_default()
+                .0 = account.total_funds;
+        }
+        for (key, total) in actual {
+            totals.entry(*key).or_default().1 = *total;
+        }
+        totals
+            .into_iter()
+            // A difference exceeding the `Decimal` range surely exceeds the tolerance.
+            .filter(|(_, (expected, actual))| {
+                expected
+                    .checked_sub(*actual)
+                    .is_none_or(|difference| difference.abs() > tolerance)
+            })
+            .map(|((file, client_id), (expected, actual))| Mismatch {
+                file,
+                client_id,
+                expected,
+                actual,
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {}", self.client_id)?;
+        if self.file != 0 {
+            write!(f, " of file {}", self.file)?;
+        }
+        write!(
+            f,
+            ": expected total {}, actual {}",
+            self.expected.normalize(),
+            self.actual.normalize()
+        )
+    }
+}
+
+/// Open dispute of a transaction for IO use, e.g. to resume disputes of a prior run.
+///
+/// * `held` - amount held by the open disputes of the transaction.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OpenDispute {
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    #[serde(rename = "tx")]
+    pub transaction_id: u32,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub held: Decimal,
+}
+
+impl OpenDispute {
+    /// Reads open disputes from a `csv::Reader`.
+    pub fn read_many<'a, T: std::io::Read>(
+        reader: &'a mut csv::Reader<T>,
+    ) -> Box<dyn Iterator<Item = Result<OpenDispute, ParseError>> + 'a> {
+        Box::new(reader.deserialize::<OpenDispute>().map(|r| Ok(r?)))
+    }
+}
+
+/// Reference data of a client for IO use, joined onto the output for reporting
+/// (see `crate::ProcessConfig::enrich`).
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClientReference {
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub name: String,
+    pub region: String,
+}
+
+impl ClientReference {
+    /// Reads client references from a `csv::Reader`.
+    pub fn read_many<'a, T: std::io::Read>(
+        reader: &'a mut csv::Reader<T>,
+    ) -> Box<dyn Iterator<Item = Result<ClientReference, ParseError>> + 'a> {
+        Box::new(reader.deserialize::<ClientReference>().map(|r| Ok(r?)))
+    }
+}
+
+/// Input row rejected for the `error` for IO use, e.g. in an error report.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RejectedRow {
+    pub line: u64,
+    pub raw_row: String,
+    pub error: String,
+}
+
+/// Audit log record of an applied transaction for IO use.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    #[serde(rename = "seq")]
+    pub sequence: u64,
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    #[serde(rename = "tx")]
+    pub transaction_id: u32,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub amount: Option<Decimal>,
+    #[serde(rename = "available")]
+    pub available_funds: Decimal,
+    #[serde(rename = "held")]
+    pub held_funds: Decimal,
 }
 
 #[derive(Debug)]
 pub enum ParseError {
     Csv(csv::Error),
-    UnknownType { kind: String },
+    InvalidHeaders(Vec<HeaderIssue>),
+    InvalidId {
+        value: String,
+    },
+    ClientIdOutOfRange {
+        client_id: u64,
+    },
+    TransactionIdOutOfRange {
+        transaction_id: u64,
+    },
+    /// The type is empty or whitespace only.
+    MissingType,
+    UnknownType {
+        kind: String,
+    },
+    InvalidAmount {
+        raw: String,
+    },
+    MissingAmount,
+    MissingOriginal,
     NonpositiveAmount,
+    /// The amount has a fraction although amounts are scaled integers
+    /// (see `ParseOptions::amount_scale`).
+    FractionalAmount {
+        raw: String,
+    },
+    InconsistentTotal {
+        client_id: u16,
+    },
+    /// The `error` of the input `row` (see `ParseOptions::keep_rows`).
+    InRow {
+        row: Row,
+        error: Box<ParseError>,
+    },
+}
+
+impl ParseError {
+    /// Attaches the input `row` to the error.
+    pub fn in_row(self, row: Row) -> ParseError {
+        ParseError::InRow {
+            row,
+            error: Box::new(self),
+        }
+    }
+
+    /// Returns whether reading can go on past the error, as it concerns a single
+    /// record only, e.g. one of invalid UTF-8, rather than the whole input.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            ParseError::Csv(err) => !matches!(err.kind(), csv::ErrorKind::Io(_)),
+            ParseError::InvalidHeaders(_) => false,
+            ParseError::InRow { error, .. } => error.is_recoverable(),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Csv(err) => write!(f, "{}", err),
+            ParseError::InvalidHeaders(issues) => {
+                let issues: Vec<_> = issues.iter().map(|i| i.to_string()).collect();
+                write!(f, "invalid headers: {}", issues.join(", "))
+            }
+            ParseError::InvalidId { value } => write!(f, "invalid id '{}'", value),
+            ParseError::ClientIdOutOfRange { client_id } => write!(
+                f,
+                "client id {} is out of range, the maximum is {}",
+                client_id,
+                u16::MAX
+            ),
+            ParseError::TransactionIdOutOfRange { transaction_id } => write!(
+                f,
+                "transaction id {} is out of range, the maximum is {}",
+                transaction_id,
+                u32::MAX
+            ),
+            ParseError::MissingType => write!(f, "missing type"),
+            ParseError::UnknownType { kind } => {
+                write!(f, "unknown type '{}'", kind)?;
+                match closest_known_type(kind) {
+                    Some(known) => write!(f, "; did you mean '{}'?", known),
+                    None => Ok(()),
+                }
+            }
+            ParseError::InvalidAmount { raw } => write!(f, "invalid amount '{}'", raw),
+            ParseError::MissingAmount => write!(f, "missing amount"),
+            ParseError::MissingOriginal => write!(f, "missing original transaction id"),
+            ParseError::NonpositiveAmount => write!(f, "nonpositive amount"),
+            ParseError::FractionalAmount { raw } => {
+                write!(f, "amount '{}' is not an integer of the amount scale", raw)
+            }
+            ParseError::InconsistentTotal { client_id } => write!(
+                f,
+                "total of client {} doesn't match available and held funds",
+                client_id
+            ),
+            ParseError::InRow { row, error } => write!(f, "line {}: {}", row.line, error),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Wraps other errors than of the csv reader as I/O errors of invalid data.
+impl From<ParseError> for csv::Error {
+    fn from(err: ParseError) -> Self {
+        match err {
+            ParseError::Csv(err) => err,
+            err => std::io::Error::new(std::io::ErrorKind::InvalidData, err).into(),
+        }
+    }
+}
+
+/// Computes the Levenshtein (edit) distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Returns the known transaction type closest to `kind` if it's close
+/// enough to be a likely typo.
+fn closest_known_type(kind: &str) -> Option<&'static str> {
+    let kind = kind.trim().to_lowercase();
+    KNOWN_TYPES
+        .iter()
+        .map(|known| (edit_distance(&kind, known), *known))
+        .filter(|(distance, known)| *distance <= known.len() / 3)
+        .min()
+        .map(|(_, known)| known)
 }
 
 impl From<csv::Error> for ParseError {
@@ -95,3 +1290,494 @@ impl From<csv::Error> for ParseError {
         ParseError::Csv(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::ReaderBuilder;
+    use indoc::indoc;
+    use rust_decimal_macros::dec;
+
+    fn parse(input: &str) -> Vec<Result<models::Transaction, ParseError>> {
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        Transaction::read_many(&mut reader)
+            .map(|r| r.unwrap().to_transaction())
+            .collect()
+    }
+
+    #[test]
+    fn missing_amount() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,
+            withdrawal,1,2,\" \"
+            dispute,1,1,
+        "};
+        let results = parse(input);
+        assert!(matches!(results[0], Err(ParseError::MissingAmount)));
+        assert!(matches!(results[1], Err(ParseError::MissingAmount)));
+        assert!(matches!(
+            results[2],
+            Ok(models::Transaction::Dispute { .. })
+        ));
+    }
+
+    #[test]
+    fn converting_into_models() {
+        let record = Transaction {
+            kind: "deposit".into(),
+            client_id: 1,
+            transaction_id: 2,
+            amount: Some(Decimal::new(25, 1)),
+            original_transaction_id: None,
+            timestamp: None,
+            row: None,
+        };
+        let expected = record.to_transaction().unwrap();
+
+        assert_eq!(models::Transaction::from_proto(&record).unwrap(), expected);
+        assert_eq!(models::Transaction::try_from(record).unwrap(), expected);
+    }
+
+    #[test]
+    fn references_outside_window() {
+        let record = |kind: &'static str, transaction_id, timestamp| Transaction {
+            kind: kind.into(),
+            client_id: 1,
+            transaction_id,
+            amount: None,
+            original_transaction_id: None,
+            timestamp: Some(timestamp),
+            row: None,
+        };
+        let window = TimeWindow {
+            since: Some(200),
+            until: None,
+        };
+        let mut outside = LeftOut::default();
+        assert!(!window.admits(&record("deposit", 1, 100), &mut outside));
+        let dispute = record("dispute", 1, 250);
+        assert!(window.admits(&dispute, &mut outside));
+        assert_eq!(
+            TimeWindow::reference_warning(&dispute, &outside).as_deref(),
+            Some("dispute of client 1 references tx 1 outside the time window")
+        );
+        assert_eq!(
+            TimeWindow::reference_warning(&record("dispute", 2, 250), &outside),
+            None
+        );
+
+        // Only the latest transactions left out are remembered.
+        for transaction_id in 2..=LEFT_OUT_MEMORY as u64 + 1 {
+            window.admits(&record("withdrawal", transaction_id, 100), &mut outside);
+        }
+        assert_eq!(TimeWindow::reference_warning(&dispute, &outside), None);
+        assert!(TimeWindow::reference_warning(&record("resolve", 2, 250), &outside).is_some());
+    }
+
+    #[test]
+    fn unknown_type_suggestion() {
+        let input = indoc! {"
+            type,client,tx,amount
+            depposit,1,1,1.0
+            withdraw,1,2,1.0
+            transfer,1,3,1.0
+        "};
+        let messages: Vec<_> = parse(input)
+            .into_iter()
+            .map(|r| r.unwrap_err().to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "unknown type 'depposit'; did you mean 'deposit'?",
+                "unknown type 'withdraw'; did you mean 'withdrawal'?",
+                "unknown type 'transfer'",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_type() {
+        let input = indoc! {"
+            type,client,tx,amount
+            ,1,1,1.0
+            \"  \",1,2,1.0
+        "};
+        let results = parse(input);
+        assert_eq!(results.len(), 2);
+        for result in results {
+            let err = result.unwrap_err();
+            assert!(matches!(err, ParseError::MissingType), "{:?}", err);
+            assert_eq!(err.to_string(), "missing type");
+        }
+    }
+
+    #[test]
+    fn splitting_amounts() {
+        let split = |amount| split_amount(amount).unwrap();
+        assert_eq!(split(dec!(2.5)), ("2".to_string(), "5000".to_string()));
+        assert_eq!(split(dec!(3)), ("3".to_string(), "0000".to_string()));
+        assert_eq!(
+            split(dec!(-1.0625)),
+            ("-1".to_string(), "-0625".to_string())
+        );
+        assert_eq!(split(dec!(-0.5)), ("0".to_string(), "-5000".to_string()));
+        assert_eq!(split_amount(dec!(0.00001)), None);
+    }
+
+    #[test]
+    fn nonfinite_amounts() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,inf
+            deposit,1,2,-Infinity
+            withdrawal,1,3,NaN
+            deposit,1,4,1/0
+            deposit,1,5,1.0
+        "};
+        let tolerant = ParseOptions {
+            tolerant_amounts: true,
+            ..ParseOptions::default()
+        };
+        let kept = ParseOptions {
+            keep_rows: true,
+            ..ParseOptions::default()
+        };
+        for options in [ParseOptions::default(), tolerant, kept] {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            let messages: Vec<_> = Transaction::read_many_with(&mut reader, &options)
+                .map(|r| match r {
+                    Ok(record) => record.amount.unwrap().to_string(),
+                    Err(ParseError::InRow { error, .. }) => error.to_string(),
+                    Err(err) => err.to_string(),
+                })
+                .collect();
+            assert_eq!(
+                messages,
+                vec![
+                    "invalid amount 'inf'",
+                    "invalid amount '-Infinity'",
+                    "invalid amount 'NaN'",
+                    "invalid amount '1/0'",
+                    "1.0",
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn parsing_columns() {
+        assert_eq!(
+            Column::parse_list("total, client").unwrap(),
+            vec![Column::Total, Column::Client]
+        );
+        assert_eq!(
+            Column::parse_list("client,totl").unwrap_err(),
+            "unknown column 'totl', expected one of file,client,available,held,total,locked,pending,fees,deposits,withdrawals,lock_reason"
+        );
+    }
+
+    #[test]
+    fn validating_headers() {
+        let headers = |input: &str| {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            header_issues(&mut reader).unwrap()
+        };
+
+        assert_eq!(headers("type,client,tx,amount\n"), vec![]);
+        assert_eq!(headers(""), vec![]);
+        assert_eq!(
+            headers("type,client,tx,amount,foo\n"),
+            vec![HeaderIssue::Unexpected("foo".to_string())]
+        );
+        assert_eq!(
+            headers("type,client,client,amont\n"),
+            vec![
+                HeaderIssue::Duplicate("client".to_string()),
+                HeaderIssue::Unexpected("amont".to_string()),
+                HeaderIssue::Missing("tx"),
+                HeaderIssue::Missing("amount"),
+            ]
+        );
+    }
+
+    #[test]
+    fn strict_headers() {
+        let input = indoc! {"
+            type,client,tx,amount,foo
+            deposit,1,1,1.0,bar
+        "};
+        let options = ParseOptions {
+            strict_headers: true,
+            ..ParseOptions::default()
+        };
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let results: Vec<_> = Transaction::read_many_with(&mut reader, &options).collect();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].as_ref().unwrap_err().to_string(),
+            "invalid headers: unexpected column 'foo'"
+        );
+
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let results: Vec<_> = Transaction::read_many(&mut reader).collect();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].as_ref().unwrap().amount, Some(Decimal::ONE));
+    }
+
+    #[test]
+    fn reading_byte_records() {
+        let inputs = [
+            indoc! {"
+                type,client,tx,amount,original,timestamp
+                deposit,1,1,1.5,,100
+                withdrawal,2,2, 2 ,,
+                correct,1,3,4,1,
+                dispute,1,1,,,
+                deposit,0x10,4,1,,
+                deposit,x,5,1,,
+                deposit,1,6,1e,,
+                deposit,1,7,1,-1,
+                deposit,1,8,1,,
+            "},
+            indoc! {"
+                tx,client,type,extra
+                1,2,freeze,x
+            "},
+            indoc! {"
+                type,client,tx,amount,amount
+                deposit,1,1,1.0,2.0
+            "},
+            indoc! {"
+                type,tx,amount
+                deposit,1,1.0
+            "},
+        ];
+        for input in inputs {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            let read: Vec<_> = Transaction::read_many_with(&mut reader, &ParseOptions::default())
+                .map(|r| format!("{:?}", r))
+                .collect();
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            let deserialized: Vec<_> = reader
+                .deserialize::<Transaction>()
+                .map(|r| format!("{:?}", r.map_err(ParseError::from)))
+                .collect();
+            assert_eq!(read, deserialized);
+        }
+    }
+
+    #[test]
+    fn reading_past_invalid_rows() {
+        let input =
+            b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,\xff\xfe,2.0\ndeposit,1,3,3.0\n";
+        let mut reader = ReaderBuilder::new().from_reader(&input[..]);
+        let results: Vec<_> =
+            Transaction::read_many_with(&mut reader, &ParseOptions::default()).collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[1].as_ref().unwrap_err().is_recoverable());
+        assert_eq!(results[2].as_ref().unwrap().transaction_id, 3);
+
+        // The plain reader yields the errors as csv errors.
+        let mut reader = ReaderBuilder::new().from_reader(&input[..]);
+        let errors: Vec<csv::Error> = Transaction::read_many(&mut reader)
+            .filter_map(Result::err)
+            .collect();
+        assert_eq!(errors.len(), 1);
+
+        let options = ParseOptions {
+            keep_rows: true,
+            ..ParseOptions::default()
+        };
+        let mut reader = ReaderBuilder::new().from_reader(&input[..]);
+        let results: Vec<_> = Transaction::read_many_with(&mut reader, &options).collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[1].as_ref().unwrap_err().is_recoverable());
+        assert_eq!(results[2].as_ref().unwrap().transaction_id, 3);
+
+        // Reading stops at an I/O error rather than repeating it.
+        struct Failing;
+        impl std::io::Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disk failure"))
+            }
+        }
+        let input = std::io::Read::chain(&b"type,client,tx,amount\n"[..], Failing);
+        let mut reader = ReaderBuilder::new().from_reader(input);
+        let results: Vec<_> =
+            Transaction::read_many_with(&mut reader, &ParseOptions::default()).collect();
+        assert_eq!(results.len(), 1);
+        assert!(!results[0].as_ref().unwrap_err().is_recoverable());
+    }
+
+    #[test]
+    fn restoring_accounts() {
+        let input = indoc! {"
+            client,available,held,total,locked
+            1,1.50,2,3.50,true
+            2,1,1,3,false
+        "};
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let records: Vec<_> = Account::read_many(&mut reader)
+            .map(|r| r.unwrap())
+            .collect();
+
+        let account = models::Account::try_from(&records[0]).unwrap();
+        assert_eq!(account.to_proto(&models::ClientId::new(1)), records[0]);
+        assert!(matches!(
+            models::Account::try_from(&records[1]),
+            Err(ParseError::InconsistentTotal { client_id: 2 })
+        ));
+    }
+
+    #[test]
+    fn ids_out_of_range() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,65535,4294967295,1.0
+            deposit,65536,1,1.0
+            deposit,1,4294967296,1.0
+        "};
+        let results = parse(input);
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(ParseError::ClientIdOutOfRange { client_id: 65536 })
+        ));
+        assert!(matches!(
+            results[2],
+            Err(ParseError::TransactionIdOutOfRange {
+                transaction_id: 4294967296
+            })
+        ));
+        assert_eq!(
+            results[1].as_ref().unwrap_err().to_string(),
+            "client id 65536 is out of range, the maximum is 65535"
+        );
+    }
+
+    #[test]
+    fn grouped_amounts() {
+        let options = |grouping| ParseOptions {
+            grouping: Some(grouping),
+            ..ParseOptions::default()
+        };
+        let amounts = |input: &str, options: &ParseOptions| -> Vec<Option<Decimal>> {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            Transaction::read_many_with(&mut reader, options)
+                .map(|r| r.unwrap().amount)
+                .collect()
+        };
+
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,\"1,000.50\"
+            deposit,1,2,2.5
+            dispute,1,1,
+        "};
+        assert_eq!(
+            amounts(input, &options(Grouping::Us)),
+            vec![Some(dec!(1000.50)), Some(dec!(2.5)), None]
+        );
+
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,\"1.000,50\"
+            deposit,1,2,\"2,5\"
+        "};
+        assert_eq!(
+            amounts(input, &options(Grouping::Eu)),
+            vec![Some(dec!(1000.50)), Some(dec!(2.5))]
+        );
+
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        assert!(Transaction::read_many(&mut reader).all(|r| r.is_err()));
+    }
+
+    #[test]
+    fn tolerant_amounts() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,$4.00
+            deposit,1,2,4.00 USD
+            deposit,1,3, € 4
+            deposit,1,4,4
+            deposit,1,5,$4.0x0
+            deposit,1,6,abc4
+            deposit,1,7,4 usd
+        "};
+        let options = ParseOptions {
+            tolerant_amounts: true,
+            ..ParseOptions::default()
+        };
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let results: Vec<_> = Transaction::read_many_with(&mut reader, &options).collect();
+
+        for result in &results[..4] {
+            assert_eq!(result.as_ref().unwrap().amount, Some(dec!(4)));
+        }
+        assert_eq!(
+            results[4].as_ref().unwrap_err().to_string(),
+            "invalid amount '$4.0x0'"
+        );
+        assert_eq!(
+            results[5].as_ref().unwrap_err().to_string(),
+            "invalid amount 'abc4'"
+        );
+        assert!(results[6].is_err());
+
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let results: Vec<_> = Transaction::read_many(&mut reader).collect();
+        assert!(results[0].is_err() && results[1].is_err() && results[3].is_ok());
+    }
+
+    #[test]
+    fn nonpositive_amount() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,-5
+            deposit,1,2,0
+        "};
+        let results = parse(input);
+        assert!(matches!(results[0], Err(ParseError::NonpositiveAmount)));
+        assert!(matches!(results[1], Err(ParseError::NonpositiveAmount)));
+    }
+
+    #[test]
+    fn zero_amounts() {
+        let input = indoc! {"
+            type,client,tx,amount,original
+            deposit,1,1,0.00,
+            withdrawal,1,2,0,
+            deposit,1,3,-1,
+            correct,1,4,0,1
+        "};
+        let parse_with = |options: &ParseOptions| -> Vec<_> {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            models::Transaction::read_many_with(&mut reader, options).collect()
+        };
+
+        let results = parse_with(&ParseOptions::default());
+        assert_eq!(results.len(), 4);
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, Err(ParseError::NonpositiveAmount))));
+
+        let options = ParseOptions {
+            zero_amounts: ZeroAmountPolicy::Accept,
+            ..ParseOptions::default()
+        };
+        let results = parse_with(&options);
+        assert!(matches!(
+            results[0],
+            Ok(models::Transaction::Deposit { amount, .. }) if amount.is_zero()
+        ));
+        assert!(matches!(
+            results[1],
+            Ok(models::Transaction::Withdrawal { amount, .. }) if amount.is_zero()
+        ));
+        assert!(matches!(results[2], Err(ParseError::NonpositiveAmount)));
+        assert!(matches!(results[3], Err(ParseError::NonpositiveAmount)));
+    }
+}
@@ -0,0 +1,320 @@
+//! Module defines destinations of the output accounts.
+
+use crate::proto::{self, Account, ClientReference, Column};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+
+/// Destination of the output accounts of a run.
+///
+/// Implement the trait to output accounts in another format than CSV
+/// (see `CsvSink`, `TableSink` and `BincodeSink`).
+pub trait AccountSink {
+    /// Writes all the `accounts` of a run, sorted by client id.
+    ///
+    /// A run outputting accounts in chunks calls it once per chunk, each sorted
+    /// by client id (see `crate::ProcessConfig::chunk_clients`).
+    fn write(&mut self, accounts: Vec<Account>) -> io::Result<()>;
+}
+
+/// Collects accounts in memory.
+impl AccountSink for Vec<Account> {
+    fn write(&mut self, accounts: Vec<Account>) -> io::Result<()> {
+        self.extend(accounts);
+        Ok(())
+    }
+}
+
+/// Writes accounts as CSV records, optionally only the given columns.
+pub struct CsvSink<'a, U: io::Write> {
+    writer: &'a mut csv::Writer<U>,
+    columns: Option<Vec<Column>>,
+    /// Number of digits client ids are zero-padded to, if any.
+    client_width: Option<usize>,
+    /// References of clients to join onto the accounts, if any, by client id.
+    references: Option<Cow<'a, HashMap<u16, ClientReference>>>,
+    /// Whether amounts are split into integer and fraction columns.
+    split_amounts: bool,
+    /// Whether the header of the `columns` has been written.
+    has_header: bool,
+}
+
+impl<'a, U: io::Write> CsvSink<'a, U> {
+    /// Creates a sink writing all columns if `columns` is `None`, otherwise only
+    /// the given columns in the given order.
+    pub fn new(writer: &'a mut csv::Writer<U>, columns: Option<Vec<Column>>) -> CsvSink<'a, U> {
+        CsvSink {
+            writer,
+            columns,
+            client_width: None,
+            references: None,
+            split_amounts: false,
+            has_header: false,
+        }
+    }
+
+    /// Zero-pads client ids to `width` digits, e.g. `00001`, for fixed-width consumers.
+    pub fn client_width(mut self, width: usize) -> CsvSink<'a, U> {
+        self.client_width = Some(width);
+        self
+    }
+
+    /// Appends the name and region of the client to every account, left blank
+    /// for clients missing from the `references`.
+    pub fn enrich(mut self, references: Vec<ClientReference>) -> CsvSink<'a, U> {
+        let references = references.into_iter().map(|r| (r.client_id, r)).collect();
+        self.references = Some(Cow::Owned(references));
+        self
+    }
+
+    /// Same as `enrich` but borrows the `references` by client id, e.g. to share
+    /// them across the sinks of a run.
+    pub fn enrich_by(mut self, references: &'a HashMap<u16, ClientReference>) -> CsvSink<'a, U> {
+        self.references = Some(Cow::Borrowed(references));
+        self
+    }
+
+    /// Splits every amount column into an integer and a four digit fraction
+    /// column, e.g. `available_int` and `available_frac` (see `proto::split_amount`).
+    /// Fails writing amounts with more decimal places, e.g. kept at their scale.
+    pub fn split_amounts(mut self) -> CsvSink<'a, U> {
+        self.split_amounts = true;
+        self
+    }
+}
+
+/// Returns the output field of the `column` of the `account`, with the client id
+/// zero-padded to `client_width` digits if set.
+fn padded_field(account: &Account, column: Column, client_width: Option<usize>) -> String {
+    match (column, client_width) {
+        (Column::Client, Some(width)) => format!("{:0width$}", account.client_id),
+        _ => account.field(column),
+    }
+}
+
+/// Returns the integer and fraction fields of the `amount` (see `proto::split_amount`).
+fn split_fields(amount: rust_decimal::Decimal) -> io::Result<[String; 2]> {
+    match proto::split_amount(amount) {
+        Some((integer, fraction)) => Ok([integer, fraction]),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("amount {} has more than four decimal places", amount),
+        )),
+    }
+}
+
+impl<'a, U: io::Write> AccountSink for CsvSink<'a, U> {
+    fn write(&mut self, accounts: Vec<Account>) -> io::Result<()> {
+        // Padded ids are no numbers to serialize, and references and split
+        // amounts no fields of accounts, so fields are written as text.
+        let textual =
+            self.client_width.is_some() || self.references.is_some() || self.split_amounts;
+        let default = textual.then(|| Column::DEFAULT.to_vec());
+        let extra = match self.references {
+            Some(_) => &["name", "region"][..],
+            None => &[],
+        };
+        match self.columns.as_ref().or(default.as_ref()) {
+            Some(columns) => {
+                let split = |column: &Column| self.split_amounts && column.is_amount();
+                if !self.has_header {
+                    let mut header = Vec::new();
+                    for column in columns {
+                        match split(column) {
+                            true => header.extend([
+                                format!("{}_int", column.name()),
+                                format!("{}_frac", column.name()),
+                            ]),
+                            false => header.push(column.name().to_string()),
+                        }
+                    }
+                    header.extend(extra.iter().map(|name| name.to_string()));
+                    self.writer.write_record(header)?;
+                    self.has_header = true;
+                }
+                for account in accounts {
+                    let mut fields = Vec::new();
+                    for column in columns {
+                        match account.amount(*column).filter(|_| split(column)) {
+                            Some(amount) => fields.extend(split_fields(amount)?),
+                            None => fields.push(padded_field(&account, *column, self.client_width)),
+                        }
+                    }
+                    if let Some(references) = &self.references {
+                        match references.get(&account.client_id) {
+                            Some(r) => fields.extend([r.name.clone(), r.region.clone()]),
+                            None => fields.extend([String::new(), String::new()]),
+                        }
+                    }
+                    self.writer.write_record(fields)?;
+                }
+            }
+            None => {
+                for account in accounts {
+                    self.writer.serialize(account)?;
+                }
+            }
+        }
+        self.writer.flush()
+    }
+}
+
+/// Writes accounts as an aligned plain text table for review in a terminal,
+/// optionally only the given columns.
+///
+/// Columns are as wide as their widest value, amounts and counts right-aligned.
+/// The table is meant for humans, parse CSV output instead.
+pub struct TableSink<U: io::Write> {
+    writer: U,
+    columns: Vec<Column>,
+}
+
+impl<U: io::Write> TableSink<U> {
+    /// Creates a sink writing the default columns if `columns` is `None`,
+    /// otherwise only the given columns in the given order.
+    pub fn new(writer: U, columns: Option<Vec<Column>>) -> TableSink<U> {
+        TableSink {
+            writer,
+            columns: columns.unwrap_or_else(|| Column::DEFAULT.to_vec()),
+        }
+    }
+}
+
+impl<U: io::Write> AccountSink for TableSink<U> {
+    fn write(&mut self, accounts: Vec<Account>) -> io::Result<()> {
+        let header: Vec<_> = self.columns.iter().map(|c| c.name().to_string()).collect();
+        let rows: Vec<Vec<_>> = accounts
+            .iter()
+            .map(|account| self.columns.iter().map(|c| account.field(*c)).collect())
+            .collect();
+        let widths: Vec<_> = (0..self.columns.len())
+            .map(|i| {
+                let cells = rows.iter().map(|row| row[i].len());
+                cells.chain([header[i].len()]).max().unwrap_or(0)
+            })
+            .collect();
+        let rule: Vec<_> = widths.iter().map(|&width| "-".repeat(width)).collect();
+
+        for row in [header, rule].iter().chain(&rows) {
+            let cells: Vec<_> = row
+                .iter()
+                .zip(&self.columns)
+                .zip(&widths)
+                .map(|((cell, column), &width)| match column {
+                    Column::Locked | Column::LockReason => format!("{:<width$}", cell),
+                    _ => format!("{:>width$}", cell),
+                })
+                .collect();
+            writeln!(self.writer, "{}", cells.join("  ").trim_end())?;
+        }
+        self.writer.flush()
+    }
+}
+
+/// Writes accounts compactly with `bincode`, e.g. to pass them to another instance.
+///
+/// Amounts are kept in their textual form, so they round-trip with their full
+/// precision and scale, and along with the file namespace of accounts.
+/// Lock reasons are not included.
+/// Read the output back with `Account::read_bincode`.
+pub struct BincodeSink<U: io::Write> {
+    writer: U,
+}
+
+impl<U: io::Write> BincodeSink<U> {
+    pub fn new(writer: U) -> BincodeSink<U> {
+        BincodeSink { writer }
+    }
+}
+
+impl<U: io::Write> AccountSink for BincodeSink<U> {
+    fn write(&mut self, accounts: Vec<Account>) -> io::Result<()> {
+        // The file namespace is no field of CSV output, so it is written alongside.
+        let accounts: Vec<_> = accounts.into_iter().map(|a| (a.file, a)).collect();
+        bincode::serialize_into(&mut self.writer, &accounts).map_err(io::Error::other)?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn table_output() {
+        let account = |client_id, available_funds: Decimal, is_locked| Account {
+            file: 0,
+            client_id,
+            available_funds,
+            held_funds: dec!(0),
+            total_funds: available_funds,
+            is_locked,
+            pending_credits: dec!(0),
+            fees: dec!(0),
+            deposits: None,
+            withdrawals: None,
+            lock_reason: None,
+        };
+        let accounts = vec![
+            account(1, dec!(1.5), false),
+            account(20, dec!(12345.6789), true),
+            account(300, dec!(-7), false),
+        ];
+
+        let mut sink = TableSink::new(vec![], None);
+        sink.write(accounts).unwrap();
+        let output = String::from_utf8(sink.writer).unwrap();
+        assert_eq!(
+            output,
+            indoc! {"
+                client   available  held       total  locked
+                ------  ----------  ----  ----------  ------
+                     1         1.5     0         1.5  false
+                    20  12345.6789     0  12345.6789  true
+                   300          -7     0          -7  false
+            "}
+        );
+    }
+
+    #[test]
+    fn bincode_round_trip() {
+        let accounts = vec![
+            Account {
+                file: 0,
+                client_id: 1,
+                available_funds: dec!(1.5000),
+                held_funds: dec!(0.00001),
+                total_funds: dec!(1.50001),
+                is_locked: false,
+                pending_credits: dec!(0),
+                fees: dec!(0),
+                deposits: None,
+                withdrawals: None,
+                lock_reason: None,
+            },
+            Account {
+                file: 2,
+                client_id: 65535,
+                available_funds: dec!(-79228162514264337593543950335),
+                held_funds: dec!(0),
+                total_funds: dec!(-79228162514264337593543950335),
+                is_locked: true,
+                pending_credits: dec!(0),
+                fees: dec!(0),
+                deposits: None,
+                withdrawals: None,
+                lock_reason: None,
+            },
+        ];
+
+        let mut sink = BincodeSink::new(vec![]);
+        sink.write(accounts.clone()).unwrap();
+        let restored = Account::read_bincode(sink.writer.as_slice()).unwrap();
+
+        assert_eq!(restored, accounts);
+        assert_eq!(restored[0].available_funds.to_string(), "1.5000");
+    }
+}
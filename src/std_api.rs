@@ -0,0 +1,3339 @@
+//! Processing runs of the `std` feature, re-exported at the crate root.
+
+use crate::{input, models, processing, proto, sink};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// Number of records between checks of the deadline of a run (see `ProcessConfig::deadline`).
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+/// Configuration of a processing run, the single surface for tuning it.
+///
+/// Construct it with `ProcessConfig::builder()` or by updating the fields of
+/// `ProcessConfig::default()`.
+///
+/// * `parsing` - options for converting input records into transactions.
+/// * `processing` - configuration of transaction processing.
+/// * `workers` - number of worker threads processing transactions, at least
+///   one, the number of CPUs if not set. A single worker still runs on its own
+///   thread, but applies the transactions strictly in input order, so its
+///   warnings and events come out in that order too. Meant for reproducible
+///   diagnostics, e.g. to assert on logs in tests, not for performance.
+/// * `progress` - if set, reports the number of records read to stderr
+///   every given number of records.
+/// * `audit_log` - if set, writes a record of every applied transaction
+///   to the file at the path as it is applied. Records of a client are in
+///   the order its transactions were read, as are all with a single worker.
+/// * `columns` - if set, outputs only the given account columns in the given order.
+/// * `seed_accounts` - if set, accounts are initialized from a prior output
+///   at the path before processing. Disputes of transactions processed before
+///   are ignored since only balances are restored. The file is read before
+///   processing, failing the run with `ProcessError::InvalidSeed`.
+/// * `seed_disputes` - if set, open disputes of a prior run are restored from
+///   the CSV file at the path, as `proto::OpenDispute` records, so they can be
+///   resolved or charged back. Their held funds are restored with the accounts
+///   (see `seed_accounts`). Not to be combined with `grouped` either. The file
+///   is read before processing, failing the run with `ProcessError::InvalidSeedDisputes`.
+/// * `rounding` - rounding of output amounts exceeding `proto::OUTPUT_DECIMALS` places.
+/// * `lock_reason` - if set, outputs the reason of account locks as an extra
+///   `lock_reason` column (see `proto::Column::LockReason`).
+/// * `client_width` - if set, client ids of CSV output are zero-padded to
+///   the given number of digits, e.g. `00001` for 5. Wider ids are output whole.
+/// * `split_amounts` - if set, every amount column of CSV output is split into
+///   an integer and a four digit fraction column, e.g. `available_int` and
+///   `available_frac`, for fixed-point consumers (see `proto::split_amount`).
+/// * `enrich` - if set, the `name` and `region` of every client are appended
+///   to CSV output from the `client,name,region` CSV file at the path, left
+///   blank for clients missing from it (see `proto::ClientReference`). The file
+///   is read once per run, failing it with `ProcessError::InvalidEnrichment`.
+///   Not to be combined with `normalize_ids`, as references are joined by the
+///   output client id.
+/// * `suppress_zero` - if set, unlocked accounts with zero total are not output.
+/// * `grouped` - if set, the input is assumed to have all transactions of a client
+///   contiguous, so runs of a client are assigned to workers in turn rather
+///   than by hash (see `processing::Processor::process_grouped`). A client
+///   reappearing after another one aborts the run with `ProcessError::UngroupedInput`.
+///   Not to be combined with `seed_accounts`.
+/// * `chunk_clients` - if set along with `grouped`, the accounts of clients
+///   whose transactions have all been read are output and evicted in chunks of
+///   the given number of clients, bounding memory by the chunk rather than by
+///   all the clients. Every chunk is sorted by client id, not the whole output.
+///   The input must have all transactions of a client contiguous as for
+///   `grouped`, a client reappearing aborts the run before its chunk is output again.
+///   Not to be combined with `shard_output`, `partition_by_lock` or
+///   `normalize_ids`, which need all the accounts at once.
+/// * `explain` - if set, writes a trace of every transaction applied to the
+///   account of the client and its resulting state to stderr at the end of the run.
+/// * `namespace_files` - if set, client ids of every input (see `process_many`)
+///   identify separate clients, output with the index of their input as an extra
+///   leading `file` column (see `proto::Column::File`).
+/// * `dead_letter` - if set, writes the transactions rejected as their account
+///   is frozen to the CSV file at the path, in the input format and the order
+///   they were read, e.g. to reprocess them once the account is unfrozen.
+/// * `errors` - if set, writes the input rows rejected as invalid to the file
+///   at the path, as a JSON array of `proto::RejectedRow`.
+/// * `shard_output` - if set, the accounts of every worker are written to
+///   a separate CSV file `<prefix>-<worker id>.csv` at the prefix path, each
+///   sorted by client id, instead of the output. Clients are partitioned by
+///   worker, so every client is in a single file.
+/// * `partition_by_lock` - if set, the accounts are written to two CSV files
+///   `<prefix>-locked.csv` and `<prefix>-unlocked.csv` at the prefix path by
+///   their lock status, each sorted by client id, instead of the output.
+///   Not to be combined with `shard_output`.
+/// * `normalize_ids` - if set, the client ids of the output accounts are
+///   remapped to the dense range `0..N` in their sorted order, e.g. to share
+///   the output without exposing real account numbers. Not to be combined
+///   with `shard_output` or `enrich`. Control totals are still matched by
+///   the real ids.
+/// * `id_map` - if set along with `normalize_ids`, the mapping of the ids is
+///   written to the CSV file at the path as `old,new` records, prefixed with
+///   the `file` of the original id if clients are namespaced by file.
+/// * `deadline` - if set, stops reading transactions once the run has taken
+///   longer and outputs the accounts processed so far. The results are then
+///   incomplete, flagged by `processing::RunMetrics::partial`.
+/// * `max_transactions` - if set, stops reading transactions once the given
+///   number of them has been submitted, as a guard against runaway inputs.
+///   Invalid records don't count. A warning is reported and the results are
+///   incomplete as with `deadline`.
+/// * `only_types` - if set, only transactions of the given types, by their
+///   input name (e.g. `deposit`), are processed. Others are skipped before
+///   reaching the workers, counted by `processing::RunMetrics::skipped`, and
+///   don't count towards `max_transactions`. Unknown names are rejected by
+///   `validate` (see `proto::parse_type_list`).
+/// * `flush_interval` - if set along with `snapshot_prefix`, snapshots of all
+///   the current accounts are written every given interval while processing
+///   carries on, e.g. to feed a dashboard. Every snapshot goes to a CSV file
+///   `<prefix>-<unix time>-<transactions>.csv` at the prefix path, named by
+///   the time of the configured clock (see `processing::Config::clock`) and the
+///   number of transactions submitted so far, which it reflects all of.
+/// * `snapshot_prefix` - prefix path of the snapshot files (see `flush_interval`).
+/// * `control` - if set, the output totals are verified against the accounts
+///   in the CSV file at the path, as output by a run. Totals differing by more
+///   than `control_tolerance` are reported by `processing::RunMetrics::control_mismatches`.
+///   Clients are matched by id and, if the file has a `file` column, by their
+///   input file (see `namespace_files`).
+/// * `quoting` - CSV quoting of the files read and written at the paths above,
+///   e.g. seed accounts, shards, snapshots and dead letters. Inputs and outputs
+///   passed in as readers and writers keep the quoting they were built with.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessConfig {
+    pub parsing: proto::ParseOptions,
+    pub processing: processing::Config,
+    pub workers: Option<usize>,
+    pub progress: Option<usize>,
+    pub audit_log: Option<PathBuf>,
+    pub columns: Option<Vec<proto::Column>>,
+    pub seed_accounts: Option<PathBuf>,
+    pub seed_disputes: Option<PathBuf>,
+    pub rounding: proto::Rounding,
+    pub lock_reason: bool,
+    pub client_width: Option<usize>,
+    pub split_amounts: bool,
+    pub enrich: Option<PathBuf>,
+    pub suppress_zero: bool,
+    pub grouped: bool,
+    pub chunk_clients: Option<usize>,
+    pub namespace_files: bool,
+    pub explain: Option<models::ClientId>,
+    pub dead_letter: Option<PathBuf>,
+    pub errors: Option<PathBuf>,
+    pub shard_output: Option<PathBuf>,
+    pub partition_by_lock: Option<PathBuf>,
+    pub normalize_ids: bool,
+    pub id_map: Option<PathBuf>,
+    pub deadline: Option<Duration>,
+    pub max_transactions: Option<u64>,
+    pub only_types: Option<Vec<String>>,
+    pub flush_interval: Option<FlushInterval>,
+    pub snapshot_prefix: Option<PathBuf>,
+    pub control: Option<PathBuf>,
+    pub control_tolerance: Decimal,
+    pub quoting: input::Quoting,
+}
+
+/// Interval between snapshots of the accounts of a run (see `ProcessConfig::flush_interval`).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FlushInterval {
+    /// Every given number of transactions submitted, at least one.
+    Transactions(u64),
+    /// Every given time elapsed, checked as transactions are submitted.
+    Time(Duration),
+}
+
+impl ProcessConfig {
+    /// Returns a builder of a configuration, starting from the default one.
+    pub fn builder() -> ProcessConfigBuilder {
+        ProcessConfigBuilder::default()
+    }
+
+    /// Checks the settings are valid, e.g. fees are not negative and
+    /// no two options are combined which are not to be.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let chunked = self.chunk_clients.is_some();
+        let sharded = self.shard_output.is_some();
+        let partitioned = self.partition_by_lock.is_some();
+        let conflicts = [
+            (
+                self.grouped && self.seed_accounts.is_some(),
+                "grouped",
+                "seed_accounts",
+            ),
+            (
+                self.grouped && self.seed_disputes.is_some(),
+                "grouped",
+                "seed_disputes",
+            ),
+            (chunked && sharded, "chunk_clients", "shard_output"),
+            (chunked && partitioned, "chunk_clients", "partition_by_lock"),
+            (
+                chunked && self.normalize_ids,
+                "chunk_clients",
+                "normalize_ids",
+            ),
+            (sharded && partitioned, "shard_output", "partition_by_lock"),
+            (
+                sharded && self.normalize_ids,
+                "shard_output",
+                "normalize_ids",
+            ),
+            (
+                self.enrich.is_some() && self.normalize_ids,
+                "enrich",
+                "normalize_ids",
+            ),
+        ];
+        if let Some((_, option, other)) = conflicts.into_iter().find(|(conflict, ..)| *conflict) {
+            return Err(ConfigError::Conflict(option, other));
+        }
+        let requirements = [
+            (chunked && !self.grouped, "chunk_clients", "grouped"),
+            (
+                self.id_map.is_some() && !self.normalize_ids,
+                "id_map",
+                "normalize_ids",
+            ),
+            (
+                self.flush_interval.is_some() && self.snapshot_prefix.is_none(),
+                "flush_interval",
+                "snapshot_prefix",
+            ),
+            (
+                self.snapshot_prefix.is_some() && self.flush_interval.is_none(),
+                "snapshot_prefix",
+                "flush_interval",
+            ),
+        ];
+        if let Some((_, option, other)) = requirements.into_iter().find(|(missing, ..)| *missing) {
+            return Err(ConfigError::Requirement(option, other));
+        }
+        if self.workers == Some(0) {
+            return Err(ConfigError::ZeroWorkers);
+        }
+        if self.parsing.amount_scale > 28 {
+            return Err(ConfigError::AmountScale(self.parsing.amount_scale));
+        }
+        let mut types = self.only_types.iter().flatten();
+        if let Some(kind) = types.find(|kind| !proto::is_known_type(kind)) {
+            return Err(ConfigError::UnknownType(kind.clone()));
+        }
+        let fees = [self.processing.deposit_fee, self.processing.withdrawal_fee];
+        if let Some(fee) = fees
+            .into_iter()
+            .flatten()
+            .find(processing::Fee::is_negative)
+        {
+            return Err(ConfigError::NegativeFee(fee));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of worker threads of a run, at least one even if
+    /// the configuration was not validated.
+    fn worker_count(&self) -> usize {
+        self.workers.unwrap_or_else(num_cpus::get).max(1)
+    }
+}
+
+/// Former name of `ProcessConfig`.
+#[deprecated(note = "renamed to `ProcessConfig`")]
+pub type Options = ProcessConfig;
+
+/// Builder of a `ProcessConfig`, setting its fields one by one.
+///
+/// Fields not set keep their default values, see `ProcessConfig` for their meaning.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessConfigBuilder {
+    config: ProcessConfig,
+}
+
+impl ProcessConfigBuilder {
+    pub fn parsing(mut self, parsing: proto::ParseOptions) -> Self {
+        self.config.parsing = parsing;
+        self
+    }
+
+    pub fn processing(mut self, processing: processing::Config) -> Self {
+        self.config.processing = processing;
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = Some(workers);
+        self
+    }
+
+    pub fn progress(mut self, every: usize) -> Self {
+        self.config.progress = Some(every);
+        self
+    }
+
+    pub fn audit_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.audit_log = Some(path.into());
+        self
+    }
+
+    pub fn columns(mut self, columns: Vec<proto::Column>) -> Self {
+        self.config.columns = Some(columns);
+        self
+    }
+
+    pub fn seed_accounts(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.seed_accounts = Some(path.into());
+        self
+    }
+
+    pub fn seed_disputes(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.seed_disputes = Some(path.into());
+        self
+    }
+
+    pub fn rounding(mut self, rounding: proto::Rounding) -> Self {
+        self.config.rounding = rounding;
+        self
+    }
+
+    pub fn lock_reason(mut self, lock_reason: bool) -> Self {
+        self.config.lock_reason = lock_reason;
+        self
+    }
+
+    pub fn client_width(mut self, width: usize) -> Self {
+        self.config.client_width = Some(width);
+        self
+    }
+
+    pub fn only_types(mut self, types: Vec<String>) -> Self {
+        self.config.only_types = Some(types);
+        self
+    }
+
+    pub fn split_amounts(mut self, split_amounts: bool) -> Self {
+        self.config.split_amounts = split_amounts;
+        self
+    }
+
+    pub fn enrich(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.enrich = Some(path.into());
+        self
+    }
+
+    pub fn suppress_zero(mut self, suppress_zero: bool) -> Self {
+        self.config.suppress_zero = suppress_zero;
+        self
+    }
+
+    pub fn grouped(mut self, grouped: bool) -> Self {
+        self.config.grouped = grouped;
+        self
+    }
+
+    pub fn chunk_clients(mut self, chunk_clients: usize) -> Self {
+        self.config.chunk_clients = Some(chunk_clients);
+        self
+    }
+
+    pub fn namespace_files(mut self, namespace_files: bool) -> Self {
+        self.config.namespace_files = namespace_files;
+        self
+    }
+
+    pub fn explain(mut self, client_id: models::ClientId) -> Self {
+        self.config.explain = Some(client_id);
+        self
+    }
+
+    pub fn dead_letter(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.dead_letter = Some(path.into());
+        self
+    }
+
+    pub fn errors(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.errors = Some(path.into());
+        self
+    }
+
+    pub fn shard_output(mut self, prefix: impl Into<PathBuf>) -> Self {
+        self.config.shard_output = Some(prefix.into());
+        self
+    }
+
+    pub fn partition_by_lock(mut self, prefix: impl Into<PathBuf>) -> Self {
+        self.config.partition_by_lock = Some(prefix.into());
+        self
+    }
+
+    pub fn normalize_ids(mut self, normalize_ids: bool) -> Self {
+        self.config.normalize_ids = normalize_ids;
+        self
+    }
+
+    pub fn id_map(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.id_map = Some(path.into());
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.config.deadline = Some(deadline);
+        self
+    }
+
+    pub fn max_transactions(mut self, max_transactions: u64) -> Self {
+        self.config.max_transactions = Some(max_transactions);
+        self
+    }
+
+    pub fn snapshots(mut self, interval: FlushInterval, prefix: impl Into<PathBuf>) -> Self {
+        self.config.flush_interval = Some(interval);
+        self.config.snapshot_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn control(mut self, path: impl Into<PathBuf>, tolerance: Decimal) -> Self {
+        self.config.control = Some(path.into());
+        self.config.control_tolerance = tolerance;
+        self
+    }
+
+    pub fn quoting(mut self, quoting: input::Quoting) -> Self {
+        self.config.quoting = quoting;
+        self
+    }
+
+    /// Returns the configuration, failing if its settings are invalid
+    /// (see `ProcessConfig::validate`).
+    pub fn build(self) -> Result<ProcessConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Invalid setting of a `ProcessConfig` (see `ProcessConfig::validate`).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ConfigError {
+    /// A deposit or withdrawal fee is negative, so it would credit the account.
+    NegativeFee(processing::Fee),
+    /// The number of workers is zero, so no transaction would be processed.
+    ZeroWorkers,
+    /// The amount scale exceeds the 28 decimal places of `Decimal`
+    /// (see `proto::ParseOptions::amount_scale`).
+    AmountScale(u32),
+    /// A type to process only is no input name of a transaction type, so
+    /// no transaction would be of it (see `ProcessConfig::only_types`).
+    UnknownType(String),
+    /// The first option is set along with the second one, which it is not
+    /// to be combined with, e.g. `grouped` and `seed_accounts`.
+    Conflict(&'static str, &'static str),
+    /// The first option is set without the second one, which it requires,
+    /// e.g. `chunk_clients` without `grouped`.
+    Requirement(&'static str, &'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NegativeFee(processing::Fee::Flat(fee)) => {
+                write!(f, "fee {} is negative", fee)
+            }
+            ConfigError::NegativeFee(processing::Fee::Percent(percent)) => {
+                write!(f, "fee {}% is negative", percent)
+            }
+            ConfigError::ZeroWorkers => write!(f, "workers must be at least 1"),
+            ConfigError::AmountScale(scale) => {
+                write!(f, "amount scale {} exceeds 28", scale)
+            }
+            ConfigError::UnknownType(kind) => write!(f, "unknown transaction type '{}'", kind),
+            ConfigError::Conflict(option, other) => {
+                write!(f, "{} cannot be combined with {}", option, other)
+            }
+            ConfigError::Requirement(option, other) => write!(f, "{} requires {}", option, other),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Error of a processing run.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ProcessError {
+    /// More than one account of the client resulted, e.g. as the input of
+    /// a `grouped` run had transactions of the client apart. Nothing is output.
+    DuplicateClient(models::ClientId),
+    /// The total funds of the client exceed the `Decimal` range in checked
+    /// arithmetic mode (see `models::ArithmeticMode`). Nothing is output.
+    TotalOverflow(models::ClientId),
+    /// Transactions of the client reappear after other clients in the record,
+    /// counted from 1 across the inputs, although the input of a `grouped` run
+    /// must have them contiguous. The run is aborted and nothing more is output.
+    UngroupedInput {
+        client_id: models::ClientId,
+        record: usize,
+    },
+    /// More inputs than file namespaces, one per `u16`, were given with
+    /// `namespace_files`. Nothing is processed.
+    TooManyFiles(usize),
+    /// The control file can't be read for the given reason (see
+    /// `ProcessConfig::control`). Nothing is processed.
+    InvalidControl(String),
+    /// More clients than normalized ids, one per `u16`, were output with
+    /// `normalize_ids`, e.g. of separate input files. Nothing is output.
+    TooManyClients(usize),
+    /// The enrichment file can't be read for the given reason (see
+    /// `ProcessConfig::enrich`). Nothing is processed.
+    InvalidEnrichment(String),
+    /// The seed accounts file can't be read for the given reason, e.g. an account
+    /// total doesn't match its funds (see `ProcessConfig::seed_accounts`).
+    /// Nothing is processed.
+    InvalidSeed(String),
+    /// The seed disputes file can't be read for the given reason (see
+    /// `ProcessConfig::seed_disputes`). Nothing is processed.
+    InvalidSeedDisputes(String),
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::DuplicateClient(client_id) => {
+                write!(f, "client {} has more than one account", client_id)
+            }
+            ProcessError::TotalOverflow(client_id) => {
+                write!(f, "total funds of client {} overflow", client_id)
+            }
+            ProcessError::UngroupedInput { client_id, record } => write!(
+                f,
+                "client {} reappears in record {} after other clients, input is not grouped",
+                client_id, record
+            ),
+            ProcessError::TooManyFiles(count) => {
+                write!(f, "{} inputs exceed the {} file namespaces", count, 1 << 16)
+            }
+            ProcessError::InvalidControl(reason) => write!(f, "invalid control file: {}", reason),
+            ProcessError::TooManyClients(count) => {
+                write!(f, "{} clients exceed the {} normalized ids", count, 1 << 16)
+            }
+            ProcessError::InvalidEnrichment(reason) => {
+                write!(f, "invalid enrichment file: {}", reason)
+            }
+            ProcessError::InvalidSeed(reason) => {
+                write!(f, "invalid seed accounts file: {}", reason)
+            }
+            ProcessError::InvalidSeedDisputes(reason) => {
+                write!(f, "invalid seed disputes file: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {}
+
+/// Processes transactions from the `reader` and outputs the resulted
+/// client account to the `writer`.
+///
+/// The output accounts are sorted by client id, each client is output once.
+pub fn process<T: std::io::Read, U: std::io::Write>(
+    reader: &mut csv::Reader<T>,
+    writer: &mut csv::Writer<U>,
+) -> Result<(), ProcessError> {
+    process_with_config(reader, writer, &ProcessConfig::default()).map(|_| ())
+}
+
+/// Same as `process` but allows tuning the run with `options`.
+/// Returns metrics of the run.
+pub fn process_with_config<T: std::io::Read, U: std::io::Write>(
+    reader: &mut csv::Reader<T>,
+    writer: &mut csv::Writer<U>,
+    options: &ProcessConfig,
+) -> Result<processing::RunMetrics, ProcessError> {
+    process_many(std::slice::from_mut(reader), writer, options)
+}
+
+/// Former name of `process_with_config`.
+#[deprecated(note = "renamed to `process_with_config`")]
+pub fn process_with_options<T: std::io::Read, U: std::io::Write>(
+    reader: &mut csv::Reader<T>,
+    writer: &mut csv::Writer<U>,
+    options: &ProcessConfig,
+) -> Result<processing::RunMetrics, ProcessError> {
+    process_with_config(reader, writer, options)
+}
+
+/// Same as `process_with_config` but reads transactions from all the `readers`,
+/// one after another, as a single input.
+pub fn process_many<T: std::io::Read, U: std::io::Write>(
+    readers: &mut [csv::Reader<T>],
+    writer: &mut csv::Writer<U>,
+    options: &ProcessConfig,
+) -> Result<processing::RunMetrics, ProcessError> {
+    let references = read_references(options)?;
+    let mut sink = csv_sink(writer, options, references.as_ref());
+    read_many_into(readers, &mut sink, options, references.as_ref())
+}
+
+/// Client references joined onto the output by client id (see `ProcessConfig::enrich`).
+type References = std::collections::HashMap<u16, proto::ClientReference>;
+
+/// Reads the client references of the enrichment file if set (see `ProcessConfig::enrich`).
+fn read_references(options: &ProcessConfig) -> Result<Option<References>, ProcessError> {
+    let Some(path) = &options.enrich else {
+        return Ok(None);
+    };
+    let invalid = |err: &dyn fmt::Display| ProcessError::InvalidEnrichment(err.to_string());
+    let mut reader = options
+        .quoting
+        .reader()
+        .from_path(path)
+        .map_err(|err| invalid(&err))?;
+    let mut references = References::new();
+    for record in proto::ClientReference::read_many(&mut reader) {
+        let record = record.map_err(|err| invalid(&err))?;
+        references.insert(record.client_id, record);
+    }
+    Ok(Some(references))
+}
+
+/// Returns a sink writing CSV output to the `writer` as configured by `options`,
+/// joining the `references` onto the accounts if set.
+fn csv_sink<'a, U: std::io::Write>(
+    writer: &'a mut csv::Writer<U>,
+    options: &ProcessConfig,
+    references: Option<&'a References>,
+) -> sink::CsvSink<'a, U> {
+    let mut sink = sink::CsvSink::new(writer, output_columns(options));
+    if let Some(width) = options.client_width {
+        sink = sink.client_width(width);
+    }
+    if options.split_amounts {
+        sink = sink.split_amounts();
+    }
+    if let Some(references) = references {
+        sink = sink.enrich_by(references);
+    }
+    sink
+}
+
+/// Returns the columns of CSV or table output as configured by `options`,
+/// `None` for the default ones (see `sink::TableSink`).
+pub fn output_columns(options: &ProcessConfig) -> Option<Vec<proto::Column>> {
+    let mut columns = options.columns.clone();
+    if options.lock_reason {
+        let columns = columns.get_or_insert_with(|| proto::Column::DEFAULT.to_vec());
+        if !columns.contains(&proto::Column::LockReason) {
+            columns.push(proto::Column::LockReason);
+        }
+    }
+    if options.namespace_files {
+        let columns = columns.get_or_insert_with(|| proto::Column::DEFAULT.to_vec());
+        if !columns.contains(&proto::Column::File) {
+            columns.insert(0, proto::Column::File);
+        }
+    }
+    columns
+}
+
+/// Processes transactions read from the `stream` (e.g. a `TcpStream`) as they
+/// arrive until the stream ends, i.e. the peer closes it. The stream carries
+/// CSV records with a header row as an input file does.
+/// Returns the resulting accounts sorted by client id.
+///
+/// Accounts are drained once the stream is closed, so they are never returned
+/// for a stream that never ends.
+/// A leading BOM of the stream is skipped (see `input::SkipBom`).
+pub fn process_stream<R: std::io::Read>(
+    stream: R,
+    options: &ProcessConfig,
+) -> Result<Vec<proto::Account>, ProcessError> {
+    let mut reader = options
+        .quoting
+        .reader()
+        .from_reader(input::SkipBom::new(stream));
+    let mut accounts = Vec::new();
+    process_into(&mut reader, &mut accounts, options)?;
+    Ok(accounts)
+}
+
+/// Same as `process_with_config` but outputs the accounts to the `sink`.
+/// The `columns` option only applies to CSV output.
+pub fn process_into<T: std::io::Read>(
+    reader: &mut csv::Reader<T>,
+    sink: &mut dyn sink::AccountSink,
+    options: &ProcessConfig,
+) -> Result<processing::RunMetrics, ProcessError> {
+    process_many_into(std::slice::from_mut(reader), sink, options)
+}
+
+/// Same as `process_many` but outputs the accounts to the `sink`.
+pub fn process_many_into<T: std::io::Read>(
+    readers: &mut [csv::Reader<T>],
+    sink: &mut dyn sink::AccountSink,
+    options: &ProcessConfig,
+) -> Result<processing::RunMetrics, ProcessError> {
+    let references = read_references(options)?;
+    read_many_into(readers, sink, options, references.as_ref())
+}
+
+/// Same as `process_many_into` but with the client `references` of the
+/// enrichment file read already, if set (see `process_transactions_into`).
+fn read_many_into<T: std::io::Read>(
+    readers: &mut [csv::Reader<T>],
+    sink: &mut dyn sink::AccountSink,
+    options: &ProcessConfig,
+    references: Option<&References>,
+) -> Result<processing::RunMetrics, ProcessError> {
+    let files = match options.namespace_files {
+        true => file_namespaces(readers.len())?,
+        false => Vec::new(),
+    };
+    let mut parsing = options.parsing.clone();
+    parsing.keep_rows |= options.errors.is_some();
+    let transactions = readers.iter_mut().enumerate().flat_map(|(i, reader)| {
+        let file = files.get(i).copied();
+        let transactions = models::Transaction::read_many_with(reader, &parsing);
+        transactions.map(move |result| match file {
+            Some(file) => result.map(|tr| tr.namespaced(file)),
+            None => result,
+        })
+    });
+    process_transactions_into(transactions, sink, options, references)
+}
+
+/// Returns the file namespaces of `count` inputs (see `ProcessConfig::namespace_files`).
+fn file_namespaces(count: usize) -> Result<Vec<u16>, ProcessError> {
+    (0..count)
+        .map(u16::try_from)
+        .collect::<Result<_, _>>()
+        .map_err(|_| ProcessError::TooManyFiles(count))
+}
+
+/// Rewrites the transactions read from the `reader` to the `writer` in the
+/// canonical `type,client,tx,amount` form without processing them, e.g. to clean
+/// data before archiving. Amounts are parsed according to `options`, so tolerant
+/// ones are written plain. Invalid records are skipped, timestamps are not kept.
+/// Transactions are written as they are read, with the `original` column
+/// if the input has one.
+///
+/// Returns the number of transactions written and of invalid records skipped.
+pub fn canonicalize<T: std::io::Read, U: std::io::Write>(
+    reader: &mut csv::Reader<T>,
+    writer: &mut csv::Writer<U>,
+    options: &proto::ParseOptions,
+) -> csv::Result<(usize, usize)> {
+    let corrections = reader.headers()?.iter().any(|name| name == "original");
+    writer.write_record(transaction_header(corrections))?;
+    let (mut written, mut skipped) = (0, 0);
+    for result in models::Transaction::read_many_with(reader, options) {
+        match result {
+            Ok(transaction) => {
+                writer.write_record(transaction_row(&transaction.to_proto(), corrections))?;
+                written += 1;
+            }
+            Err(_) => skipped += 1,
+        }
+    }
+    writer.flush()?;
+    Ok((written, skipped))
+}
+
+/// Processes already parsed `transactions` (e.g. read with
+/// `models::Transaction::read_json`) and outputs the accounts to the `sink`.
+///
+/// Parsing options don't apply since nothing is parsed.
+pub fn replay_into(
+    transactions: impl IntoIterator<Item = models::Transaction>,
+    sink: &mut dyn sink::AccountSink,
+    options: &ProcessConfig,
+) -> Result<processing::RunMetrics, ProcessError> {
+    let references = read_references(options)?;
+    let transactions = transactions.into_iter().map(Ok);
+    process_transactions_into(transactions, sink, options, references.as_ref())
+}
+
+/// Processes the `transactions`, skipping the invalid ones, and outputs the accounts to the `sink`.
+///
+/// The client `references` are joined onto the CSV files written besides
+/// the `sink`, e.g. shards, read once per run (see `read_references`).
+fn process_transactions_into(
+    transactions: impl Iterator<Item = Result<models::Transaction, proto::ParseError>>,
+    sink: &mut dyn sink::AccountSink,
+    options: &ProcessConfig,
+    references: Option<&References>,
+) -> Result<processing::RunMetrics, ProcessError> {
+    let started = Instant::now();
+    let control = options
+        .control
+        .as_deref()
+        .map(|path| read_control(path, options.quoting))
+        .transpose()?;
+    let seed_accounts = options
+        .seed_accounts
+        .as_deref()
+        .map(|path| read_seed_accounts(path, options.quoting))
+        .transpose()?;
+    let seed_disputes = options
+        .seed_disputes
+        .as_deref()
+        .map(|path| read_seed_disputes(path, options.quoting))
+        .transpose()?;
+    let mut config = options.processing.clone();
+    config.emit_events |= options.audit_log.is_some() || options.explain.is_some();
+    if options.audit_log.is_none() {
+        config.event_client = options.explain;
+    }
+    config.emit_dead_letters |= options.dead_letter.is_some();
+    let mut processor = processing::Processor::spawn_with_config(options.worker_count(), config);
+    // Events are consumed as they come, so they never pile up in memory.
+    let events = processor.take_events().map(|events| {
+        let audit_log = options.audit_log.clone();
+        let (explained, quoting) = (options.explain, options.quoting);
+        std::thread::spawn(move || consume_events(events, audit_log, explained, quoting))
+    });
+    let dead_letters = processor.take_dead_letters();
+
+    for (client_id, account) in seed_accounts.into_iter().flatten() {
+        processor.seed(client_id, account);
+    }
+    for record in seed_disputes.into_iter().flatten() {
+        processor.seed_dispute(
+            models::ClientId::new(record.client_id),
+            models::TransactionId::new(record.transaction_id),
+            record.held,
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!(
+        "read",
+        records = tracing::field::Empty,
+        transactions = tracing::field::Empty,
+    )
+    .entered();
+    #[cfg(feature = "tracing")]
+    let mut n_records = 0;
+    let mut summary = proto::Summary::default();
+    let mut totals = BTreeMap::new();
+    let mut collect_totals = |records: &[proto::Account]| {
+        if options.control.is_some() {
+            totals.extend(
+                records
+                    .iter()
+                    .map(|r| ((r.file, r.client_id), r.total_funds)),
+            );
+        }
+    };
+    let mut partial = false;
+    let mut submitted = 0;
+    let mut skipped = 0;
+    let mut flushed_at = Instant::now();
+    let mut rejected_rows = Vec::new();
+    // Clients of grouped input whose run of transactions has completed.
+    let mut grouped_clients = HashSet::new();
+    let mut grouped_client = None;
+    for (n_read, result) in (1..).zip(transactions) {
+        #[cfg(feature = "tracing")]
+        {
+            n_records = n_read;
+        }
+        if let Some(deadline) = options.deadline {
+            if n_read % DEADLINE_CHECK_INTERVAL == 0 && started.elapsed() >= deadline {
+                partial = true;
+                break;
+            }
+        }
+        if let Some(every) = options.progress {
+            if n_read % every == 0 {
+                eprintln!("Read {} records", n_read);
+            }
+        }
+
+        // Invalid records are skipped, reported only by the error report if any.
+        match result {
+            Ok(tr)
+                if options
+                    .only_types
+                    .as_ref()
+                    .is_some_and(|types| !types.iter().any(|kind| kind == tr.kind())) =>
+            {
+                skipped += 1;
+            }
+            Ok(_) if options.max_transactions == Some(submitted) => {
+                eprintln!(
+                    "Warning: reached the limit of {} transactions, stopped reading",
+                    submitted
+                );
+                partial = true;
+                break;
+            }
+            Ok(tr) => {
+                let client_id = tr.meta().client_id;
+                if options.grouped && grouped_client != Some(client_id) {
+                    if grouped_clients.contains(&client_id) {
+                        // Workers are halted before bailing out, not to fail sending their output.
+                        processor.wait_shards();
+                        return Err(ProcessError::UngroupedInput {
+                            client_id,
+                            record: n_read,
+                        });
+                    }
+                    grouped_clients.extend(grouped_client.replace(client_id));
+                }
+                submitted += 1;
+                match options.grouped {
+                    true => processor.process_grouped(tr),
+                    false => processor.process(tr),
+                }
+                if let (Some(interval), Some(prefix)) =
+                    (options.flush_interval, &options.snapshot_prefix)
+                {
+                    let due = match interval {
+                        FlushInterval::Transactions(n) => submitted % n.max(1) == 0,
+                        FlushInterval::Time(time) => flushed_at.elapsed() >= time,
+                    };
+                    if due {
+                        write_snapshot(&processor, prefix, submitted, options, references);
+                        flushed_at = Instant::now();
+                    }
+                }
+                if let Some(chunk) = options.chunk_clients.filter(|_| options.grouped) {
+                    if processor.completed_clients() >= chunk {
+                        let accounts = processor.flush_completed();
+                        let records = match output_records(&accounts, options, &mut summary) {
+                            Ok(records) => records,
+                            Err(err) => {
+                                // Workers are halted before bailing out, not to fail sending their output.
+                                processor.wait_shards();
+                                return Err(err);
+                            }
+                        };
+                        collect_totals(&records);
+                        sink.write(records).expect("Failed to write accounts");
+                    }
+                }
+            }
+            Err(err) => {
+                // Reading stops at an error it can't go past, the rest is unread.
+                partial |= !err.is_recoverable();
+                if let (proto::ParseError::InRow { row, error }, Some(_)) = (err, &options.errors) {
+                    rejected_rows.push(proto::RejectedRow {
+                        line: row.line,
+                        raw_row: row.raw,
+                        error: error.to_string(),
+                    })
+                }
+            }
+        }
+    }
+    if let Some(path) = &options.errors {
+        write_error_report(path, &rejected_rows);
+    }
+
+    #[cfg(feature = "tracing")]
+    {
+        span.record("records", n_records)
+            .record("transactions", submitted);
+        drop(span);
+    }
+
+    // Accounts are merged unless sharded, so a client of several partitions
+    // is told by adjacent accounts.
+    let (shards, accounts) = match options.shard_output {
+        Some(_) => {
+            let shards = processor.wait_shards();
+            if let Some(client_id) = processing::find_duplicate_in_shards(&shards) {
+                return Err(ProcessError::DuplicateClient(client_id));
+            }
+            (shards, Vec::new())
+        }
+        None => {
+            let accounts = processing::merge_sorted(processor.wait_shards());
+            if let Some(client_id) = processing::find_duplicate(&accounts) {
+                return Err(ProcessError::DuplicateClient(client_id));
+            }
+            (Vec::new(), accounts)
+        }
+    };
+
+    if let (Some(dead_letters), Some(path)) = (dead_letters, &options.dead_letter) {
+        let mut dead_letters: Vec<_> = dead_letters.iter().collect();
+        dead_letters.sort_by_key(|d| d.sequence);
+        write_dead_letters(path, &dead_letters, options.quoting);
+    }
+
+    if let Some(trace) = events.and_then(|events| events.join().unwrap()) {
+        eprint!("{}", trace);
+    }
+
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!(
+        "output",
+        accounts = shards.iter().map(Vec::len).sum::<usize>() + accounts.len()
+    )
+    .entered();
+    match (&options.shard_output, &options.partition_by_lock) {
+        (Some(prefix), _) => {
+            // All shards are converted first, so an overflowing total leaves no
+            // partial output behind.
+            let shards = shards
+                .iter()
+                .map(|shard| output_records(shard, options, &mut summary))
+                .collect::<Result<Vec<_>, _>>()?;
+            for (i, records) in shards.into_iter().enumerate() {
+                collect_totals(&records);
+                write_output_file(prefix, &format!("-{}.csv", i), records, options, references);
+            }
+        }
+        (None, partition_by_lock) => {
+            let mut records = output_records(&accounts, options, &mut summary)?;
+            collect_totals(&records);
+            if options.normalize_ids {
+                let id_map = normalize_ids(&mut records)?;
+                if let Some(path) = &options.id_map {
+                    write_id_map(path, &id_map, options.namespace_files, options.quoting)
+                        .expect("Failed to write id map file");
+                }
+            }
+            match partition_by_lock {
+                Some(prefix) => {
+                    let (locked, unlocked) = records.into_iter().partition(|r| r.is_locked);
+                    write_output_file(prefix, "-locked.csv", locked, options, references);
+                    write_output_file(prefix, "-unlocked.csv", unlocked, options, references);
+                }
+                None => sink.write(records).expect("Failed to write accounts"),
+            }
+        }
+    }
+
+    let mut metrics = processor.metrics().clone();
+    metrics.summary = summary;
+    metrics.partial = partial;
+    metrics.skipped = skipped;
+    metrics.duration = started.elapsed();
+    if let Some(control) = control {
+        metrics.control_mismatches =
+            proto::Mismatch::find(&totals, &control, options.control_tolerance);
+    }
+    Ok(metrics)
+}
+
+/// Reads the accounts of the control file at `path`, with their input file
+/// if the file has a `file` column (see `ProcessConfig::control`).
+fn read_control(
+    path: &std::path::Path,
+    quoting: input::Quoting,
+) -> Result<Vec<proto::Account>, ProcessError> {
+    let invalid = |err: csv::Error| ProcessError::InvalidControl(err.to_string());
+    let mut reader = quoting.reader().from_path(path).map_err(invalid)?;
+    let headers = reader.headers().map_err(invalid)?.clone();
+    let file_column = headers.iter().position(|name| name == "file");
+    let mut accounts = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(invalid)?;
+        let mut account: proto::Account = record.deserialize(Some(&headers)).map_err(invalid)?;
+        if let Some(file) = file_column.map(|i| &record[i]) {
+            account.file = file
+                .parse()
+                .map_err(|_| ProcessError::InvalidControl(format!("invalid file {}", file)))?;
+        }
+        accounts.push(account);
+    }
+    Ok(accounts)
+}
+
+/// Reads the accounts of a prior output to seed the run with (see
+/// `ProcessConfig::seed_accounts`), validating their totals.
+fn read_seed_accounts(
+    path: &std::path::Path,
+    quoting: input::Quoting,
+) -> Result<Vec<(models::ClientId, models::Account)>, ProcessError> {
+    let invalid = |err: &dyn fmt::Display| ProcessError::InvalidSeed(err.to_string());
+    let mut reader = quoting
+        .reader()
+        .from_path(path)
+        .map_err(|err| invalid(&err))?;
+    let mut accounts = Vec::new();
+    for record in proto::Account::read_many(&mut reader) {
+        let record = record.map_err(|err| invalid(&err))?;
+        let account = models::Account::try_from(&record).map_err(|err| invalid(&err))?;
+        accounts.push((models::ClientId::new(record.client_id), account));
+    }
+    Ok(accounts)
+}
+
+/// Reads the open disputes of a prior run to seed the run with (see
+/// `ProcessConfig::seed_disputes`).
+fn read_seed_disputes(
+    path: &std::path::Path,
+    quoting: input::Quoting,
+) -> Result<Vec<proto::OpenDispute>, ProcessError> {
+    let invalid = |err: &dyn fmt::Display| ProcessError::InvalidSeedDisputes(err.to_string());
+    let mut reader = quoting
+        .reader()
+        .from_path(path)
+        .map_err(|err| invalid(&err))?;
+    proto::OpenDispute::read_many(&mut reader)
+        .collect::<Result<_, _>>()
+        .map_err(|err| invalid(&err))
+}
+
+/// Original client ids, as `(file, client)`, with their normalized ones.
+type IdMap = Vec<((u16, u16), u16)>;
+
+/// Remaps the client ids of the account `records`, sorted by client id, to the
+/// dense range `0..N` in their order. Returns the original ids, as `(file, client)`,
+/// and the new ones. Fails leaving the ids intact if there are more records than ids.
+fn normalize_ids(records: &mut [proto::Account]) -> Result<IdMap, ProcessError> {
+    if records.len() > 1 << 16 {
+        return Err(ProcessError::TooManyClients(records.len()));
+    }
+    let id_map = (0..=u16::MAX)
+        .zip(records.iter_mut())
+        .map(|(new_id, record)| {
+            let old_id = (record.file, record.client_id);
+            record.client_id = new_id;
+            (old_id, new_id)
+        });
+    Ok(id_map.collect())
+}
+
+/// Writes the mapping of normalized client ids to the CSV file at `path`,
+/// with the input file of the original ids if clients are namespaced by file.
+fn write_id_map(
+    path: &std::path::Path,
+    id_map: &[((u16, u16), u16)],
+    namespace_files: bool,
+    quoting: input::Quoting,
+) -> std::io::Result<()> {
+    let mut writer = quoting.writer().from_path(path)?;
+    let header: &[&str] = match namespace_files {
+        true => &["file", "old", "new"],
+        false => &["old", "new"],
+    };
+    writer.write_record(header)?;
+    for ((file, old_id), new_id) in id_map {
+        let mut row = vec![old_id.to_string(), new_id.to_string()];
+        if namespace_files {
+            row.insert(0, file.to_string());
+        }
+        writer.write_record(&row)?;
+    }
+    writer.flush()
+}
+
+/// Writes the account `records` to the CSV file at the `prefix` path
+/// followed by the `suffix`, with the columns configured by `options`
+/// and the `references` joined if set.
+fn write_output_file(
+    prefix: &std::path::Path,
+    suffix: &str,
+    records: Vec<proto::Account>,
+    options: &ProcessConfig,
+    references: Option<&References>,
+) {
+    let mut path = prefix.as_os_str().to_owned();
+    path.push(suffix);
+    let mut writer = options
+        .quoting
+        .writer()
+        .from_path(path)
+        .expect("Failed to create output file");
+    let mut file_sink = csv_sink(&mut writer, options, references);
+    sink::AccountSink::write(&mut file_sink, records).expect("Failed to write accounts");
+}
+
+/// Writes a snapshot of the current accounts of the `processor`, reflecting
+/// the `submitted` transactions, to a CSV file at the `prefix` path
+/// (see `ProcessConfig::flush_interval`).
+fn write_snapshot(
+    processor: &processing::Processor,
+    prefix: &std::path::Path,
+    submitted: u64,
+    options: &ProcessConfig,
+    references: Option<&References>,
+) {
+    let accounts = processor.snapshot();
+    // Snapshots don't add up to the summary of the run.
+    let mut summary = proto::Summary::default();
+    match output_records(&accounts, options, &mut summary) {
+        Ok(records) => {
+            let time = options.processing.now().duration_since(UNIX_EPOCH);
+            let suffix = format!("-{}-{}.csv", time.map_or(0, |t| t.as_secs()), submitted);
+            write_output_file(prefix, &suffix, records, options, references);
+        }
+        Err(err) => eprintln!("Warning: skipped a snapshot: {}", err),
+    }
+}
+
+/// Converts the `accounts` into output records as configured by `options`,
+/// adding up the output ones to the `summary`.
+fn output_records(
+    accounts: &[models::Record<models::Account, models::ClientId>],
+    options: &ProcessConfig,
+    summary: &mut proto::Summary,
+) -> Result<Vec<proto::Account>, ProcessError> {
+    let mut records = Vec::with_capacity(accounts.len());
+    for r in accounts {
+        let mut record = r
+            .item
+            .to_proto_with(&r.id, options.processing.arithmetic_mode)
+            .map_err(|_| ProcessError::TotalOverflow(r.id))?;
+        round_amounts(&mut record, options);
+        if options.processing.count_transactions {
+            record.deposits = Some(r.item.get_deposit_count());
+            record.withdrawals = Some(r.item.get_withdrawal_count());
+        }
+        if !(options.suppress_zero && record.total_funds.is_zero() && !record.is_locked) {
+            summary.add(&record);
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+/// Rounds the amounts of the account `record` as configured by `options`.
+fn round_amounts(record: &mut proto::Account, options: &ProcessConfig) {
+    record.round(options.rounding);
+    if !options.parsing.preserve_scale {
+        record.available_funds = record.available_funds.normalize();
+        record.held_funds = record.held_funds.normalize();
+        record.total_funds = record.total_funds.normalize();
+        record.pending_credits = record.pending_credits.normalize();
+        record.fees = record.fees.normalize();
+    }
+}
+
+/// Writes the `events` to the audit log file at `path` as they come, if set.
+/// Returns a trace of the events of the `explained` client if set, one line
+/// per applied transaction.
+fn consume_events(
+    events: std::sync::mpsc::Receiver<processing::Event>,
+    path: Option<PathBuf>,
+    explained: Option<models::ClientId>,
+    quoting: input::Quoting,
+) -> Option<String> {
+    let mut writer = path.map(|path| {
+        quoting
+            .writer()
+            .from_path(path)
+            .expect("Failed to create audit log")
+    });
+    let mut trace = explained.map(|client_id| format!("Trace of client {}:\n", client_id));
+    for event in events {
+        if let Some(writer) = &mut writer {
+            writer.serialize(event.to_proto()).unwrap();
+        }
+        if let Some(trace) = &mut trace {
+            if Some(event.transaction.meta().client_id) == explained {
+                *trace += &format!("  {}\n", event);
+            }
+        }
+    }
+    if let Some(writer) = &mut writer {
+        writer.flush().unwrap();
+    }
+    trace
+}
+
+/// Writes the `dead_letters` to the file at `path` in the input format.
+fn write_dead_letters(
+    path: &PathBuf,
+    dead_letters: &[processing::DeadLetter],
+    quoting: input::Quoting,
+) {
+    let records: Vec<_> = dead_letters
+        .iter()
+        .map(|d| d.transaction.to_proto())
+        .collect();
+    let mut writer = quoting
+        .writer()
+        .from_path(path)
+        .expect("Failed to create dead letter file");
+    write_transactions(&mut writer, &records).unwrap();
+}
+
+/// Writes the transaction `records` in the input format.
+///
+/// The optional `original` column is only written if any of them is a correction.
+fn write_transactions<U: std::io::Write>(
+    writer: &mut csv::Writer<U>,
+    records: &[proto::Transaction],
+) -> csv::Result<()> {
+    let corrections = records.iter().any(|r| r.original_transaction_id.is_some());
+    writer.write_record(transaction_header(corrections))?;
+    for record in records {
+        writer.write_record(transaction_row(record, corrections))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Returns the header of transactions in the input format, with the
+/// `original` column if there are `corrections`.
+fn transaction_header(corrections: bool) -> Vec<&'static str> {
+    let mut header = vec!["type", "client", "tx", "amount"];
+    if corrections {
+        header.push("original");
+    }
+    header
+}
+
+/// Returns the fields of the transaction `record` in the input format, with
+/// the `original` one if there are `corrections` (see `transaction_header`).
+fn transaction_row(record: &proto::Transaction, corrections: bool) -> Vec<String> {
+    let mut row = vec![
+        record.kind.to_string(),
+        record.client_id.to_string(),
+        record.transaction_id.to_string(),
+        record.amount.map(|a| a.to_string()).unwrap_or_default(),
+    ];
+    if corrections {
+        let original = record.original_transaction_id.map(|id| id.to_string());
+        row.push(original.unwrap_or_default());
+    }
+    row
+}
+
+/// Writes the `rows` rejected as invalid to the error report file at `path`.
+fn write_error_report(path: &PathBuf, rows: &[proto::RejectedRow]) {
+    let file = std::fs::File::create(path).expect("Failed to create error report");
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), rows)
+        .expect("Failed to write error report");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+    use csv::ReaderBuilder;
+    use csv::WriterBuilder;
+    use indoc::indoc;
+    use rust_decimal_macros::dec;
+    use std::io::Write;
+
+    fn check(input: &str, expected_output: &str) {
+        check_with_config(input, expected_output, &ProcessConfig::default());
+    }
+
+    fn check_with_config(input: &str, expected_output: &str, options: &ProcessConfig) {
+        let mut reader = ReaderBuilder::new()
+            .delimiter(b',')
+            .from_reader(input.as_bytes());
+
+        let mut writer = WriterBuilder::new().delimiter(b',').from_writer(vec![]);
+        process_with_config(&mut reader, &mut writer, options).unwrap();
+
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(output, expected_output);
+    }
+
+    #[test]
+    fn depositing() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            withdrawal,1,5,1.5
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,2.5,0,2.5,false
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn dispute() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,1,2,3.0
+            dispute,1,2,
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,4,3,7,false
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn dispute_resolved() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,1,2,3.0
+            dispute,1,2,
+            resolve,1,2,
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,7,0,7,false
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn dispute_chargeback() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,1,2,3.0
+            dispute,1,2,
+            chargeback,1,2,
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,4,0,4,true
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn no_ops_after_chargeback() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,1,2,3.0
+            dispute,1,2,
+            chargeback,1,2,
+            deposit,1,2,100.0
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,4,0,4,true
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn partial_disputes() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,10.0
+            dispute,1,1,3.0
+            dispute,1,1,4.0
+            dispute,1,1,5.0
+            resolve,1,1,3.0
+            chargeback,1,1,4.0
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,6,0,6,true
+        "};
+        check(input, output);
+
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,10.0
+            dispute,1,1,3.0
+            dispute,1,1,
+            resolve,1,1,2.0
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,0,10,10,false
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn resolve_before_dispute() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,2,5.0
+            resolve,1,2,
+            dispute,1,2,
+            chargeback,1,2,
+            deposit,2,3,1.0
+            chargeback,2,3,
+            dispute,2,3,
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,0,0,0,true
+            2,0,1,1,false
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn overflowing_totals() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,79228162514264337593543950000
+            dispute,1,1,
+            deposit,1,2,79228162514264337593543950000
+        "};
+        let run = |options: &ProcessConfig| {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            let mut writer = WriterBuilder::new().from_writer(vec![]);
+            process_with_config(&mut reader, &mut writer, options)
+                .map(|_| String::from_utf8(writer.into_inner().unwrap()).unwrap())
+        };
+
+        assert_eq!(
+            run(&ProcessConfig::default()),
+            Err(ProcessError::TotalOverflow(models::ClientId::new(1)))
+        );
+
+        let mut options = ProcessConfig::default();
+        options.processing.arithmetic_mode = models::ArithmeticMode::Saturating;
+        assert_eq!(
+            run(&options).unwrap(),
+            indoc! {"
+                client,available,held,total,locked
+                1,79228162514264337593543950000,79228162514264337593543950000,79228162514264337593543950335,false
+            "}
+        );
+
+        // No shard is written unless all of them are.
+        let dir = tempfile::tempdir().unwrap();
+        let options = ProcessConfig {
+            shard_output: Some(dir.path().join("shard")),
+            ..ProcessConfig::default()
+        };
+        assert_eq!(
+            run(&options),
+            Err(ProcessError::TotalOverflow(models::ClientId::new(1)))
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn time_window() {
+        let input = indoc! {"
+            type,client,tx,amount,timestamp
+            deposit,1,1,5.0,100
+            deposit,1,2,3.0,200
+            deposit,2,3,7.0,
+            dispute,1,1,,250
+            dispute,1,2,,260
+            withdrawal,1,4,1.0,300
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,0,3,3,false
+            2,7,0,7,false
+        "};
+        let mut options = ProcessConfig::default();
+        options.parsing.window = Some(proto::TimeWindow {
+            since: Some(200),
+            until: Some(300),
+        });
+        check_with_config(input, output, &options);
+    }
+
+    #[test]
+    fn corrections() {
+        let input = indoc! {"
+            type,client,tx,amount,original
+            deposit,1,1,10.0,
+            withdrawal,1,2,2.0,
+            correct,1,3,12.0,1
+            correct,1,4,1.5,2
+            dispute,1,1,,
+            deposit,2,5,1.0,
+            correct,2,6,5.0,7
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,-1.5,12,10.5,false
+            2,1,0,1,false
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn disputed_withdrawals() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,1,2,4.0
+            dispute,1,2,
+            deposit,2,3,5.0
+            withdrawal,2,4,5.0
+            dispute,2,4,
+            chargeback,2,4,
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked,pending
+            1,6,0,6,false,4
+            2,5,0,5,true,0
+        "};
+        let options = ProcessConfig {
+            columns: Some(
+                proto::Column::parse_list("client,available,held,total,locked,pending").unwrap(),
+            ),
+            ..ProcessConfig::default()
+        };
+        check_with_config(input, output, &options);
+    }
+
+    #[test]
+    fn disabling_disputes() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,2,2,3.0
+            withdrawal,1,3,1.5
+            withdrawal,2,4,5.0
+            deposit,3,5,0.25
+            withdrawal,3,6,0.25
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,2.5,0,2.5,false
+            2,3,0,3,false
+            3,0,0,0,false
+        "};
+        check(input, output);
+        let mut options = ProcessConfig::default();
+        options.processing.no_disputes = true;
+        check_with_config(input, output, &options);
+    }
+
+    #[test]
+    fn writing_shards() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=40 {
+            input += &format!("deposit,{},{},1.0\n", tx % 8 + 1, tx);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("transactor-shard-test");
+        let options = ProcessConfig {
+            shard_output: Some(prefix.clone()),
+            ..ProcessConfig::default()
+        };
+        check_with_config(&input, "", &options);
+
+        let mut clients = Vec::new();
+        for i in 0..num_cpus::get() {
+            let path = format!("{}-{}.csv", prefix.display(), i);
+            let mut reader = ReaderBuilder::new().from_path(&path).unwrap();
+            let shard: Vec<_> = proto::Account::read_many(&mut reader)
+                .map(|r| r.unwrap().client_id)
+                .collect();
+            assert!(shard.windows(2).all(|pair| pair[0] < pair[1]));
+            clients.extend(shard);
+        }
+        clients.sort_unstable();
+        assert_eq!(clients, (1..=8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn quoting_files() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+        "};
+        let dir = tempfile::tempdir().unwrap();
+        let references = dir.path().join("transactor-quoting-test-enrich.csv");
+        std::fs::write(&references, "client,name,region\n1,'Doe, Jane',US\n").unwrap();
+        let prefix = dir.path().join("transactor-quoting-test");
+        let options = ProcessConfig::builder()
+            .enrich(&references)
+            .partition_by_lock(&prefix)
+            .quoting(input::Quoting {
+                quote: b'\'',
+                escape: None,
+            })
+            .build()
+            .unwrap();
+        check_with_config(input, "", &options);
+
+        let path = format!("{}-unlocked.csv", prefix.display());
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            indoc! {"
+                client,available,held,total,locked,name,region
+                1,1,0,1,false,'Doe, Jane',US
+            "}
+        );
+    }
+
+    #[test]
+    fn partitioning_by_lock() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,3,1,4.0
+            deposit,1,2,3.0
+            deposit,2,3,2.0
+            deposit,4,4,1.0
+            dispute,3,1,
+            chargeback,3,1,
+            freeze,1,5,
+        "};
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("transactor-lock-test");
+        let options = ProcessConfig {
+            partition_by_lock: Some(prefix.clone()),
+            ..ProcessConfig::default()
+        };
+        check_with_config(input, "", &options);
+
+        let read = |suffix: &str| {
+            let path = format!("{}{}", prefix.display(), suffix);
+            std::fs::read_to_string(path).unwrap()
+        };
+        assert_eq!(
+            read("-locked.csv"),
+            indoc! {"
+                client,available,held,total,locked
+                1,3,0,3,true
+                3,0,0,0,true
+            "}
+        );
+        assert_eq!(
+            read("-unlocked.csv"),
+            indoc! {"
+                client,available,held,total,locked
+                2,2,0,2,false
+                4,1,0,1,false
+            "}
+        );
+    }
+
+    #[test]
+    fn normalizing_ids() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,700,1,1.0
+            deposit,3,2,2.0
+            deposit,10,3,3.0
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            0,2,0,2,false
+            1,3,0,3,false
+            2,1,0,1,false
+        "};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactor-id-map-test.csv");
+        let options = ProcessConfig {
+            normalize_ids: true,
+            id_map: Some(path.clone()),
+            ..ProcessConfig::default()
+        };
+        check_with_config(input, output, &options);
+
+        let id_map = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            id_map,
+            indoc! {"
+                old,new
+                3,0
+                10,1
+                700,2
+            "}
+        );
+
+        let account = |client_id| proto::Account {
+            file: 0,
+            client_id,
+            available_funds: Decimal::ZERO,
+            held_funds: Decimal::ZERO,
+            total_funds: Decimal::ZERO,
+            is_locked: false,
+            pending_credits: Decimal::ZERO,
+            fees: Decimal::ZERO,
+            deposits: None,
+            withdrawals: None,
+            lock_reason: None,
+        };
+        let mut records = vec![account(7); (1 << 16) + 1];
+        assert_eq!(
+            normalize_ids(&mut records),
+            Err(ProcessError::TooManyClients(65537))
+        );
+        assert_eq!(records[0].client_id, 7);
+        assert_eq!(normalize_ids(&mut records[1..]).unwrap().len(), 1 << 16);
+        assert_eq!(records[65536].client_id, 65535);
+    }
+
+    #[test]
+    fn writing_dead_letters() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,2,2,3.0
+            dispute,1,1,
+            chargeback,1,1,
+            deposit,1,3,1.5
+            deposit,2,4,1.0
+            withdrawal,1,5,0.5
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,0,0,0,true
+            2,4,0,4,false
+        "};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactor-dead-letter-test.csv");
+        let options = ProcessConfig {
+            dead_letter: Some(path.clone()),
+            ..ProcessConfig::default()
+        };
+        check_with_config(input, output, &options);
+
+        let dead_letters = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            dead_letters,
+            indoc! {"
+                type,client,tx,amount
+                deposit,1,3,1.5
+                withdrawal,1,5,0.5
+            "}
+        );
+    }
+
+    #[test]
+    fn verifying_control() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,2,2,3.0
+            withdrawal,2,3,1.0
+        "};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactor-control-test.csv");
+        let mut options = ProcessConfig {
+            control: Some(path.clone()),
+            control_tolerance: dec!(0.01),
+            ..ProcessConfig::default()
+        };
+        let run = |control: &str, options: &ProcessConfig| {
+            std::fs::write(&path, control).unwrap();
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            let mut writer = WriterBuilder::new().from_writer(vec![]);
+            process_with_config(&mut reader, &mut writer, options).unwrap()
+        };
+
+        let control = indoc! {"
+            client,available,held,total,locked
+            1,4,0,4,false
+            2,2.005,0,2.005,false
+        "};
+        assert_eq!(run(control, &options).control_mismatches, vec![]);
+
+        options.control_tolerance = Decimal::ZERO;
+        let control = indoc! {"
+            client,available,held,total,locked
+            2,2.005,0,2.005,false
+            3,1,0,1,false
+        "};
+        let mismatches = run(control, &options).control_mismatches;
+        assert_eq!(
+            mismatches.iter().map(|m| m.to_string()).collect::<Vec<_>>(),
+            vec![
+                "client 1: expected total 0, actual 4",
+                "client 2: expected total 2.005, actual 2",
+                "client 3: expected total 1, actual 0",
+            ]
+        );
+
+        // Differences beyond the `Decimal` range are mismatches too.
+        let control = indoc! {"
+            client,available,held,total,locked
+            1,-79228162514264337593543950335,0,-79228162514264337593543950335,false
+            2,2,0,2,false
+        "};
+        let mismatches = run(control, &options).control_mismatches;
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].client_id, 1);
+
+        // Clients of separate files are told apart by the `file` column.
+        options.namespace_files = true;
+        let control = indoc! {"
+            file,client,available,held,total,locked
+            0,1,4,0,4,false
+            1,1,1,0,1,false
+            0,2,2,0,2,false
+        "};
+        std::fs::write(&path, control).unwrap();
+        let mut readers = [
+            ReaderBuilder::new().from_reader(input.as_bytes()),
+            ReaderBuilder::new().from_reader("type,client,tx,amount\ndeposit,1,1,2.0\n".as_bytes()),
+        ];
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+        let metrics = process_many(&mut readers, &mut writer, &options).unwrap();
+        assert_eq!(
+            metrics.control_mismatches,
+            vec![proto::Mismatch {
+                file: 1,
+                client_id: 1,
+                expected: dec!(1),
+                actual: dec!(2),
+            }]
+        );
+        assert_eq!(
+            metrics.control_mismatches[0].to_string(),
+            "client 1 of file 1: expected total 1, actual 2"
+        );
+
+        // A bad control file fails the run rather than panicking.
+        std::fs::write(&path, "client,available,held,total,locked\nx,1,0,1,false\n").unwrap();
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+        let result = process_with_config(&mut reader, &mut writer, &options);
+        assert!(matches!(result, Err(ProcessError::InvalidControl(_))));
+    }
+
+    #[test]
+    fn reporting_rejected_rows() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,x,2,1.0
+            withdrawal,1,3,
+            deposit,1,4,\"1,0\"
+            transfer,1,5,1.0
+            withdrawal,1,6,1.5
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,2.5,0,2.5,false
+        "};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactor-error-report-test.json");
+        let options = ProcessConfig {
+            errors: Some(path.clone()),
+            ..ProcessConfig::default()
+        };
+        check_with_config(input, output, &options);
+
+        let report = std::fs::read_to_string(&path).unwrap();
+        let rows: Vec<proto::RejectedRow> = serde_json::from_str(&report).unwrap();
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|r| (r.line, r.raw_row.as_str(), r.error.as_str()))
+            .collect();
+        assert_eq!(
+            rows.iter().map(|r| (r.0, r.1)).collect::<Vec<_>>(),
+            vec![
+                (3, "deposit,x,2,1.0"),
+                (4, "withdrawal,1,3,"),
+                (5, "deposit,1,4,\"1,0\""),
+                (6, "transfer,1,5,1.0"),
+            ]
+        );
+        assert!(rows[0].2.contains("invalid digit"));
+        assert_eq!(rows[1].2, "missing amount");
+        assert!(rows[2].2.contains("Invalid decimal"));
+        assert_eq!(rows[3].2, "unknown type 'transfer'");
+    }
+
+    #[test]
+    fn stopping_at_deadline() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=10_000 {
+            input += &format!("deposit,{},{},1.0\n", tx % 10, tx);
+        }
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let mut accounts = Vec::new();
+        let options = ProcessConfig {
+            deadline: Some(Duration::ZERO),
+            ..ProcessConfig::default()
+        };
+        let metrics = process_into(&mut reader, &mut accounts, &options).unwrap();
+
+        assert!(metrics.partial);
+        assert_eq!(metrics.processed, DEADLINE_CHECK_INTERVAL as u64 - 1);
+        assert_eq!(metrics.summary.total_funds, dec!(1023));
+
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let options = ProcessConfig {
+            deadline: Some(Duration::from_secs(3600)),
+            ..ProcessConfig::default()
+        };
+        let metrics = process_into(&mut reader, &mut accounts, &options).unwrap();
+        assert!(!metrics.partial);
+        assert_eq!(metrics.processed, 10_000);
+    }
+
+    #[test]
+    fn chunking_clients() {
+        /// Sink keeping the client ids of every chunk written.
+        struct Chunks(Vec<Vec<u16>>);
+
+        impl sink::AccountSink for Chunks {
+            fn write(&mut self, accounts: Vec<proto::Account>) -> std::io::Result<()> {
+                self.0.push(accounts.iter().map(|a| a.client_id).collect());
+                Ok(())
+            }
+        }
+
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,3,1,1.0
+            deposit,1,2,2.0
+            dispute,1,2,
+            deposit,2,3,3.0
+            withdrawal,2,4,1.0
+            deposit,5,5,4.0
+            deposit,4,6,5.0
+            chargeback,4,6,
+        "};
+        let options = ProcessConfig {
+            grouped: true,
+            chunk_clients: Some(2),
+            columns: Some(vec![proto::Column::Client, proto::Column::Available]),
+            ..ProcessConfig::default()
+        };
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let mut chunks = Chunks(Vec::new());
+        process_into(&mut reader, &mut chunks, &options).unwrap();
+        assert_eq!(chunks.0, vec![vec![1, 3], vec![2, 5], vec![4]]);
+
+        check_with_config(
+            input,
+            indoc! {"
+                client,available
+                1,0
+                3,1
+                2,2
+                5,4
+                4,5
+            "},
+            &options,
+        );
+    }
+
+    #[test]
+    fn processing_only_types() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            withdrawal,1,2,1.5
+            deposit,2,3,2.0
+            dispute,2,3,
+            withdrawal,2,4,5.0
+        "};
+        let options = ProcessConfig {
+            only_types: Some(proto::parse_type_list("deposit").unwrap()),
+            max_transactions: Some(2),
+            ..ProcessConfig::default()
+        };
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                1,4,0,4,false
+                2,2,0,2,false
+            "},
+            &options,
+        );
+
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+        let metrics = process_with_config(&mut reader, &mut writer, &options).unwrap();
+        assert_eq!(metrics.skipped, 3);
+        assert_eq!(metrics.processed, 2);
+        assert!(!metrics.partial);
+
+        assert_eq!(
+            proto::parse_type_list("deposit, withdrawal").unwrap(),
+            vec!["deposit", "withdrawal"]
+        );
+        assert!(proto::parse_type_list("deposit,transfer").is_err());
+    }
+
+    #[test]
+    fn ungrouped_input() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,1,2,1.0
+            deposit,2,3,1.0
+            deposit,x,4,1.0
+            deposit,1,5,1.0
+            deposit,3,6,1.0
+        "};
+        let mut options = ProcessConfig {
+            grouped: true,
+            ..ProcessConfig::default()
+        };
+        let run = |options: &ProcessConfig| {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            let mut writer = WriterBuilder::new().from_writer(vec![]);
+            let result = process_with_config(&mut reader, &mut writer, options);
+            (result, writer.into_inner().unwrap())
+        };
+
+        let (result, output) = run(&options);
+        let err = result.unwrap_err();
+        assert_eq!(
+            err,
+            ProcessError::UngroupedInput {
+                client_id: models::ClientId::new(1),
+                record: 5,
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "client 1 reappears in record 5 after other clients, input is not grouped"
+        );
+        assert!(output.is_empty());
+
+        options.chunk_clients = Some(1);
+        assert!(matches!(
+            run(&options).0,
+            Err(ProcessError::UngroupedInput { record: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn writing_snapshots() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,2,2,2.0
+            withdrawal,1,3,0.5
+            deposit,3,4,3.0
+            deposit,2,5,1.0
+        "};
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("transactor-snapshot-test");
+        let clock = clock::FixedClock::new(UNIX_EPOCH + Duration::from_secs(1000));
+        let mut options = ProcessConfig::builder()
+            .snapshots(FlushInterval::Transactions(2), &prefix)
+            .build()
+            .unwrap();
+        options.processing.clock = Some(std::sync::Arc::new(clock));
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                1,0.5,0,0.5,false
+                2,3,0,3,false
+                3,3,0,3,false
+            "},
+            &options,
+        );
+
+        let read = |transactions: u64| {
+            let path = format!("{}-1000-{}.csv", prefix.display(), transactions);
+            std::fs::read_to_string(path).unwrap()
+        };
+        assert_eq!(
+            read(2),
+            indoc! {"
+                client,available,held,total,locked
+                1,1,0,1,false
+                2,2,0,2,false
+            "}
+        );
+        assert_eq!(
+            read(4),
+            indoc! {"
+                client,available,held,total,locked
+                1,0.5,0,0.5,false
+                2,2,0,2,false
+                3,3,0,3,false
+            "}
+        );
+    }
+
+    #[test]
+    fn limiting_transactions() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,x,2,1.0
+            deposit,2,3,2.0
+            deposit,1,4,4.0
+            deposit,2,5,8.0
+        "};
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let mut accounts = Vec::new();
+        let options = ProcessConfig {
+            max_transactions: Some(3),
+            ..ProcessConfig::default()
+        };
+        let metrics = process_into(&mut reader, &mut accounts, &options).unwrap();
+        assert!(metrics.partial);
+        assert_eq!(metrics.processed, 3);
+        assert_eq!(metrics.summary.total_funds, dec!(7));
+
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let options = ProcessConfig {
+            max_transactions: Some(4),
+            ..ProcessConfig::default()
+        };
+        let metrics = process_into(&mut reader, &mut accounts, &options).unwrap();
+        assert!(!metrics.partial);
+        assert_eq!(metrics.processed, 4);
+    }
+
+    #[test]
+    fn failing_input() {
+        struct Failing;
+        impl std::io::Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disk failure"))
+            }
+        }
+        let input = std::io::Read::chain(&b"type,client,tx,amount\ndeposit,1,1,1.0\n"[..], Failing);
+        let mut reader = ReaderBuilder::new().from_reader(input);
+        let mut accounts = Vec::new();
+        let metrics = process_into(&mut reader, &mut accounts, &ProcessConfig::default()).unwrap();
+        assert!(metrics.partial);
+        assert_eq!(metrics.processed, 1);
+    }
+
+    #[test]
+    fn padding_client_ids() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,65535,2,2.0
+            deposit,123,3,3.0
+        "};
+        let mut options = ProcessConfig {
+            client_width: Some(5),
+            ..ProcessConfig::default()
+        };
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                00001,1,0,1,false
+                00123,3,0,3,false
+                65535,2,0,2,false
+            "},
+            &options,
+        );
+
+        options.client_width = Some(2);
+        options.columns = Some(vec![proto::Column::Total, proto::Column::Client]);
+        check_with_config(
+            input,
+            indoc! {"
+                total,client
+                1,01
+                3,123
+                2,65535
+            "},
+            &options,
+        );
+    }
+
+    #[test]
+    fn splitting_amounts() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,2.5
+            deposit,2,2,3.0
+        "};
+        let options = ProcessConfig {
+            split_amounts: true,
+            ..ProcessConfig::default()
+        };
+        check_with_config(
+            input,
+            indoc! {"
+                client,available_int,available_frac,held_int,held_frac,total_int,total_frac,locked
+                1,2,5000,0,0000,2,5000,false
+                2,3,0000,0,0000,3,0000,false
+            "},
+            &options,
+        );
+    }
+
+    #[test]
+    fn enriching_output() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,2,2,2.0
+            deposit,3,3,3.0
+        "};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactor-enrich-test.csv");
+        std::fs::write(
+            &path,
+            indoc! {"
+                client,name,region
+                3,Carol,EU
+                1,Alice,US
+            "},
+        )
+        .unwrap();
+        let mut options = ProcessConfig {
+            enrich: Some(path.clone()),
+            ..ProcessConfig::default()
+        };
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked,name,region
+                1,1,0,1,false,Alice,US
+                2,2,0,2,false,,
+                3,3,0,3,false,Carol,EU
+            "},
+            &options,
+        );
+
+        options.columns = Some(vec![proto::Column::Client, proto::Column::Total]);
+        check_with_config(
+            input,
+            indoc! {"
+                client,total,name,region
+                1,1,Alice,US
+                2,2,,
+                3,3,Carol,EU
+            "},
+            &options,
+        );
+
+        // A bad enrichment file fails the run before any processing.
+        std::fs::write(&path, "client,name,region\nx,Dave,EU\n").unwrap();
+        let run = |options: &ProcessConfig| {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            process_into(&mut reader, &mut Vec::new(), options)
+        };
+        assert!(matches!(
+            run(&options),
+            Err(ProcessError::InvalidEnrichment(_))
+        ));
+        options.enrich = Some(dir.path().join("transactor-enrich-missing.csv"));
+        assert!(matches!(
+            run(&options),
+            Err(ProcessError::InvalidEnrichment(_))
+        ));
+    }
+
+    #[test]
+    fn configuring_runs() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,2,2,2.0
+            withdrawal,2,3,2.0
+            deposit,3,4,3.0
+            deposit,1,5,4.0
+        "};
+        let options = ProcessConfig::builder()
+            .workers(2)
+            .suppress_zero(true)
+            .columns(vec![proto::Column::Client, proto::Column::Total])
+            .build()
+            .unwrap();
+        assert_eq!(options.workers, Some(2));
+        assert!(options.audit_log.is_none());
+        check_with_config(
+            input,
+            indoc! {"
+                client,total
+                1,5
+                3,3
+            "},
+            &options,
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("transactor-config-test");
+        let options = ProcessConfig::builder()
+            .workers(3)
+            .shard_output(&prefix)
+            .build()
+            .unwrap();
+        check_with_config(input, "", &options);
+        let mut clients = Vec::new();
+        for i in 0..3 {
+            let path = format!("{}-{}.csv", prefix.display(), i);
+            let mut reader = ReaderBuilder::new().from_path(&path).unwrap();
+            clients.extend(proto::Account::read_many(&mut reader).map(|r| r.unwrap().client_id));
+        }
+        clients.sort_unstable();
+        assert_eq!(clients, vec![1, 2, 3]);
+        assert!(!std::path::Path::new(&format!("{}-3.csv", prefix.display())).exists());
+    }
+
+    #[test]
+    fn validating_config() {
+        let fee = |fee| processing::Config {
+            withdrawal_fee: Some(fee),
+            ..processing::Config::default()
+        };
+        let build = |processing| ProcessConfig::builder().processing(processing).build();
+
+        assert!(build(fee(processing::Fee::Flat(dec!(0)))).is_ok());
+        assert!(build(fee(processing::Fee::Percent(dec!(1.5)))).is_ok());
+        assert_eq!(
+            build(fee(processing::Fee::Flat(dec!(-1)))).unwrap_err(),
+            ConfigError::NegativeFee(processing::Fee::Flat(dec!(-1)))
+        );
+        let err = build(fee(processing::Fee::Percent(dec!(-0.5)))).unwrap_err();
+        assert_eq!(err.to_string(), "fee -0.5% is negative");
+
+        let workers = |workers| ProcessConfig::builder().workers(workers).build();
+        assert!(workers(1).is_ok());
+        assert_eq!(workers(0).unwrap_err(), ConfigError::ZeroWorkers);
+        let types = |types: &str| {
+            let types = types.split(',').map(String::from).collect();
+            ProcessConfig::builder().only_types(types).build()
+        };
+        assert!(types("deposit,withdrawal").is_ok());
+        assert_eq!(
+            types("deposit,withdrawl").unwrap_err(),
+            ConfigError::UnknownType("withdrawl".to_string())
+        );
+        let scale = |amount_scale| {
+            let parsing = proto::ParseOptions {
+                amount_scale,
+                ..proto::ParseOptions::default()
+            };
+            ProcessConfig::builder().parsing(parsing).build()
+        };
+        assert!(scale(28).is_ok());
+        assert_eq!(scale(29).unwrap_err(), ConfigError::AmountScale(29));
+
+        let err = ProcessConfig::builder()
+            .grouped(true)
+            .seed_accounts("seed.csv")
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::Conflict("grouped", "seed_accounts"));
+        let err = ProcessConfig::builder()
+            .shard_output("shard")
+            .normalize_ids(true)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "shard_output cannot be combined with normalize_ids"
+        );
+        let err = ProcessConfig::builder()
+            .enrich("references.csv")
+            .normalize_ids(true)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::Conflict("enrich", "normalize_ids"));
+        let err = ProcessConfig::builder()
+            .chunk_clients(2)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::Requirement("chunk_clients", "grouped"));
+        let err = ProcessConfig::builder()
+            .id_map("ids.csv")
+            .build()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "id_map requires normalize_ids");
+        assert!(ProcessConfig::builder()
+            .grouped(true)
+            .chunk_clients(2)
+            .build()
+            .is_ok());
+        // A configuration set up by hand runs with a single worker instead.
+        let options = ProcessConfig {
+            workers: Some(0),
+            ..ProcessConfig::default()
+        };
+        check_with_config(
+            "type,client,tx,amount\ndeposit,1,1,1.0\n",
+            "client,available,held,total,locked\n1,1,0,1,false\n",
+            &options,
+        );
+    }
+
+    #[test]
+    fn replaying_transactions() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.5
+            deposit,2,2,3.0
+            withdrawal,2,3,1.0
+            deposit,1,4,2.25
+            dispute,1,4,
+            deposit,3,5,1.0
+            dispute,3,5,0.5
+            chargeback,3,5,0.5
+        "};
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let mut processed = Vec::new();
+        process_into(&mut reader, &mut processed, &ProcessConfig::default()).unwrap();
+
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let transactions: Vec<_> = models::Transaction::read_many(&mut reader)
+            .map(|r| r.unwrap())
+            .collect();
+        let json = serde_json::to_vec(&transactions).unwrap();
+        let transactions = models::Transaction::read_json(json.as_slice()).unwrap();
+
+        let mut replayed = Vec::new();
+        replay_into(transactions, &mut replayed, &ProcessConfig::default()).unwrap();
+        assert_eq!(replayed, processed);
+        assert_eq!(replayed.len(), 3);
+    }
+
+    #[test]
+    fn summarizing_accounts() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.5
+            deposit,2,2,3.0
+            withdrawal,2,3,1.0
+            deposit,3,4,2.25
+            dispute,3,4,
+            deposit,4,5,1.0
+            dispute,4,5,
+            chargeback,4,5,
+        "};
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+        let metrics =
+            process_with_config(&mut reader, &mut writer, &ProcessConfig::default()).unwrap();
+
+        assert_eq!(
+            metrics.summary,
+            proto::Summary {
+                accounts: 4,
+                available_funds: dec!(6.5),
+                held_funds: dec!(2.25),
+                total_funds: dec!(8.75),
+                locked_accounts: 1,
+                overflow: false,
+            }
+        );
+        assert_eq!(
+            metrics.summary.to_string(),
+            "accounts 4, available 6.5, held 2.25, total 8.75, locked 1"
+        );
+    }
+
+    #[test]
+    fn summarizing_large_accounts() {
+        let mut input = "type,client,tx,amount\n".to_string();
+        for client in 0..1000 {
+            input += &format!("deposit,{},{},79000000000000000000000000\n", client, client);
+        }
+        let summarize = |input: &str| {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            let mut writer = WriterBuilder::new().from_writer(vec![]);
+            process_with_config(&mut reader, &mut writer, &ProcessConfig::default())
+                .unwrap()
+                .summary
+        };
+
+        let summary = summarize(&input);
+        assert_eq!(summary.total_funds, dec!(79000000000000000000000000000));
+        assert!(!summary.overflow);
+
+        input += "deposit,1000,1000,1000000000000000000000000000\n";
+        let summary = summarize(&input);
+        assert!(summary.overflow);
+        assert_eq!(summary.total_funds, Decimal::MAX);
+        assert_eq!(summary.accounts, 1001);
+    }
+
+    #[test]
+    fn counting_frozen_accounts() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,2,2,3.0
+            dispute,2,2,
+            chargeback,2,2,
+        "};
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+        let metrics =
+            process_with_config(&mut reader, &mut writer, &ProcessConfig::default()).unwrap();
+
+        assert_eq!(metrics.frozen_accounts, 1);
+    }
+
+    #[test]
+    fn lock_reason() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,1,2,3.0
+            dispute,1,2,
+            chargeback,1,2,
+            deposit,2,3,1.0
+            freeze,2,4,
+            deposit,3,5,1.0
+        "};
+        let options = ProcessConfig {
+            lock_reason: true,
+            ..ProcessConfig::default()
+        };
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked,lock_reason
+                1,4,0,4,true,2
+                2,1,0,1,true,admin
+                3,1,0,1,false,
+            "},
+            &options,
+        );
+    }
+
+    #[test]
+    fn canonicalizing() {
+        let input = indoc! {r#"
+            type,client,tx,amount,original,timestamp
+            deposit,1,1,"$1,000.50",,100
+            withdrawal,1,2,4.00 USD,,
+            deposit,1,3,x,,
+            correct,1,4,"1,200.0",1,
+            dispute,1,1,,,
+        "#};
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+        let options = proto::ParseOptions {
+            grouping: Some(proto::Grouping::Us),
+            tolerant_amounts: true,
+            ..proto::ParseOptions::default()
+        };
+        assert_eq!(
+            canonicalize(&mut reader, &mut writer, &options).unwrap(),
+            (4, 1)
+        );
+
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            indoc! {"
+                type,client,tx,amount,original
+                deposit,1,1,1000.5,
+                withdrawal,1,2,4,
+                correct,1,4,1200,1
+                dispute,1,1,,
+            "}
+        );
+    }
+
+    #[test]
+    fn negative_deposits() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,5.0
+            deposit,1,2,-2.0
+            deposit,1,3,-4.0
+            dispute,1,2,
+            deposit,2,4,-1.0
+        "};
+        check(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                1,5,0,5,false
+            "},
+        );
+
+        let mut options = ProcessConfig::default();
+        options.parsing.negative_deposits = true;
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                1,3,0,3,false
+                2,0,0,0,false
+            "},
+            &options,
+        );
+    }
+
+    #[test]
+    fn wide_output() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,100.0
+            deposit,1,2,50.0
+            withdrawal,1,3,20.0
+            dispute,1,2,
+            chargeback,1,2,
+            deposit,2,4,10.0
+        "};
+        let mut options = ProcessConfig {
+            columns: Some(proto::Column::ALL.to_vec()),
+            ..ProcessConfig::default()
+        };
+        options.processing.count_transactions = true;
+        options.processing.deposit_fee = Some(processing::Fee::Percent(dec!(1)));
+        check_with_config(
+            input,
+            indoc! {"
+                file,client,available,held,total,locked,pending,fees,deposits,withdrawals,lock_reason
+                0,1,78.5,0,78.5,true,0,1.5,2,1,2
+                0,2,9.9,0,9.9,false,0,0.1,1,0,
+            "},
+            &options,
+        );
+
+        options.processing.count_transactions = false;
+        check_with_config(
+            input,
+            indoc! {"
+                file,client,available,held,total,locked,pending,fees,deposits,withdrawals,lock_reason
+                0,1,78.5,0,78.5,true,0,1.5,,,2
+                0,2,9.9,0,9.9,false,0,0.1,,,
+            "},
+            &options,
+        );
+    }
+
+    #[test]
+    fn freeze_unfreeze() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            freeze,1,2,
+            deposit,1,3,1.0
+            withdrawal,1,4,1.0
+            unfreeze,1,5,
+            deposit,1,6,2.0
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,6,0,6,false
+        "};
+        check(input, output);
+
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            freeze,1,2,
+            deposit,1,3,1.0
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,4,0,4,true
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn selecting_columns() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,2,2,3.0
+            dispute,2,2,
+            chargeback,2,2,
+        "};
+        let output = indoc! {"
+            total,client,locked
+            4,1,false
+            0,2,true
+        "};
+        let options = ProcessConfig {
+            columns: Some(vec![
+                proto::Column::Total,
+                proto::Column::Client,
+                proto::Column::Locked,
+            ]),
+            ..ProcessConfig::default()
+        };
+        check_with_config(input, output, &options);
+    }
+
+    #[test]
+    fn withdrawal_nonexisting_funds() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            withdrawal,1,2,3.0
+            withdrawal,1,2,3.0
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,1,0,1,false
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn disputing_other_client() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,2,2,3.0
+            deposit,3,3,2.0
+            dispute,1,2,
+            dispute,1,3,
+            resolve,1,2,
+            chargeback,1,3,
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,4,0,4,false
+            2,3,0,3,false
+            3,2,0,2,false
+        "};
+        check(input, output);
+    }
+
+    #[test]
+    fn preserving_scale() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,2,2,4.00
+        "};
+        let mut options = ProcessConfig::default();
+
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                1,4,0,4,false
+                2,4,0,4,false
+            "},
+            &options,
+        );
+
+        options.parsing.preserve_scale = true;
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                1,4.0,0,4.0,false
+                2,4.00,0,4.00,false
+            "},
+            &options,
+        );
+    }
+
+    #[test]
+    fn scaling_cents() {
+        let cents = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,400
+            withdrawal,1,2,150
+            deposit,2,3,1
+            dispute,2,3,
+        "};
+        let dollars = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.00
+            withdrawal,1,2,1.50
+            deposit,2,3,0.01
+            dispute,2,3,
+        "};
+        let mut options = ProcessConfig::default();
+        options.parsing.amount_scale = 2;
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,2.5,0,2.5,false
+            2,0,0.01,0.01,false
+        "};
+
+        check_with_config(cents, output, &options);
+        check(dollars, output);
+
+        options.parsing.preserve_scale = true;
+        check_with_config(
+            cents,
+            indoc! {"
+                client,available,held,total,locked
+                1,2.50,0,2.50,false
+                2,0.00,0.01,0.01,false
+            "},
+            &options,
+        );
+
+        // Amounts of cents have no fraction, even a zero one is fine.
+        let fractional = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,400.0
+            deposit,1,2,1.5
+        "};
+        let mut reader = ReaderBuilder::new().from_reader(fractional.as_bytes());
+        let errors: Vec<_> = models::Transaction::read_many_with(&mut reader, &options.parsing)
+            .filter_map(Result::err)
+            .map(|err| err.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec!["amount '1.5' is not an integer of the amount scale"]
+        );
+    }
+
+    #[test]
+    fn rounding() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.00005
+            deposit,2,2,1.00015
+        "};
+        let mut options = ProcessConfig::default();
+
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                1,1,0,1,false
+                2,1.0002,0,1.0002,false
+            "},
+            &options,
+        );
+
+        options.rounding = proto::Rounding::HalfUp;
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                1,1.0001,0,1.0001,false
+                2,1.0002,0,1.0002,false
+            "},
+            &options,
+        );
+
+        options.rounding = proto::Rounding::Truncate;
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                1,1,0,1,false
+                2,1.0001,0,1.0001,false
+            "},
+            &options,
+        );
+    }
+
+    #[test]
+    fn suppressing_zero_accounts() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,2.0
+            withdrawal,1,2,2.0
+            deposit,2,3,1.0
+        "};
+        let mut options = ProcessConfig::default();
+
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                1,0,0,0,false
+                2,1,0,1,false
+            "},
+            &options,
+        );
+
+        options.suppress_zero = true;
+        check_with_config(
+            input,
+            indoc! {"
+                client,available,held,total,locked
+                2,1,0,1,false
+            "},
+            &options,
+        );
+    }
+
+    #[test]
+    fn processing_stream() {
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        let feeder = std::thread::spawn(move || {
+            let lines = [
+                "type,client,tx,amount\n",
+                "deposit,1,1,2.0\n",
+                "deposit,2,2,1.5\n",
+            ];
+            for line in lines {
+                writer.write_all(line.as_bytes()).unwrap();
+                writer.flush().unwrap();
+            }
+            writer.write_all(b"withdrawal,1,3,0.5\n").unwrap();
+        });
+
+        let accounts = process_stream(reader, &ProcessConfig::default()).unwrap();
+        feeder.join().unwrap();
+
+        let balances: Vec<_> = accounts
+            .iter()
+            .map(|a| (a.client_id, a.available_funds))
+            .collect();
+        assert_eq!(balances, vec![(1, dec!(1.5)), (2, dec!(1.5))]);
+    }
+
+    #[test]
+    fn namespacing_files() {
+        let inputs = [
+            indoc! {"
+                type,client,tx,amount
+                deposit,1,1,1.0
+                deposit,2,2,2.0
+            "},
+            indoc! {"
+                type,client,tx,amount
+                deposit,2,1,4.0
+                deposit,1,2,3.0
+                dispute,1,2,
+            "},
+        ];
+        let run = |options: &ProcessConfig| {
+            let mut readers: Vec<_> = inputs
+                .iter()
+                .map(|input| ReaderBuilder::new().from_reader(input.as_bytes()))
+                .collect();
+            let mut writer = WriterBuilder::new().from_writer(vec![]);
+            process_many(&mut readers, &mut writer, options).unwrap();
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        };
+
+        let options = ProcessConfig {
+            namespace_files: true,
+            ..ProcessConfig::default()
+        };
+        assert_eq!(
+            run(&options),
+            indoc! {"
+                file,client,available,held,total,locked
+                0,1,1,0,1,false
+                0,2,2,0,2,false
+                1,1,0,3,3,false
+                1,2,4,0,4,false
+            "}
+        );
+
+        assert_eq!(
+            run(&ProcessConfig::default()),
+            indoc! {"
+                client,available,held,total,locked
+                1,1,3,4,false
+                2,6,0,6,false
+            "}
+        );
+
+        assert_eq!(file_namespaces(1 << 16).unwrap().len(), 1 << 16);
+        assert!(matches!(
+            file_namespaces((1 << 16) + 1),
+            Err(ProcessError::TooManyFiles(65537))
+        ));
+    }
+
+    #[test]
+    fn reading_back_output() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.5
+            deposit,2,2,2.25
+            dispute,2,2,
+        "};
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+        process(&mut reader, &mut writer).unwrap();
+
+        let output = writer.into_inner().unwrap();
+        let mut reader = ReaderBuilder::new().from_reader(output.as_slice());
+        let accounts: Vec<proto::Account> = proto::Account::read_many(&mut reader)
+            .map(|r| r.unwrap())
+            .collect();
+
+        assert_eq!(
+            accounts,
+            vec![
+                proto::Account {
+                    file: 0,
+                    client_id: 1,
+                    available_funds: dec!(1.5),
+                    held_funds: dec!(0),
+                    total_funds: dec!(1.5),
+                    is_locked: false,
+                    pending_credits: dec!(0),
+                    fees: dec!(0),
+                    deposits: None,
+                    withdrawals: None,
+                    lock_reason: None,
+                },
+                proto::Account {
+                    file: 0,
+                    client_id: 2,
+                    available_funds: dec!(0),
+                    held_funds: dec!(2.25),
+                    total_funds: dec!(2.25),
+                    is_locked: false,
+                    pending_credits: dec!(0),
+                    fees: dec!(0),
+                    deposits: None,
+                    withdrawals: None,
+                    lock_reason: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn audit_log() {
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,4.0
+            deposit,2,2,3.0
+            withdrawal,1,3,5.0
+            dispute,1,1,
+            chargeback,1,1,
+            deposit,1,4,1.0
+            dispute,2,9,
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,0,0,0,true
+            2,3,0,3,false
+        "};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactor-audit-log-test.csv");
+        let options = ProcessConfig {
+            audit_log: Some(path.clone()),
+            workers: Some(1),
+            ..ProcessConfig::default()
+        };
+        check_with_config(input, output, &options);
+
+        let audit = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            audit,
+            indoc! {"
+                seq,client,tx,type,amount,available,held
+                0,1,1,deposit,4,4,0
+                1,2,2,deposit,3,3,0
+                3,1,1,dispute,,0,4
+                4,1,1,chargeback,,0,0
+            "}
+        );
+    }
+
+    #[test]
+    fn seeding_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactor-seed-accounts-test.csv");
+        std::fs::write(
+            &path,
+            indoc! {"
+                client,available,held,total,locked
+                1,10,2,12,false
+                2,5,0,5,true
+            "},
+        )
+        .unwrap();
+
+        let input = indoc! {"
+            type,client,tx,amount
+            withdrawal,1,1,3.0
+            deposit,2,2,1.0
+            deposit,3,3,1.0
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,7,2,9,false
+            2,5,0,5,true
+            3,1,0,1,false
+        "};
+        let mut options = ProcessConfig {
+            seed_accounts: Some(path.clone()),
+            ..ProcessConfig::default()
+        };
+        check_with_config(input, output, &options);
+
+        // A bad seed file fails the run before any processing.
+        let run = |options: &ProcessConfig| {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            process_into(&mut reader, &mut Vec::new(), options)
+        };
+        std::fs::write(
+            &path,
+            "client,available,held,total,locked\n1,10,2,11,false\n",
+        )
+        .unwrap();
+        assert!(matches!(run(&options), Err(ProcessError::InvalidSeed(_))));
+        std::fs::write(
+            &path,
+            "client,available,held,total,locked\nx,10,2,12,false\n",
+        )
+        .unwrap();
+        assert!(matches!(run(&options), Err(ProcessError::InvalidSeed(_))));
+        options.seed_accounts = Some(dir.path().join("transactor-seed-accounts-missing.csv"));
+        assert!(matches!(run(&options), Err(ProcessError::InvalidSeed(_))));
+    }
+
+    #[test]
+    fn seeding_disputes() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts_path = dir.path().join("transactor-seed-dispute-accounts-test.csv");
+        std::fs::write(
+            &accounts_path,
+            indoc! {"
+                client,available,held,total,locked
+                1,10,5,15,false
+                2,1,2,3,false
+            "},
+        )
+        .unwrap();
+        let disputes_path = dir.path().join("transactor-seed-disputes-test.csv");
+        std::fs::write(
+            &disputes_path,
+            indoc! {"
+                client,tx,held
+                1,7,3
+                1,8,2
+                2,9,2
+            "},
+        )
+        .unwrap();
+
+        let input = indoc! {"
+            type,client,tx,amount
+            resolve,1,7,
+            chargeback,2,9,
+            resolve,1,10,
+        "};
+        let output = indoc! {"
+            client,available,held,total,locked
+            1,13,2,15,false
+            2,1,0,1,true
+        "};
+        let mut options = ProcessConfig {
+            seed_accounts: Some(accounts_path.clone()),
+            seed_disputes: Some(disputes_path.clone()),
+            ..ProcessConfig::default()
+        };
+        check_with_config(input, output, &options);
+
+        // A bad seed disputes file fails the run before any processing.
+        let run = |options: &ProcessConfig| {
+            let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+            process_into(&mut reader, &mut Vec::new(), options)
+        };
+        std::fs::write(&disputes_path, "client,tx,held\n1,7,x\n").unwrap();
+        assert!(matches!(
+            run(&options),
+            Err(ProcessError::InvalidSeedDisputes(_))
+        ));
+        options.seed_disputes = Some(dir.path().join("transactor-seed-disputes-missing.csv"));
+        assert!(matches!(
+            run(&options),
+            Err(ProcessError::InvalidSeedDisputes(_))
+        ));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_phases() {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+        use tracing_subscriber::registry::LookupSpan;
+
+        /// Formats the visited fields as `name=value` pairs.
+        #[derive(Default)]
+        struct Fields(String);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0 += &format!(" {}={:?}", field.name(), value);
+            }
+        }
+
+        /// Collects spans and events as lines of their name and fields.
+        #[derive(Clone, Default)]
+        struct Collector(Arc<Mutex<Vec<String>>>);
+
+        impl<S: tracing::Subscriber + for<'a> LookupSpan<'a>> tracing_subscriber::Layer<S> for Collector {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                _: &tracing::span::Id,
+                _: Context<'_, S>,
+            ) {
+                let mut fields = Fields::default();
+                attrs.record(&mut fields);
+                let line = format!("{}{}", attrs.metadata().name(), fields.0);
+                self.0.lock().unwrap().push(line);
+            }
+
+            fn on_record(
+                &self,
+                id: &tracing::span::Id,
+                values: &tracing::span::Record<'_>,
+                ctx: Context<'_, S>,
+            ) {
+                let mut fields = Fields::default();
+                values.record(&mut fields);
+                let name = ctx.span(id).unwrap().name();
+                self.0.lock().unwrap().push(format!("{}{}", name, fields.0));
+            }
+
+            fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                self.0.lock().unwrap().push(format!("event{}", fields.0));
+            }
+        }
+
+        // Workers report to the subscriber of the thread spawning them.
+        let collector = Collector::default();
+        let subscriber = tracing_subscriber::registry().with(collector.clone());
+        let input = indoc! {"
+            type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,x,2,1.0
+            withdrawal,1,3,2.0
+        "};
+        let options = ProcessConfig {
+            workers: Some(1),
+            ..ProcessConfig::default()
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            check_with_config(
+                input,
+                indoc! {"
+                    client,available,held,total,locked
+                    1,1,0,1,false
+                "},
+                &options,
+            )
+        });
+
+        // The worker runs alongside the reading, so only its own lines are ordered.
+        let lines = collector.0.lock().unwrap().clone();
+        let worker: Vec<_> = lines
+            .iter()
+            .filter(|l| l.starts_with("worker") || l.starts_with("event"))
+            .collect();
+        assert_eq!(
+            worker,
+            [
+                "worker worker=0",
+                "event message=transaction rejected client=1 tx=3 reason=InsufficientFunds",
+                "worker processed=1",
+                "worker rejected=1",
+            ]
+        );
+        let phases: Vec<_> = lines
+            .iter()
+            .filter(|l| !l.starts_with("worker") && !l.starts_with("event"))
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                "read",
+                "read records=3",
+                "read transactions=2",
+                "output accounts=1"
+            ]
+        );
+    }
+}
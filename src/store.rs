@@ -0,0 +1,86 @@
+//! Module defines storage of client accounts used by processing partitions.
+
+use crate::models::{Account, ClientId};
+use std::collections::HashMap;
+
+/// Storage of client accounts owned by a single partition.
+///
+/// The default implementation keeps accounts in memory (see `HashMap` impl).
+/// Implement the trait to back accounts by an external store (e.g. an embedded
+/// key-value database) when client population doesn't fit into memory.
+pub trait AccountStore {
+    /// Returns the account of the client `id`, creating a new empty account if absent.
+    fn get_or_create(&mut self, id: ClientId) -> &mut Account;
+
+    /// Returns the account of the client `id` if present.
+    ///
+    /// Also saves the account before every transaction, so a transaction rolled
+    /// back after a panic restores it (see `crate::processing::Config::continue_on_panic`).
+    fn get(&self, id: ClientId) -> Option<&Account>;
+
+    /// Removes the account of the client `id`, returning it if present.
+    ///
+    /// Also removes the account opened by a transaction rolled back after a panic.
+    fn remove(&mut self, id: ClientId) -> Option<Account>;
+
+    /// Returns copies of all the accounts the store holds. Order is unspecified.
+    fn snapshot(&self) -> Vec<(ClientId, Account)>;
+
+    /// Consumes the store returning all the accounts it holds. Order is unspecified.
+    fn drain(self: Box<Self>) -> Vec<(ClientId, Account)>;
+}
+
+impl AccountStore for HashMap<ClientId, Account> {
+    fn get_or_create(&mut self, id: ClientId) -> &mut Account {
+        self.entry(id).or_default()
+    }
+
+    fn get(&self, id: ClientId) -> Option<&Account> {
+        HashMap::get(self, &id)
+    }
+
+    fn remove(&mut self, id: ClientId) -> Option<Account> {
+        HashMap::remove(self, &id)
+    }
+
+    fn snapshot(&self) -> Vec<(ClientId, Account)> {
+        self.iter()
+            .map(|(id, account)| (*id, account.clone()))
+            .collect()
+    }
+
+    fn drain(self: Box<Self>) -> Vec<(ClientId, Account)> {
+        self.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ArithmeticMode;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn in_memory_store() {
+        let mut store: Box<dyn AccountStore> = Box::new(HashMap::new());
+        let mode = ArithmeticMode::Checked;
+        let acc = store.get_or_create(ClientId::new(1));
+        acc.deposit(&dec!(1.5), mode).unwrap();
+        acc.deposit(&dec!(2), mode).unwrap();
+        store.get_or_create(ClientId::new(2));
+        assert!(store.get(ClientId::new(2)).is_some());
+        assert!(store.get(ClientId::new(3)).is_none());
+        store.get_or_create(ClientId::new(3));
+        assert_eq!(store.snapshot().len(), 3);
+        assert!(store.remove(ClientId::new(3)).is_some());
+        assert!(store.remove(ClientId::new(3)).is_none());
+
+        let accounts: HashMap<_, _> = store.drain().into_iter().collect();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(
+            accounts[&ClientId::new(1)].get_available_funds(),
+            &dec!(3.5)
+        );
+        assert_eq!(accounts[&ClientId::new(2)].get_available_funds(), &dec!(0));
+    }
+}